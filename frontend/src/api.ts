@@ -1,3 +1,4 @@
+import type { CorpusIndex } from './corpus';
 import { parseModsBitmask } from './modParser';
 
 import { PUBLIC_API_BRIDGE_BASE_URL } from '$env/static/public';
@@ -7,6 +8,22 @@ export const fetchCorpus = async (url: string) => {
   return response.arrayBuffer();
 };
 
+export const fetchSearchIndex = async (url: string) => {
+  const response = await fetch(url);
+  if (!response.ok) {
+    throw new Error(`Failed to fetch search index: ${response.status}`);
+  }
+  return response.arrayBuffer();
+};
+
+export const fetchCorpusIndex = async (url: string): Promise<CorpusIndex> => {
+  const response = await fetch(url);
+  if (!response.ok) {
+    throw new Error(`Failed to fetch corpus index: ${response.status}`);
+  }
+  return response.json();
+};
+
 export const updateUser = (userID: number) =>
   fetch(`https://osutrack-api.ameo.dev/update?user=${userID}&mode=0`, { method: 'POST' });
 
@@ -2,7 +2,7 @@ import * as FuzzySearcher from '@m31coding/fuzzy-search';
 
 export interface SearchDatum {
   originalIx: number;
-  data: [string, string, string, string];
+  data: string[];
 }
 
 onmessage = async (e) => {
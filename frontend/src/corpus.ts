@@ -1,6 +1,6 @@
-import { PUBLIC_CORPUS_URL } from '$env/static/public';
+import { PUBLIC_CORPUS_INDEX_URL } from '$env/static/public';
 import { get, writable } from 'svelte/store';
-import { fetchCorpus } from './api';
+import { fetchCorpus, fetchCorpusIndex } from './api';
 import { parseModsBitmask } from './modParser';
 import { logError } from './sentry';
 import { delay } from './util';
@@ -18,76 +18,235 @@ export interface ScoreMetadata {
   beatmapName: string;
   difficultyName: string;
   mapperName: string;
+  /**
+   * Title in its original script, which is often Japanese or Korean, if the map sets one
+   */
+  beatmapNameUnicode: string | null;
+  artistName: string;
+  artistNameUnicode: string | null;
+  source: string;
+  /**
+   * Space-separated tags from the `.osu` file
+   */
+  tags: string;
+  rankedStatus: RankedStatus;
   releaseYear: number;
   lengthSeconds: number;
   realLengthSeconds: number;
+  /**
+   * Length excluding breaks and the lead-in before the first object
+   */
+  drainSeconds: number;
+  realDrainSeconds: number;
   bpm: number;
   actualBPM: number;
   AR: number;
   CS: number;
   OD: number;
+  HP: number;
+  /**
+   * 0 for osu!standard, 1 for taiko, 2 for catch, 3 for mania
+   */
+  mode: number;
   aimDifficulty: number;
   speedDifficulty: number;
   aimSpeedRatio: number;
   numUsers: number;
+  /**
+   * Set for points which were placed into an existing embedding after the fact rather than being embedded along
+   * with everything else, so their positions are only approximate
+   */
+  lowConfidence: boolean;
 }
 
 export type Corpus = ScoreMetadata[];
 
-const parseCorpus = (buffer: ArrayBuffer, version: CorpusVersion): ScoreMetadata[] => {
+/**
+ * The osu! API's `approved` values
+ */
+export enum RankedStatus {
+  Graveyard = -2,
+  WIP = -1,
+  Pending = 0,
+  Ranked = 1,
+  Approved = 2,
+  Qualified = 3,
+  Loved = 4,
+}
+
+/**
+ * Entry in the `corpus-index.json` maintained by the corpus builder's `publish` command
+ */
+export interface CorpusRelease {
+  id: string;
+  /**
+   * Either absolute or relative to the corpus index
+   */
+  url: string;
+  format_version: number;
+  row_count: number;
+  /**
+   * Unix timestamp in seconds
+   */
+  built_at: number;
+  bounds: { min_x: number; max_x: number; min_y: number; max_y: number };
+  /**
+   * In the coordinate space of the positions stored in the corpus
+   */
+  default_view: { center: [number, number]; span_x: number; span_y: number };
+  /**
+   * Set for releases whose positions are mirrored through the origin compared to the others
+   */
+  mirrored?: boolean;
+  /**
+   * URLs of files built alongside the corpus like `search_index`, relative to the corpus index
+   */
+  sidecars?: Record<string, string>;
+}
+
+export interface CorpusIndex {
+  index_version: number;
+  /**
+   * ID of the release which is shown when no version is specified
+   */
+  latest: string | null;
+  /**
+   * Oldest first
+   */
+  releases: CorpusRelease[];
+}
+
+// `OSUA` magic bytes read as a little-endian u32.  Legacy corpora have no header and start directly
+// with the item count instead.
+const CORPUS_MAGIC = 0x4155534f;
+const ROW_FLAG_LOW_CONFIDENCE = 1 << 0;
+// String table index stored for optional strings which aren't set
+const NO_STRING = 0xffffffff;
+
+const readStringTable = (buffer: ArrayBuffer, offset: number, numStrings: number): string[] => {
+  const dataView = new DataView(buffer);
+  const textDecoder = new TextDecoder();
+
+  const strings: string[] = new Array(numStrings);
+  let stringOffset = offset + numStrings * 4;
+  for (let i = 0; i < numStrings; i++) {
+    const length = dataView.getUint32(offset + i * 4, true);
+    strings[i] = textDecoder.decode(new Uint8Array(buffer, stringOffset, length));
+    stringOffset += length;
+  }
+  return strings;
+};
+
+/**
+ * Converts a position stored in the corpus into the coordinates that it's displayed at
+ */
+const toDisplayPosition = ([x, y]: [number, number], release: CorpusRelease): [number, number] => {
+  const sign = release.mirrored ? -1 : 1;
+  return [sign * 2 * x, sign * -2 * y];
+};
+
+const parseCorpus = (buffer: ArrayBuffer, release: CorpusRelease): ScoreMetadata[] => {
   const dataView = new DataView(buffer);
 
+  const isVersioned = dataView.getUint32(0, true) === CORPUS_MAGIC;
+  const formatVersion = isVersioned ? dataView.getUint32(4, true) : 1;
+  const hasFlags = formatVersion >= 3;
+  const hasExtendedMetadata = formatVersion >= 4;
+  const hasUnicodeNames = formatVersion >= 5;
+  const headerSize = isVersioned ? 16 : 4;
+  const rowSize = hasUnicodeNames ? 98 : hasExtendedMetadata ? 90 : hasFlags ? 70 : isVersioned ? 68 : 62;
+
   // read item count first
-  const numItems = dataView.getUint32(0, true);
-  let rowDataOffset = 4;
+  const numItems = dataView.getUint32(isVersioned ? 8 : 0, true);
+  let rowDataOffset = headerSize;
 
   const beatmaps: ScoreMetadata[] = [];
-  const stringStartOffset = numItems * 62 + 4;
+  const stringStartOffset = numItems * rowSize + headerSize;
   let stringOffset = stringStartOffset;
+  const strings = isVersioned ? readStringTable(buffer, stringStartOffset, dataView.getUint32(12, true)) : null;
 
   const textDecoder = new TextDecoder();
+  const readInlineString = (length: number) => {
+    const str = textDecoder.decode(new Uint8Array(buffer, stringOffset, length));
+    stringOffset += length;
+    return str;
+  };
 
   for (let i = 0; i < numItems; i++) {
     const beatmapId = dataView.getInt32(rowDataOffset, true);
     const modsBitmask = dataView.getUint32(rowDataOffset + 4, true);
-    let x = 2 * dataView.getFloat32(rowDataOffset + 8, true);
-    let y = 2 * -dataView.getFloat32(rowDataOffset + 12, true);
-    switch (version) {
-      case CorpusVersion._2024:
-        x *= -1;
-        y *= -1;
-        break;
-      default:
-        break;
-    }
+    const position = toDisplayPosition(
+      [dataView.getFloat32(rowDataOffset + 8, true), dataView.getFloat32(rowDataOffset + 12, true)],
+      release
+    );
     const averagePp = dataView.getFloat32(rowDataOffset + 16, true);
     const starRating = dataView.getFloat32(rowDataOffset + 20, true);
-    const beatmapNameLength = dataView.getUint16(rowDataOffset + 24, true);
-    const difficultyNameLength = dataView.getUint16(rowDataOffset + 26, true);
-    const mapperNameLength = dataView.getUint16(rowDataOffset + 28, true);
-    const releaseYear = dataView.getUint16(rowDataOffset + 30, true);
-    const lengthSeconds = dataView.getUint16(rowDataOffset + 32, true);
-    const bpm = dataView.getUint16(rowDataOffset + 34, true);
-    const AR = dataView.getFloat32(rowDataOffset + 36, true);
-    const CS = dataView.getFloat32(rowDataOffset + 40, true);
-    const OD = dataView.getFloat32(rowDataOffset + 44, true);
-    const aimDifficulty = dataView.getFloat32(rowDataOffset + 48, true);
-    const speedDifficulty = dataView.getFloat32(rowDataOffset + 52, true);
-    const beatmapSetID = dataView.getUint32(rowDataOffset + 56, true);
-    const numUsers = dataView.getUint16(rowDataOffset + 60, true);
-
-    rowDataOffset += 62;
-
-    const beatmapName = textDecoder.decode(new Uint8Array(buffer, stringOffset, beatmapNameLength));
-    stringOffset += beatmapNameLength;
-    const difficultyName = textDecoder.decode(new Uint8Array(buffer, stringOffset, difficultyNameLength));
-    stringOffset += difficultyNameLength;
-    const mapperName = textDecoder.decode(new Uint8Array(buffer, stringOffset, mapperNameLength));
-    stringOffset += mapperNameLength;
+
+    let beatmapName: string;
+    let difficultyName: string;
+    let mapperName: string;
+    let o = rowDataOffset + 24;
+    if (strings) {
+      beatmapName = strings[dataView.getUint32(o, true)];
+      difficultyName = strings[dataView.getUint32(o + 4, true)];
+      mapperName = strings[dataView.getUint32(o + 8, true)];
+      o += 12;
+    } else {
+      const beatmapNameLength = dataView.getUint16(o, true);
+      const difficultyNameLength = dataView.getUint16(o + 2, true);
+      const mapperNameLength = dataView.getUint16(o + 4, true);
+      beatmapName = readInlineString(beatmapNameLength);
+      difficultyName = readInlineString(difficultyNameLength);
+      mapperName = readInlineString(mapperNameLength);
+      o += 6;
+    }
+
+    const releaseYear = dataView.getUint16(o, true);
+    const lengthSeconds = dataView.getUint16(o + 2, true);
+    const bpm = dataView.getUint16(o + 4, true);
+    const AR = dataView.getFloat32(o + 6, true);
+    const CS = dataView.getFloat32(o + 10, true);
+    const OD = dataView.getFloat32(o + 14, true);
+    const aimDifficulty = dataView.getFloat32(o + 18, true);
+    const speedDifficulty = dataView.getFloat32(o + 22, true);
+    const beatmapSetID = dataView.getUint32(o + 26, true);
+    const numUsers = dataView.getUint16(o + 30, true);
+    const flags = hasFlags ? dataView.getUint16(o + 32, true) : 0;
+
+    // Corpora from before these were added are decoded as empty or zero, like in the builder
+    let artistName = '';
+    let source = '';
+    let tags = '';
+    let drainSeconds = 0;
+    let HP = 0;
+    let rankedStatus = RankedStatus.Pending;
+    let mode = 0;
+    let beatmapNameUnicode: string | null = null;
+    let artistNameUnicode: string | null = null;
+    if (hasExtendedMetadata && strings) {
+      artistName = strings[dataView.getUint32(o + 34, true)];
+      source = strings[dataView.getUint32(o + 38, true)];
+      tags = strings[dataView.getUint32(o + 42, true)];
+      drainSeconds = dataView.getUint16(o + 46, true);
+      HP = dataView.getFloat32(o + 48, true);
+      rankedStatus = dataView.getInt8(o + 52);
+      mode = dataView.getUint8(o + 53);
+    }
+    if (hasUnicodeNames && strings) {
+      const readOptionalString = (offset: number) => {
+        const ix = dataView.getUint32(offset, true);
+        return ix === NO_STRING ? null : strings[ix];
+      };
+      beatmapNameUnicode = readOptionalString(o + 54);
+      artistNameUnicode = readOptionalString(o + 58);
+    }
+
+    rowDataOffset += rowSize;
 
     const modString = parseModsBitmask(modsBitmask);
 
     const realLengthSeconds = modString.includes('DT') ? Math.ceil(lengthSeconds / 1.5) : lengthSeconds;
+    const realDrainSeconds = modString.includes('DT') ? Math.ceil(drainSeconds / 1.5) : drainSeconds;
     const aimSpeedRatio = aimDifficulty / speedDifficulty;
     const actualBPM = bpm * (modString.includes('DT') ? 1.5 : 1);
 
@@ -98,24 +257,35 @@ const parseCorpus = (buffer: ArrayBuffer, version: CorpusVersion): ScoreMetadata
       beatmapSetID,
       modsBitmask,
       modString,
-      position: [x, y],
+      position,
       averagePp,
       starRating,
       beatmapName,
       difficultyName,
       mapperName,
+      beatmapNameUnicode,
+      artistName,
+      artistNameUnicode,
+      source,
+      tags,
+      rankedStatus,
       releaseYear,
       lengthSeconds,
       realLengthSeconds,
+      drainSeconds,
+      realDrainSeconds,
       bpm,
       actualBPM,
       AR,
       CS,
       OD,
+      HP,
+      mode,
       aimDifficulty,
       speedDifficulty,
       aimSpeedRatio,
       numUsers,
+      lowConfidence: (flags & ROW_FLAG_LOW_CONFIDENCE) !== 0,
     });
   }
 
@@ -125,64 +295,43 @@ const parseCorpus = (buffer: ArrayBuffer, version: CorpusVersion): ScoreMetadata
 type FetchedCorpus =
   | { status: 'notFetched' }
   | { status: 'loading' }
-  | { status: 'loaded'; data: ScoreMetadata[] }
+  | { status: 'loaded'; data: ScoreMetadata[]; release: CorpusRelease; index: CorpusIndex }
   | { status: 'error'; error: Error };
 
 export const GlobalCorpus = writable<FetchedCorpus>({ status: 'notFetched' });
 
-export enum CorpusVersion {
-  Latest = 'LATEST',
-  _2024 = '1736199183',
-  First = '1716022133',
-}
-
-export const CorpusVersions = [CorpusVersion.First, CorpusVersion._2024, CorpusVersion.Latest];
-
-export const getCorpusURL = (version: CorpusVersion) => {
-  switch (version) {
-    case CorpusVersion.Latest:
-      return PUBLIC_CORPUS_URL;
-    case CorpusVersion._2024:
-      return 'https://osu-map.b-cdn.net/corpus_1730166567.txt';
-    case CorpusVersion.First:
-      return 'https://osu-map.b-cdn.net/corpus_1716022133.txt';
+/**
+ * Picks the release for the `version` URL parameter, falling back to the latest release if it's missing or unknown
+ */
+const getCorpusRelease = (index: CorpusIndex, version: string | null): CorpusRelease => {
+  const selected =
+    index.releases.find((release) => release.id === version) ??
+    index.releases.find((release) => release.id === index.latest) ??
+    index.releases[index.releases.length - 1];
+  if (!selected) {
+    throw new Error('Corpus index has no releases');
   }
+  return selected;
 };
 
-export const parseCorpusVersion = (version: number | string | null | undefined): CorpusVersion => {
-  switch (version) {
-    case 'LATEST':
-    case '':
-    case null:
-    case undefined:
-      return CorpusVersion.Latest;
-    case '1716022133':
-    case 1716022133:
-      return CorpusVersion.First;
-    case '1736199183':
-    case 1736199183:
-      return CorpusVersion._2024;
-    default:
-      return CorpusVersion.Latest;
-  }
+const getCorpusIndexURL = () => new URL(PUBLIC_CORPUS_INDEX_URL, window.location.href);
+
+/**
+ * URL of a sidecar built alongside the release, or `null` if it doesn't have one
+ */
+export const getSidecarURL = (release: CorpusRelease, name: string): string | null => {
+  const url = release.sidecars?.[name];
+  return url ? new URL(url, getCorpusIndexURL()).href : null;
 };
 
 export const getCorpusDefaultView = (
-  version: CorpusVersion
-): { initialCenter: [number, number]; initialSpanX: number } => {
-  switch (version) {
-    case CorpusVersion.First:
-      return { initialCenter: [14.4, -8], initialSpanX: 81.6 };
-    case CorpusVersion._2024:
-      return { initialCenter: [-10.4, 5], initialSpanX: 122.6 };
-    case CorpusVersion.Latest:
-      return { initialCenter: [-20, 25], initialSpanX: 322.6 };
-    default:
-      throw new Error(`Invalid corpus version: ${version}`);
-  }
-};
+  release: CorpusRelease
+): { initialCenter: [number, number]; initialSpanX: number } => ({
+  initialCenter: toDisplayPosition(release.default_view.center, release),
+  initialSpanX: 2 * release.default_view.span_x,
+});
 
-export const loadCorpus = async (version: CorpusVersion) => {
+export const loadCorpus = async (version: string | null) => {
   if (get(GlobalCorpus).status !== 'notFetched') {
     return;
   }
@@ -191,9 +340,12 @@ export const loadCorpus = async (version: CorpusVersion) => {
 
   for (;;) {
     try {
-      const buffer = await fetchCorpus(getCorpusURL(version));
-      const corpus = parseCorpus(buffer, version);
-      GlobalCorpus.set({ status: 'loaded', data: corpus });
+      const indexURL = getCorpusIndexURL();
+      const index = await fetchCorpusIndex(indexURL.href);
+      const release = getCorpusRelease(index, version);
+      const buffer = await fetchCorpus(new URL(release.url, indexURL).href);
+      const corpus = parseCorpus(buffer, release);
+      GlobalCorpus.set({ status: 'loaded', data: corpus, release, index });
       return;
     } catch (err) {
       logError('Failed to load corpus', err);
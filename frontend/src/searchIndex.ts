@@ -0,0 +1,159 @@
+import type { Corpus } from './corpus';
+
+/**
+ * Trigram index over the searchable text of the corpus, built alongside it by the corpus builder's
+ * `search_index` module.  See the docs there for the binary format.
+ */
+export interface SearchIndex {
+  /**
+   * Corpus row indices in each document.  There's one document per beatmap.
+   */
+  docRows: Uint32Array[];
+  /**
+   * Offset and document count of each trigram's posting list, which are decoded on demand
+   */
+  postings: Map<string, { offset: number; docCount: number }>;
+  bytes: Uint8Array;
+}
+
+// `OSUS` magic bytes read as a little-endian u32
+const SEARCH_INDEX_MAGIC = 0x5355534f;
+const SEARCH_INDEX_FORMAT_VERSION = 1;
+/**
+ * Fraction of a query's trigrams which a document needs to contain to match, which leaves room for typos
+ */
+const MIN_MATCHED_TRIGRAM_FRACTION = 0.75;
+
+/**
+ * Folds compatibility characters like fullwidth letters, strips diacritics, and lowercases text, then splits it into
+ * words.  Must match `normalize_search_text` in the corpus builder.
+ */
+export const normalizeSearchText = (text: string): string[] =>
+  text
+    .normalize('NFKD')
+    .replace(/\p{M}/gu, '')
+    .toLowerCase()
+    .split(/[^\p{L}\p{N}]+/u)
+    .filter(Boolean);
+
+/**
+ * The last word is treated as a prefix so that results show up while it's still being typed
+ */
+const queryTrigrams = (query: string): string[] => {
+  const words = normalizeSearchText(query);
+  const trigrams = new Set<string>();
+  words.forEach((word, i) => {
+    const chars = [' ', ...word];
+    if (i !== words.length - 1) {
+      chars.push(' ');
+    }
+    for (let j = 0; j + 3 <= chars.length; j++) {
+      trigrams.add(chars.slice(j, j + 3).join(''));
+    }
+  });
+  return [...trigrams];
+};
+
+export const parseSearchIndex = (buffer: ArrayBuffer, numRows: number): SearchIndex => {
+  const dataView = new DataView(buffer);
+  const bytes = new Uint8Array(buffer);
+  if (dataView.getUint32(0, true) !== SEARCH_INDEX_MAGIC) {
+    throw new Error('Not a search index');
+  }
+  const formatVersion = dataView.getUint32(4, true);
+  if (formatVersion !== SEARCH_INDEX_FORMAT_VERSION) {
+    throw new Error(`Unsupported search index format version ${formatVersion}`);
+  }
+  if (dataView.getUint32(8, true) !== numRows) {
+    throw new Error("Search index doesn't match the corpus");
+  }
+  const numDocs = dataView.getUint32(12, true);
+  const numTrigrams = dataView.getUint32(16, true);
+
+  let offset = 20;
+  const docRows: Uint32Array[] = new Array(numDocs);
+  for (let i = 0; i < numDocs; i++) {
+    const rowCount = dataView.getUint32(offset, true);
+    offset += 4;
+    docRows[i] = new Uint32Array(rowCount);
+    for (let j = 0; j < rowCount; j++) {
+      docRows[i][j] = dataView.getUint32(offset, true);
+      offset += 4;
+    }
+  }
+
+  const textDecoder = new TextDecoder();
+  const postings = new Map<string, { offset: number; docCount: number }>();
+  for (let i = 0; i < numTrigrams; i++) {
+    const length = bytes[offset];
+    const trigram = textDecoder.decode(bytes.subarray(offset + 1, offset + 1 + length));
+    offset += 1 + length;
+    const docCount = dataView.getUint32(offset, true);
+    const postingLength = dataView.getUint32(offset + 4, true);
+    offset += 8;
+    postings.set(trigram, { offset, docCount });
+    offset += postingLength;
+  }
+
+  return { docRows, postings, bytes };
+};
+
+const forEachPosting = (index: SearchIndex, trigram: string, cb: (docIx: number) => void) => {
+  const posting = index.postings.get(trigram);
+  if (!posting) {
+    return;
+  }
+
+  let offset = posting.offset;
+  let docIx = 0;
+  for (let i = 0; i < posting.docCount; i++) {
+    let delta = 0;
+    let shift = 0;
+    let byte: number;
+    do {
+      byte = index.bytes[offset++];
+      delta += (byte & 0x7f) * 2 ** shift;
+      shift += 7;
+    } while (byte & 0x80);
+    docIx += delta;
+    cb(docIx);
+  }
+};
+
+/**
+ * Returns the indices of the rows matching `query`, best matches first.  Documents are ranked by how many of the
+ * query's trigrams they contain and then by how many users have played them, the same as the corpus builder's `serve`.
+ */
+export const searchCorpus = (index: SearchIndex, corpus: Corpus, query: string, limit: number): number[] => {
+  const trigrams = queryTrigrams(query);
+  if (trigrams.length === 0) {
+    return [];
+  }
+  const minMatched = Math.max(1, Math.ceil(trigrams.length * MIN_MATCHED_TRIGRAM_FRACTION));
+
+  const matchedCountByDoc = new Map<number, number>();
+  for (const trigram of trigrams) {
+    forEachPosting(index, trigram, (docIx) => matchedCountByDoc.set(docIx, (matchedCountByDoc.get(docIx) ?? 0) + 1));
+  }
+
+  const popularity = (docIx: number) =>
+    index.docRows[docIx].reduce((acc, rowIx) => Math.max(acc, corpus[rowIx].numUsers), 0);
+  const matches: { docIx: number; matchedCount: number; popularity: number }[] = [];
+  for (const [docIx, matchedCount] of matchedCountByDoc) {
+    if (matchedCount >= minMatched) {
+      matches.push({ docIx, matchedCount, popularity: popularity(docIx) });
+    }
+  }
+  matches.sort((a, b) => b.matchedCount - a.matchedCount || b.popularity - a.popularity || b.docIx - a.docIx);
+
+  const rowIxs: number[] = [];
+  for (const { docIx } of matches) {
+    for (const rowIx of index.docRows[docIx]) {
+      if (rowIxs.length >= limit) {
+        return rowIxs;
+      }
+      rowIxs.push(rowIx);
+    }
+  }
+  return rowIxs;
+};
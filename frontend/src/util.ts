@@ -1,7 +1,6 @@
 import { browser } from '$app/environment';
 import { page } from '$app/stores';
 import { derived, get } from 'svelte/store';
-import { CorpusVersion, parseCorpusVersion } from './corpus';
 
 export const delay = (ms: number) => new Promise((resolve) => setTimeout(resolve, ms));
 
@@ -31,18 +30,17 @@ export const genRandomStringID =
   return x;
 };
 
-export const getCorpusVersionStore = () =>
-  derived(page, (page) => parseCorpusVersion(page.url.searchParams.get('version')));
+export const getCorpusVersionStore = () => derived(page, (page) => page.url.searchParams.get('version') || null);
 
-export const getCorpusVersion = (): CorpusVersion => {
+/**
+ * ID of the corpus release selected with the `version` URL parameter, or `null` for the latest release
+ */
+export const getCorpusVersion = (): string | null => {
   if (!browser) {
-    return CorpusVersion.Latest;
+    return null;
   }
 
-  const corpusVersionStore = getCorpusVersionStore();
-  const rawVersion = get(corpusVersionStore);
-  const corpusVersion = parseCorpusVersion(rawVersion);
-  return corpusVersion;
+  return get(getCorpusVersionStore());
 };
 
 declare global {
@@ -5,7 +5,7 @@ import { ColorMode, ColorModeConfigs } from '$lib';
 import { get, writable, type Writable } from 'svelte/store';
 import { getHiscoreIDsForUser, getUserID, submitAnalyticsEvent, updateUser } from '../api';
 import { buildColorLegend } from '../components/ColorLegend';
-import { getCorpusDefaultView, GlobalCorpus, type Corpus, type CorpusVersion, type ScoreMetadata } from '../corpus';
+import { getCorpusDefaultView, GlobalCorpus, type Corpus, type CorpusRelease, type ScoreMetadata } from '../corpus';
 import { clamp, mix, UnreachableError } from '../util';
 import { turboColormap } from './colormap';
 import circleFragShader from './shaders/circle.frag';
@@ -86,6 +86,10 @@ export class AtlasVizRegl {
   cachedCorpusPositions: number[] = [];
   private curRadii: number[] = [];
   public transformMatrix: mat3;
+  /**
+   * Set until the corpus has loaded and the initial transform matrix can be set to its default view
+   */
+  private needsDefaultView: boolean;
   private highlightedScoreIDs: Writable<Set<string> | null>;
   private hoveredScoreIx: number | null = null;
   private hoveredScoreLabel: { scoreIx: number; node: HTMLDivElement } | null = null;
@@ -108,7 +112,6 @@ export class AtlasVizRegl {
     highlightedScoreIDs: Writable<Set<string> | null>,
     filterState: FilterState,
     onCanvasClick: () => void,
-    corpusVersion: CorpusVersion,
     initialTransformMatrix?: mat3
   ) {
     this.canvas = canvas;
@@ -137,18 +140,10 @@ export class AtlasVizRegl {
 
     // Set up initial transform matrix for the world coordinates to viewport coordinates from [-1, -1] (botton left) to [1, 1] (top right)
     //
-    // The initial view spans from -5 to 5 in X with Y scaled to maintain aspect ratio.
-    this.transformMatrix =
-      initialTransformMatrix ??
-      (() => {
-        const { initialSpanX, initialCenter } = getCorpusDefaultView(corpusVersion);
-        const aspectRatio = canvas.clientWidth / canvas.clientHeight;
-        const initialSpanY = initialSpanX / aspectRatio;
-        const transformMatrix = mat3.create();
-        mat3.fromScaling(transformMatrix, [2 / initialSpanX, 2 / initialSpanY]);
-        mat3.translate(transformMatrix, transformMatrix, [-initialCenter[0], -initialCenter[1]]);
-        return transformMatrix;
-      })();
+    // The initial view is the release's default view with Y scaled to maintain aspect ratio, which is set once the
+    // corpus has loaded.
+    this.transformMatrix = initialTransformMatrix ?? mat3.create();
+    this.needsDefaultView = !initialTransformMatrix;
 
     this.setupInputHandlers(onCanvasClick);
 
@@ -489,12 +484,27 @@ export class AtlasVizRegl {
     this.updateHoveredScoreLabelPosition();
   }
 
+  private buildDefaultTransformMatrix(release: CorpusRelease): mat3 {
+    const { initialSpanX, initialCenter } = getCorpusDefaultView(release);
+    const aspectRatio = this.canvas.clientWidth / this.canvas.clientHeight;
+    const initialSpanY = initialSpanX / aspectRatio;
+    const transformMatrix = mat3.create();
+    mat3.fromScaling(transformMatrix, [2 / initialSpanX, 2 / initialSpanY]);
+    mat3.translate(transformMatrix, transformMatrix, [-initialCenter[0], -initialCenter[1]]);
+    return transformMatrix;
+  }
+
   private updateData(skipRadiiUpdate = false) {
     const fetchedCorpus = get(GlobalCorpus);
     if (fetchedCorpus.status !== 'loaded') {
       return;
     }
 
+    if (this.needsDefaultView) {
+      this.transformMatrix = this.buildDefaultTransformMatrix(fetchedCorpus.release);
+      this.needsDefaultView = false;
+    }
+
     const highlightedScoreIDs = get(this.highlightedScoreIDs);
     this.fullCorpus = fetchedCorpus.data;
     if (!this.sortedFullCorpus) {
@@ -0,0 +1,213 @@
+//! Aligns a freshly generated embedding to a reference corpus.
+//!
+//! Each new embedding comes out of PyMDE/Emblaze in an arbitrary rotation, reflection, and scale.
+//! To keep familiar regions of the atlas in the same place between releases, we find the
+//! similarity transform (rotation, optional reflection, uniform scale, and translation) which best
+//! maps the new positions onto the reference positions of the scores that both corpora share, in
+//! the least-squares sense, and apply it to every point in the new embedding.
+
+use std::path::Path;
+
+use foundations::telemetry::log::*;
+use fxhash::FxHashMap;
+
+use crate::build_corpus::{decode_corpus, CorpusRow};
+
+/// Minimum number of shared score IDs needed to produce a meaningful alignment
+const MIN_SHARED_POINTS: usize = 3;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SimilarityTransform {
+  /// Orthogonal matrix; includes the reflection if `reflected` is set
+  pub rotation: [[f64; 2]; 2],
+  pub scale: f64,
+  pub translation: [f64; 2],
+  pub reflected: bool,
+}
+
+impl SimilarityTransform {
+  pub fn apply(&self, [x, y]: [f32; 2]) -> [f32; 2] {
+    let (x, y) = (x as f64, y as f64);
+    let r = &self.rotation;
+    [
+      (self.scale * (r[0][0] * x + r[0][1] * y) + self.translation[0]) as f32,
+      (self.scale * (r[1][0] * x + r[1][1] * y) + self.translation[1]) as f32,
+    ]
+  }
+
+  pub fn rotation_degrees(&self) -> f64 {
+    // The reflection negates y before rotating, so the first column is the pure rotation
+    let col0 = [self.rotation[0][0], self.rotation[1][0]];
+    col0[1].atan2(col0[0]).to_degrees()
+  }
+}
+
+fn mean(points: &[[f32; 2]]) -> [f64; 2] {
+  let n = points.len() as f64;
+  let (sx, sy) = points
+    .iter()
+    .fold((0., 0.), |(sx, sy), p| (sx + p[0] as f64, sy + p[1] as f64));
+  [sx / n, sy / n]
+}
+
+/// Finds the similarity transform minimizing the squared distance between `transform(src[i])` and
+/// `dst[i]` over all pairs.  Returns `None` if there aren't enough points or they're degenerate.
+pub(crate) fn fit_similarity_transform(
+  src: &[[f32; 2]],
+  dst: &[[f32; 2]],
+) -> Option<SimilarityTransform> {
+  assert_eq!(src.len(), dst.len());
+  if src.len() < MIN_SHARED_POINTS {
+    return None;
+  }
+
+  let src_mean = mean(src);
+  let dst_mean = mean(dst);
+
+  // Sums of dot and cross products of the centered point pairs, computed both as-is and with the
+  // source reflected over the x axis.  In 2D these fully determine the optimal rotation.
+  let (mut dot, mut cross, mut dot_reflected, mut cross_reflected, mut src_sq_norm) =
+    (0., 0., 0., 0., 0.);
+  for (a, b) in src.iter().zip(dst) {
+    let (ax, ay) = (a[0] as f64 - src_mean[0], a[1] as f64 - src_mean[1]);
+    let (bx, by) = (b[0] as f64 - dst_mean[0], b[1] as f64 - dst_mean[1]);
+    dot += ax * bx + ay * by;
+    cross += ax * by - ay * bx;
+    dot_reflected += ax * bx - ay * by;
+    cross_reflected += ax * by + ay * bx;
+    src_sq_norm += ax * ax + ay * ay;
+  }
+  if src_sq_norm <= f64::EPSILON {
+    return None;
+  }
+
+  let fit = dot.hypot(cross);
+  let fit_reflected = dot_reflected.hypot(cross_reflected);
+  let reflected = fit_reflected > fit;
+  let (dot, cross, fit) = if reflected {
+    (dot_reflected, cross_reflected, fit_reflected)
+  } else {
+    (dot, cross, fit)
+  };
+
+  let theta = cross.atan2(dot);
+  let (sin, cos) = theta.sin_cos();
+  // Reflection is applied first by negating the source y coordinate
+  let flip = if reflected { -1. } else { 1. };
+  let rotation = [[cos, -sin * flip], [sin, cos * flip]];
+  let scale = fit / src_sq_norm;
+
+  let rotated_src_mean = [
+    rotation[0][0] * src_mean[0] + rotation[0][1] * src_mean[1],
+    rotation[1][0] * src_mean[0] + rotation[1][1] * src_mean[1],
+  ];
+  let translation = [
+    dst_mean[0] - scale * rotated_src_mean[0],
+    dst_mean[1] - scale * rotated_src_mean[1],
+  ];
+
+  Some(SimilarityTransform {
+    rotation,
+    scale,
+    translation,
+    reflected,
+  })
+}
+
+fn rms_distance(a: &[[f32; 2]], b: &[[f32; 2]]) -> f64 {
+  let sum_sq: f64 = a
+    .iter()
+    .zip(b)
+    .map(|(a, b)| {
+      let (dx, dy) = ((a[0] - b[0]) as f64, (a[1] - b[1]) as f64);
+      dx * dx + dy * dy
+    })
+    .sum();
+  (sum_sq / a.len().max(1) as f64).sqrt()
+}
+
+/// Fits a transform mapping `rows` onto `reference` using the scores they share and applies it to
+/// every row in `rows`.
+pub(crate) fn align_rows(
+  rows: &mut [CorpusRow],
+  reference: &[CorpusRow],
+) -> Result<SimilarityTransform, String> {
+  let reference_positions: FxHashMap<&str, [f32; 2]> = reference
+    .iter()
+    .map(|row| (row.score_id.as_str(), row.position))
+    .collect();
+  let (src, dst): (Vec<[f32; 2]>, Vec<[f32; 2]>) = rows
+    .iter()
+    .filter_map(|row| {
+      reference_positions
+        .get(row.score_id.as_str())
+        .map(|&ref_pos| (row.position, ref_pos))
+    })
+    .unzip();
+
+  let transform = fit_similarity_transform(&src, &dst).ok_or_else(|| {
+    format!(
+      "Can't align embedding; only {} score IDs are shared with the reference corpus",
+      src.len()
+    )
+  })?;
+
+  let aligned_src: Vec<[f32; 2]> = src.iter().map(|&p| transform.apply(p)).collect();
+  info!(
+    "Aligned embedding to reference using {} shared scores: rotation={:.1}°, reflected={}, \
+     scale={:.4}, RMS distance {:.3} -> {:.3}",
+    src.len(),
+    transform.rotation_degrees(),
+    transform.reflected,
+    transform.scale,
+    rms_distance(&src, &dst),
+    rms_distance(&aligned_src, &dst)
+  );
+
+  for row in rows.iter_mut() {
+    row.position = transform.apply(row.position);
+  }
+  Ok(transform)
+}
+
+/// Loads the reference corpus at `reference_path` and aligns `rows` to it.
+pub(crate) fn align_rows_to_corpus_file(
+  rows: &mut [CorpusRow],
+  reference_path: &Path,
+) -> Result<SimilarityTransform, String> {
+  let data = std::fs::read(reference_path).map_err(|err| {
+    format!(
+      "Failed to read reference corpus {}: {err}",
+      reference_path.display()
+    )
+  })?;
+  let reference = decode_corpus(&data)?;
+  align_rows(rows, &reference)
+}
+
+/// Transform a set of points with a known rotation, reflection, scale, and translation and make
+/// sure the fit recovers it
+#[test]
+fn fit_recovers_known_transform() {
+  let src: Vec<[f32; 2]> = vec![[0., 0.], [1., 0.], [0., 2.], [3., 1.], [-1., 4.]];
+  let (sin, cos) = 0.7f64.sin_cos();
+  let dst: Vec<[f32; 2]> = src
+    .iter()
+    .map(|&[x, y]| {
+      // reflect, rotate, scale by 2.5, then translate
+      let (x, y) = (x as f64, -y as f64);
+      [
+        (2.5 * (cos * x - sin * y) + 10.) as f32,
+        (2.5 * (sin * x + cos * y) - 3.) as f32,
+      ]
+    })
+    .collect();
+
+  let transform = fit_similarity_transform(&src, &dst).unwrap();
+  assert!(transform.reflected);
+  assert!((transform.scale - 2.5).abs() < 1e-4);
+  for (&s, d) in src.iter().zip(&dst) {
+    let aligned = transform.apply(s);
+    assert!((aligned[0] - d[0]).abs() < 1e-3 && (aligned[1] - d[1]).abs() < 1e-3);
+  }
+}
@@ -1,9 +1,15 @@
 //! Generates the binary corpus file which will be loaded by the frontend to display the embedding
 //! visualization and associated metadata.
 //!
-//! The corpus file is a custom binary format which starts with a [u32] indicating the number of
-//! items in the embedding.  This is then followed by that number of fixed-width rows.  After that,
-//! there is a dynamically sized region containing string data which is referenced by the data rows.
+//! The corpus file is a custom binary format which starts with a fixed-size header:
+//!
+//! [u8; 4] magic bytes `OSUA`
+//! [u32] format version
+//! [u32] number of items in the embedding
+//! [u32] number of strings in the string table
+//!
+//! This is then followed by that number of fixed-width rows.  After that, there is a dynamically
+//! sized region containing the string table which is referenced by the data rows.
 //!
 //! Each data row contains the following fields:
 //!
@@ -12,9 +18,9 @@
 //! [f32, f32] [x, y] coordinates of embedded point
 //! [f32] average pp
 //! [f32] star rating
-//! [u16] length of beatmap name from string region
-//! [u16] length of difficulty name from string region
-//! [u16] length of mapper name from string region
+//! [u32] index of beatmap name in string table
+//! [u32] index of difficulty name in string table
+//! [u32] index of mapper name in string table
 //! [u16] release year
 //! [u16] length seconds
 //! [u16] bpm
@@ -25,24 +31,100 @@
 //! [f32] speed difficulty
 //! [u32] beatmapset id
 //! [u16] user count
+//! [u16] flags; see [`ROW_FLAG_LOW_CONFIDENCE`]
+//! [u32] index of artist name in string table
+//! [u32] index of source in string table
+//! [u32] index of tags in string table
+//! [u16] drain time seconds
+//! [f32] HP
+//! [i8] ranked status, using the osu! API's `approved` values like 1 for ranked and 4 for loved
+//! [u8] game mode
+//! [u32] index of the title in its original script in string table, or [`NO_STRING`] if unset
+//! [u32] index of the artist name in its original script in string table, or [`NO_STRING`]
+//!
+//! The string table starts at (row_size_bytes) * (number_of_rows) + header_size bytes from the
+//! start of the file.  It contains a [u32] byte length for each string followed by the strings
+//! themselves, stored end to end as UTF-8 bytes in the same order.  Strings are _not_
+//! null-terminated.  Each distinct string is only stored once, so titles and mapper names shared
+//! between difficulties and mod variants don't get repeated.
+//!
+//! Version 4 corpus files are identical except that their rows end after the game mode, version 3
+//! corpus files' rows end after the flags field, and version 2 corpus files also don't have the
+//! flags field.  Fields which older versions don't have are decoded as empty or zero.
 //!
-//! The string region starts at (row_size_bytes) * (number_of_rows) + 4 bytes from the start of the
-//! file. Each string is _not_ null-terminated and is stored as a sequence of UTF-8 bytes end to
-//! end.  When parsing rows, a pointer should be initialized to the start of the string region and
-//! incremented by the length of each string field in each row as they are parsed.
+//! Legacy (version 1) corpus files have no header and start directly with the [u32] item count.
+//! They store [u16] string lengths in place of the string indices and write every row's strings
+//! inline.
 
 use std::path::Path;
 
 use chrono::{DateTime, Datelike, Utc};
 use foundations::telemetry::log::*;
-use fxhash::FxHashMap;
+use fxhash::{FxHashMap, FxHashSet};
 use parquet::{
   file::reader::{FileReader, SerializedFileReader},
   record::RowAccessor,
 };
 use rosu_mods::{GameMod, GameMode, GameMods};
 
-use crate::{DifficultyRecord, ScoreMetadata};
+use crate::{osu_metadata::OsuFileMetadata, DifficultyRecord, ScoreMetadata};
+
+pub(crate) const CORPUS_MAGIC: [u8; 4] = *b"OSUA";
+pub(crate) const CORPUS_FORMAT_VERSION: u32 = 5;
+const CORPUS_ROW_SIZE: usize = 98;
+const V4_CORPUS_ROW_SIZE: usize = 90;
+const V3_CORPUS_ROW_SIZE: usize = 70;
+const V2_CORPUS_ROW_SIZE: usize = 68;
+const LEGACY_CORPUS_ROW_SIZE: usize = 62;
+
+/// Set for points which were placed into an existing embedding by `place-new` rather than being
+/// embedded along with everything else, so their positions are only approximate
+pub(crate) const ROW_FLAG_LOW_CONFIDENCE: u16 = 1 << 0;
+
+/// String table index stored for optional strings which aren't set
+pub(crate) const NO_STRING: u32 = u32::MAX;
+
+pub(crate) struct BuiltCorpus {
+  pub data: Vec<u8>,
+  pub num_items: u32,
+  /// Rows in the same order as they're stored in `data`
+  pub rows: Vec<CorpusRow>,
+}
+
+/// Deduplicates strings referenced by corpus rows so that each distinct value is only stored once.
+#[derive(Default)]
+struct StringTable {
+  ix_by_string: FxHashMap<String, u32>,
+  strings: Vec<String>,
+}
+
+impl StringTable {
+  fn intern(&mut self, s: &str) -> u32 {
+    if let Some(&ix) = self.ix_by_string.get(s) {
+      return ix;
+    }
+
+    let ix = self.strings.len() as u32;
+    self.strings.push(s.to_owned());
+    self.ix_by_string.insert(s.to_owned(), ix);
+    ix
+  }
+
+  fn len(&self) -> usize { self.strings.len() }
+
+  fn encoded_size(&self) -> usize {
+    self.strings.len() * 4 + self.strings.iter().map(String::len).sum::<usize>()
+  }
+
+  fn write(&self, buf: &mut Vec<u8>) {
+    for s in &self.strings {
+      buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    }
+    for s in &self.strings {
+      buf.extend_from_slice(s.as_bytes());
+    }
+  }
+}
 
 // beatmap metadata:
 // +------------------+--------------+------+-----+---------+-------+
@@ -74,30 +156,31 @@ use crate::{DifficultyRecord, ScoreMetadata};
 struct BeatmapMetadata {
   // id: i32,
   beatmapset_id: i32,
+  #[allow(dead_code)]
   beatmap_id: i32,
-  // approved: i32,
+  approved: i32,
   approved_date: Option<DateTime<Utc>>,
   // last_update: String,
   total_length: i32,
-  // hit_length: i32,
+  hit_length: i32,
   version: String,
-  // artist: String,
+  artist: String,
   title: String,
   creator: String,
   bpm: i32,
-  // source: String,
+  source: String,
+  #[allow(dead_code)]
   difficultyrating: f64,
   diff_size: i32,
   diff_overall: i32,
   diff_approach: i32,
-  // diff_drain: i32,
-  // mode: i32,
+  diff_drain: i32,
+  mode: i32,
 }
 
-fn read_beatmap_metadata() -> FxHashMap<i32, BeatmapMetadata> {
-  let beatmap_metadata_fname = Path::new("../../data/beatmaps.parquet");
-  let beatmap_metadata_file =
-    std::fs::File::open(beatmap_metadata_fname).expect("Failed to open beatmap metadata file");
+fn read_beatmap_metadata(beatmaps_path: &Path) -> FxHashMap<i32, BeatmapMetadata> {
+  let beatmap_metadata_file = std::fs::File::open(beatmaps_path)
+    .unwrap_or_else(|err| panic!("Failed to open {}: {err}", beatmaps_path.display()));
   let beatmap_metadata_reader = SerializedFileReader::new(beatmap_metadata_file).unwrap();
   let parquet_metadata = beatmap_metadata_reader.metadata();
   assert_eq!(parquet_metadata.num_row_groups(), 1);
@@ -108,9 +191,14 @@ fn read_beatmap_metadata() -> FxHashMap<i32, BeatmapMetadata> {
   let mut beatmap_metadata_by_id = FxHashMap::default();
   for row_res in beatmap_metadata_row_group.get_row_iter(None).unwrap() {
     let row = row_res.unwrap();
+    // Columns which were added for the optional metadata can be null in the dump, in which case
+    // they're left empty or zero
+    let optional_long = |ix: usize| row.get_long(ix).unwrap_or(0);
+    let optional_string = |ix: usize| row.get_string(ix).cloned().unwrap_or_default();
     // skip the first column which is the id
     let beatmapset_id = row.get_long(1).unwrap();
     let beatmap_id = row.get_long(2).unwrap();
+    let approved = optional_long(3);
     let approved_date_ts_nanos = row.get_long(4).unwrap_or(0);
     let approved_date = if approved_date_ts_nanos == 0 {
       None
@@ -118,28 +206,39 @@ fn read_beatmap_metadata() -> FxHashMap<i32, BeatmapMetadata> {
       Some(chrono::DateTime::from_timestamp(approved_date_ts_nanos / 1_000_000_000, 0).unwrap())
     };
     let total_length = row.get_long(6).unwrap();
+    let hit_length = optional_long(7);
     let version = row.get_string(8).unwrap().clone();
+    let artist = optional_string(9);
     let title = row.get_string(10).unwrap().clone();
     let creator = row.get_string(11).unwrap().clone();
     let bpm = row.get_long(12).unwrap();
+    let source = optional_string(13);
     let difficultyrating = row.get_double(14).unwrap();
     let diff_size = row.get_long(15).unwrap();
     let diff_overall = row.get_long(16).unwrap();
     let diff_approach = row.get_long(17).unwrap();
+    let diff_drain = optional_long(18);
+    let mode = optional_long(19);
 
     let beatmap_metadata = BeatmapMetadata {
       beatmapset_id: beatmapset_id.try_into().unwrap(),
       beatmap_id: beatmap_id.try_into().unwrap(),
+      approved: approved.try_into().unwrap(),
       approved_date,
       total_length: total_length.try_into().unwrap(),
+      hit_length: hit_length.try_into().unwrap(),
       version,
+      artist,
       title,
       creator,
       bpm: bpm.try_into().unwrap(),
-      difficultyrating: difficultyrating.try_into().unwrap(),
+      source,
+      difficultyrating,
       diff_size: diff_size.try_into().unwrap(),
       diff_overall: diff_overall.try_into().unwrap(),
       diff_approach: diff_approach.try_into().unwrap(),
+      diff_drain: diff_drain.try_into().unwrap(),
+      mode: mode.try_into().unwrap(),
     };
 
     beatmap_metadata_by_id.insert(beatmap_id.try_into().unwrap(), beatmap_metadata);
@@ -148,37 +247,143 @@ fn read_beatmap_metadata() -> FxHashMap<i32, BeatmapMetadata> {
   beatmap_metadata_by_id
 }
 
-pub(crate) async fn build_corpus(score_metadata: Vec<ScoreMetadata>) -> Vec<u8> {
+/// A single fully joined corpus entry, combining the embedding position with score, beatmap, and
+/// difficulty metadata.
+pub(crate) struct CorpusRow {
+  pub score_id: String,
+  pub beatmap_id: i32,
+  pub beatmapset_id: i32,
+  pub mods_bits: u32,
+  pub position: [f32; 2],
+  pub avg_pp: f64,
+  pub num_users: i32,
+  pub stars: f64,
+  pub aim_difficulty: f64,
+  pub speed_difficulty: f64,
+  pub title: String,
+  pub version: String,
+  pub creator: String,
+  /// Title in its original script from the `.osu` file, which is often Japanese or Korean
+  pub title_unicode: Option<String>,
+  pub artist: String,
+  pub artist_unicode: Option<String>,
+  pub source: String,
+  /// Space-separated tags from the `.osu` file
+  pub tags: String,
+  /// The osu! API's `approved` value, like 1 for ranked and 4 for loved
+  pub ranked_status: i32,
+  pub release_year: Option<u16>,
+  pub length_seconds: i32,
+  /// Length excluding breaks and the lead-in before the first object
+  pub drain_seconds: i32,
+  pub bpm: i32,
+  pub ar: f32,
+  pub cs: f32,
+  pub od: f32,
+  pub hp: f32,
+  pub mode: i32,
+  pub low_confidence: bool,
+}
+
+/// Mods which are treated as distinct entries in the embedding, in the order in which their
+/// acronyms appear in score IDs.
+pub(crate) const SCORE_ID_MODS: [(&str, u32); 5] =
+  [("DT", 64), ("EZ", 2), ("FL", 1024), ("HR", 16), ("HT", 256)];
+
+/// Builds a score ID like `856861_DT` from a beatmap ID and mods bitmask.
+pub(crate) fn score_id_from_parts(beatmap_id: i32, mods_bits: u32) -> String {
+  let mut score_id = format!("{beatmap_id}_");
+  for (acronym, bit) in SCORE_ID_MODS {
+    if mods_bits & bit != 0 {
+      score_id.push_str(acronym);
+    }
+  }
+  score_id
+}
+
+impl CorpusRow {
+  /// The mods portion of the score ID, like `DTHR`
+  pub fn mods_str(&self) -> &str { self.score_id.split_once('_').map_or("", |(_, mods)| mods) }
+
+  /// Ranked row with placeholder metadata, for tests to override the fields that they care about
+  #[cfg(test)]
+  pub fn test_row(beatmap_id: i32, mods_bits: u32) -> Self {
+    CorpusRow {
+      score_id: score_id_from_parts(beatmap_id, mods_bits),
+      beatmap_id,
+      beatmapset_id: beatmap_id,
+      mods_bits,
+      position: [0., 0.],
+      avg_pp: 100.,
+      num_users: 10,
+      stars: 5.,
+      aim_difficulty: 2.,
+      speed_difficulty: 2.,
+      title: String::new(),
+      version: String::new(),
+      creator: String::new(),
+      title_unicode: None,
+      artist: String::new(),
+      artist_unicode: None,
+      source: String::new(),
+      tags: String::new(),
+      ranked_status: 1,
+      release_year: None,
+      length_seconds: 0,
+      drain_seconds: 0,
+      bpm: 0,
+      ar: 0.,
+      cs: 0.,
+      od: 0.,
+      hp: 0.,
+      mode: 0,
+      low_confidence: false,
+    }
+  }
+}
+
+/// Joins the embedding at `embedding_path` with score metadata, the beatmap metadata at
+/// `beatmaps_path`, metadata from the `.osu` files, and difficulties to produce one row per
+/// embedded point.  Embedded scores without score metadata are an error unless
+/// `allow_missing_metadata` is set, in which case they're skipped.
+pub(crate) async fn build_corpus_rows(
+  score_metadata: Vec<ScoreMetadata>,
+  difficulties: Vec<DifficultyRecord>,
+  osu_metadata: FxHashMap<i32, OsuFileMetadata>,
+  embedding_path: &Path,
+  beatmaps_path: &Path,
+  allow_missing_metadata: bool,
+) -> Vec<CorpusRow> {
   let score_metadata_by_id: FxHashMap<String, ScoreMetadata> = score_metadata
     .into_iter()
     .map(|sm| (sm.score_id.clone(), sm))
     .collect();
 
-  let beatmap_metadata_by_id = tokio::task::block_in_place(|| read_beatmap_metadata());
+  let beatmap_metadata_by_id = tokio::task::block_in_place(|| read_beatmap_metadata(beatmaps_path));
 
-  let embedding_file = tokio::fs::read("../../data/embedding_new_2.json")
+  let embedding_file = tokio::fs::read(embedding_path)
     .await
-    .expect("Failed to read embedding file");
+    .unwrap_or_else(|err| panic!("Failed to read {}: {err}", embedding_path.display()));
   // Embedding is in format score_id -> [x, y]
   let embedding: FxHashMap<String, [f32; 2]> =
     serde_json::from_slice(&embedding_file).expect("Failed to parse embedding file");
 
-  let mut strings_buffer = String::new();
-  let mut corpus_buffer = Vec::new();
-
-  let num_items = embedding.len() as u32;
-  corpus_buffer.extend_from_slice(&num_items.to_le_bytes());
-
-  let difficulties: Vec<DifficultyRecord> = crate::load_difficulties().await;
   let mut difficulties_by_score_id: FxHashMap<String, DifficultyRecord> = difficulties
     .into_iter()
     .map(|dr| (dr.score_id.clone(), dr))
     .collect();
 
+  let mut rows = Vec::with_capacity(embedding.len());
+  let mut missing_metadata_count = 0usize;
+  let mut missing_osu_metadata_beatmap_ids = FxHashSet::default();
   for (score_id, embedding) in embedding {
-    let score_metadata = score_metadata_by_id
-      .get(&score_id)
-      .expect("Failed to find score metadata");
+    let Some(score_metadata) = score_metadata_by_id.get(&score_id) else {
+      if !allow_missing_metadata {
+        panic!("Failed to find score metadata for {score_id}");
+      }
+      missing_metadata_count += 1;
+      continue;
+    };
 
     let (beatmap_id, mods_str) = score_metadata.score_id.split_once('_').unwrap();
     let beatmap_id: i32 = beatmap_id.parse().unwrap();
@@ -194,10 +399,11 @@ pub(crate) async fn build_corpus(score_metadata: Vec<ScoreMetadata>) -> Vec<u8>
     let beatmap_metadata = beatmap_metadata_by_id
       .get(&beatmap_id)
       .unwrap_or_else(|| panic!("Failed to find beatmap metadata for beatmap {beatmap_id}"));
-    let release_year: u16 = beatmap_metadata
-      .approved_date
-      .map(|dt| dt.year() as u16)
-      .unwrap_or(0);
+    let release_year = beatmap_metadata.approved_date.map(|dt| dt.year() as u16);
+    let osu_metadata = osu_metadata.get(&beatmap_id).cloned().unwrap_or_else(|| {
+      missing_osu_metadata_beatmap_ids.insert(beatmap_id);
+      OsuFileMetadata::default()
+    });
 
     // TODO: Temp until all difficulties are computed
     if !difficulties_by_score_id.contains_key(&score_id) {
@@ -217,33 +423,446 @@ pub(crate) async fn build_corpus(score_metadata: Vec<ScoreMetadata>) -> Vec<u8>
       .get(&score_id)
       .unwrap_or_else(|| panic!("Failed to find difficulty record for score {score_id}"));
 
-    corpus_buffer.extend_from_slice(&beatmap_id.to_le_bytes());
-    corpus_buffer.extend_from_slice(&mods_bits.to_le_bytes());
-    corpus_buffer.extend_from_slice(&embedding[0].to_le_bytes());
-    corpus_buffer.extend_from_slice(&embedding[1].to_le_bytes());
-    corpus_buffer.extend_from_slice(&(score_metadata.avg_pp as f32).to_le_bytes());
-    corpus_buffer.extend_from_slice(&(difficulties.stars as f32).to_le_bytes());
-    corpus_buffer.extend_from_slice(&(beatmap_metadata.title.len() as u16).to_le_bytes());
-    corpus_buffer.extend_from_slice(&(beatmap_metadata.version.len() as u16).to_le_bytes());
-    corpus_buffer.extend_from_slice(&(beatmap_metadata.creator.len() as u16).to_le_bytes());
-    corpus_buffer.extend_from_slice(&release_year.to_le_bytes());
-    corpus_buffer.extend_from_slice(&(beatmap_metadata.total_length as u16).to_le_bytes());
-    corpus_buffer.extend_from_slice(&(beatmap_metadata.bpm as u16).to_le_bytes());
-    corpus_buffer.extend_from_slice(&(beatmap_metadata.diff_approach as f32).to_le_bytes());
-    corpus_buffer.extend_from_slice(&(beatmap_metadata.diff_size as f32).to_le_bytes());
-    corpus_buffer.extend_from_slice(&(beatmap_metadata.diff_overall as f32).to_le_bytes());
-    corpus_buffer.extend_from_slice(&(difficulties.difficulty_aim as f32).to_le_bytes());
-    corpus_buffer.extend_from_slice(&(difficulties.difficulty_speed as f32).to_le_bytes());
-    corpus_buffer.extend_from_slice(&(beatmap_metadata.beatmapset_id as u32).to_le_bytes());
-    corpus_buffer.extend_from_slice(&(score_metadata.num_users as u16).to_le_bytes());
-
-    strings_buffer.push_str(&beatmap_metadata.title);
-    strings_buffer.push_str(&beatmap_metadata.version);
-    strings_buffer.push_str(&beatmap_metadata.creator);
-  }
-
-  corpus_buffer.extend_from_slice(strings_buffer.as_bytes());
+    rows.push(CorpusRow {
+      score_id,
+      beatmap_id,
+      beatmapset_id: beatmap_metadata.beatmapset_id,
+      mods_bits,
+      position: embedding,
+      avg_pp: score_metadata.avg_pp,
+      num_users: score_metadata.num_users,
+      stars: difficulties.stars,
+      aim_difficulty: difficulties.difficulty_aim,
+      speed_difficulty: difficulties.difficulty_speed,
+      title: beatmap_metadata.title.clone(),
+      version: beatmap_metadata.version.clone(),
+      creator: beatmap_metadata.creator.clone(),
+      title_unicode: osu_metadata.title_unicode,
+      artist: beatmap_metadata.artist.clone(),
+      artist_unicode: osu_metadata.artist_unicode,
+      source: beatmap_metadata.source.clone(),
+      tags: osu_metadata.tags,
+      ranked_status: beatmap_metadata.approved,
+      release_year,
+      length_seconds: beatmap_metadata.total_length,
+      drain_seconds: beatmap_metadata.hit_length,
+      bpm: beatmap_metadata.bpm,
+      ar: beatmap_metadata.diff_approach as f32,
+      cs: beatmap_metadata.diff_size as f32,
+      od: beatmap_metadata.diff_overall as f32,
+      hp: beatmap_metadata.diff_drain as f32,
+      mode: beatmap_metadata.mode,
+      low_confidence: false,
+    });
+  }
+
+  if missing_metadata_count > 0 {
+    info!("Skipped {missing_metadata_count} embedded scores without score metadata");
+  }
+  if !missing_osu_metadata_beatmap_ids.is_empty() {
+    warn!(
+      "{} beatmaps have no downloaded .osu file, so their tags and unicode names are empty",
+      missing_osu_metadata_beatmap_ids.len()
+    );
+  }
+  rows
+}
+
+/// Serializes corpus rows into the binary corpus format described in the module docs.
+pub(crate) fn encode_corpus(rows: Vec<CorpusRow>) -> BuiltCorpus {
+  let mut string_table = StringTable::default();
+  let mut inline_strings_size = 0usize;
+  let mut corpus_buffer = Vec::new();
+
+  let num_items = rows.len() as u32;
+
+  for row in &rows {
+    corpus_buffer.extend_from_slice(&row.beatmap_id.to_le_bytes());
+    corpus_buffer.extend_from_slice(&row.mods_bits.to_le_bytes());
+    corpus_buffer.extend_from_slice(&row.position[0].to_le_bytes());
+    corpus_buffer.extend_from_slice(&row.position[1].to_le_bytes());
+    corpus_buffer.extend_from_slice(&(row.avg_pp as f32).to_le_bytes());
+    corpus_buffer.extend_from_slice(&(row.stars as f32).to_le_bytes());
+    corpus_buffer.extend_from_slice(&string_table.intern(&row.title).to_le_bytes());
+    corpus_buffer.extend_from_slice(&string_table.intern(&row.version).to_le_bytes());
+    corpus_buffer.extend_from_slice(&string_table.intern(&row.creator).to_le_bytes());
+    corpus_buffer.extend_from_slice(&row.release_year.unwrap_or(0).to_le_bytes());
+    corpus_buffer.extend_from_slice(&(row.length_seconds as u16).to_le_bytes());
+    corpus_buffer.extend_from_slice(&(row.bpm as u16).to_le_bytes());
+    corpus_buffer.extend_from_slice(&row.ar.to_le_bytes());
+    corpus_buffer.extend_from_slice(&row.cs.to_le_bytes());
+    corpus_buffer.extend_from_slice(&row.od.to_le_bytes());
+    corpus_buffer.extend_from_slice(&(row.aim_difficulty as f32).to_le_bytes());
+    corpus_buffer.extend_from_slice(&(row.speed_difficulty as f32).to_le_bytes());
+    corpus_buffer.extend_from_slice(&(row.beatmapset_id as u32).to_le_bytes());
+    corpus_buffer.extend_from_slice(&(row.num_users as u16).to_le_bytes());
+    let flags = if row.low_confidence {
+      ROW_FLAG_LOW_CONFIDENCE
+    } else {
+      0
+    };
+    corpus_buffer.extend_from_slice(&flags.to_le_bytes());
+    corpus_buffer.extend_from_slice(&string_table.intern(&row.artist).to_le_bytes());
+    corpus_buffer.extend_from_slice(&string_table.intern(&row.source).to_le_bytes());
+    corpus_buffer.extend_from_slice(&string_table.intern(&row.tags).to_le_bytes());
+    corpus_buffer.extend_from_slice(&(row.drain_seconds as u16).to_le_bytes());
+    corpus_buffer.extend_from_slice(&row.hp.to_le_bytes());
+    corpus_buffer.push(row.ranked_status as i8 as u8);
+    corpus_buffer.push(row.mode as u8);
+    for optional in [&row.title_unicode, &row.artist_unicode] {
+      let ix = optional
+        .as_deref()
+        .map_or(NO_STRING, |s| string_table.intern(s));
+      corpus_buffer.extend_from_slice(&ix.to_le_bytes());
+    }
+
+    inline_strings_size += row.title.len()
+      + row.version.len()
+      + row.creator.len()
+      + row.artist.len()
+      + row.source.len()
+      + row.tags.len()
+      + row.title_unicode.as_deref().map_or(0, str::len)
+      + row.artist_unicode.as_deref().map_or(0, str::len);
+  }
+
+  debug_assert_eq!(corpus_buffer.len(), rows.len() * CORPUS_ROW_SIZE);
+
+  let string_table_size = string_table.encoded_size();
+  info!(
+    "Interned {} unique strings into {string_table_size} bytes vs. {inline_strings_size} bytes \
+     stored inline ({:.1}% smaller)",
+    string_table.len(),
+    (1. - string_table_size as f64 / inline_strings_size.max(1) as f64) * 100.
+  );
+
+  let mut out = Vec::with_capacity(16 + corpus_buffer.len() + string_table_size);
+  out.extend_from_slice(&CORPUS_MAGIC);
+  out.extend_from_slice(&CORPUS_FORMAT_VERSION.to_le_bytes());
+  out.extend_from_slice(&num_items.to_le_bytes());
+  out.extend_from_slice(&(string_table.len() as u32).to_le_bytes());
+  out.extend_from_slice(&corpus_buffer);
+  string_table.write(&mut out);
 
   info!("Built corpus with {} items", num_items);
-  corpus_buffer
+  BuiltCorpus {
+    data: out,
+    num_items,
+    rows,
+  }
+}
+
+/// Builds the corpus.  If `align_to` is provided, the embedding is first rotated, reflected,
+/// scaled, and translated to best match the positions in that reference corpus.  Rows for score
+/// IDs listed in the `placements` file written by `place-new` are flagged as low confidence.
+/// Time-sliced builds set `allow_missing_metadata` since they only have metadata for the scores
+/// which had been played by then.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn build_corpus(
+  score_metadata: Vec<ScoreMetadata>,
+  difficulties: Vec<DifficultyRecord>,
+  osu_metadata: FxHashMap<i32, OsuFileMetadata>,
+  embedding_path: &Path,
+  beatmaps_path: &Path,
+  align_to: Option<&Path>,
+  placements: Option<&Path>,
+  allow_missing_metadata: bool,
+) -> BuiltCorpus {
+  let mut rows = build_corpus_rows(
+    score_metadata,
+    difficulties,
+    osu_metadata,
+    embedding_path,
+    beatmaps_path,
+    allow_missing_metadata,
+  )
+  .await;
+  if let Some(placements_path) = placements {
+    let placed = crate::place_new::read_placed_score_ids(placements_path)
+      .unwrap_or_else(|err| panic!("{err}"));
+    let mut flagged_count = 0usize;
+    for row in &mut rows {
+      if placed.contains(&row.score_id) {
+        row.low_confidence = true;
+        flagged_count += 1;
+      }
+    }
+    info!("Flagged {flagged_count} placed rows as low confidence");
+  }
+  if let Some(reference_path) = align_to {
+    crate::align::align_rows_to_corpus_file(&mut rows, reference_path)
+      .unwrap_or_else(|err| panic!("{err}"));
+  }
+  encode_corpus(rows)
+}
+
+/// Reads little-endian values sequentially out of a corpus file.
+struct CorpusReader<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> CorpusReader<'a> {
+  fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+    let bytes = self.data.get(self.pos..self.pos + len).ok_or_else(|| {
+      format!(
+        "Corpus is truncated; expected {len} bytes at offset {}",
+        self.pos
+      )
+    })?;
+    self.pos += len;
+    Ok(bytes)
+  }
+
+  fn u8(&mut self) -> Result<u8, String> { Ok(self.take(1)?[0]) }
+
+  fn u16(&mut self) -> Result<u16, String> {
+    Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+  }
+
+  fn u32(&mut self) -> Result<u32, String> {
+    Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+  }
+
+  fn f32(&mut self) -> Result<f32, String> { Ok(f32::from_bits(self.u32()?)) }
+
+  /// Reads a string table index and looks it up in `strings`
+  fn string_ref(&mut self, strings: &[String]) -> Result<String, String> {
+    self
+      .optional_string_ref(strings)?
+      .ok_or_else(|| format!("Missing required string at offset {}", self.pos - 4))
+  }
+
+  /// Like [`CorpusReader::string_ref`], but for optional strings which may be [`NO_STRING`]
+  fn optional_string_ref(&mut self, strings: &[String]) -> Result<Option<String>, String> {
+    let ix = self.u32()?;
+    if ix == NO_STRING {
+      return Ok(None);
+    }
+    strings
+      .get(ix as usize)
+      .cloned()
+      .map(Some)
+      .ok_or_else(|| format!("String index {ix} out of range"))
+  }
+
+  fn string(&mut self, len: usize) -> Result<String, String> {
+    String::from_utf8(self.take(len)?.to_vec())
+      .map_err(|err| format!("Invalid corpus string: {err}"))
+  }
+}
+
+/// Format version of an encoded corpus.  Corpora from before the header was added are version 1.
+pub(crate) fn corpus_format_version(data: &[u8]) -> u32 {
+  match data.get(CORPUS_MAGIC.len()..CORPUS_MAGIC.len() + 4) {
+    Some(version) if data.starts_with(&CORPUS_MAGIC) =>
+      u32::from_le_bytes(version.try_into().unwrap()),
+    _ => 1,
+  }
+}
+
+/// Parses a corpus file in either the current or the legacy format back into rows.
+pub(crate) fn decode_corpus(data: &[u8]) -> Result<Vec<CorpusRow>, String> {
+  let is_versioned = data.starts_with(&CORPUS_MAGIC);
+  let mut reader = CorpusReader { data, pos: 0 };
+
+  let mut format_version = 1;
+  let (num_items, string_table) = if is_versioned {
+    reader.take(CORPUS_MAGIC.len())?;
+    format_version = reader.u32()?;
+    let row_size = match format_version {
+      2 => V2_CORPUS_ROW_SIZE,
+      3 => V3_CORPUS_ROW_SIZE,
+      4 => V4_CORPUS_ROW_SIZE,
+      CORPUS_FORMAT_VERSION => CORPUS_ROW_SIZE,
+      _ =>
+        return Err(format!(
+          "Unsupported corpus format version {format_version}"
+        )),
+    };
+    let num_items = reader.u32()? as usize;
+    let num_strings = reader.u32()? as usize;
+
+    // The string table sits after all of the rows, so read it up front with a separate reader
+    let mut strings_reader = CorpusReader {
+      data,
+      pos: reader.pos + num_items * row_size,
+    };
+    let lengths = (0..num_strings)
+      .map(|_| strings_reader.u32())
+      .collect::<Result<Vec<_>, _>>()?;
+    let strings = lengths
+      .into_iter()
+      .map(|len| strings_reader.string(len as usize))
+      .collect::<Result<Vec<_>, _>>()?;
+    (num_items, Some(strings))
+  } else {
+    (reader.u32()? as usize, None)
+  };
+
+  let mut inline_strings_reader = CorpusReader {
+    data,
+    pos: reader.pos + num_items * LEGACY_CORPUS_ROW_SIZE,
+  };
+
+  let mut rows = Vec::with_capacity(num_items);
+  for _ in 0..num_items {
+    let beatmap_id = reader.u32()? as i32;
+    let mods_bits = reader.u32()?;
+    let position = [reader.f32()?, reader.f32()?];
+    let avg_pp = reader.f32()? as f64;
+    let stars = reader.f32()? as f64;
+    let (title, version, creator) = match &string_table {
+      Some(strings) => (
+        reader.string_ref(strings)?,
+        reader.string_ref(strings)?,
+        reader.string_ref(strings)?,
+      ),
+      None => {
+        let lens = [reader.u16()?, reader.u16()?, reader.u16()?];
+        (
+          inline_strings_reader.string(lens[0] as usize)?,
+          inline_strings_reader.string(lens[1] as usize)?,
+          inline_strings_reader.string(lens[2] as usize)?,
+        )
+      },
+    };
+    let release_year = Some(reader.u16()?).filter(|&year| year != 0);
+    let length_seconds = reader.u16()? as i32;
+    let bpm = reader.u16()? as i32;
+    let ar = reader.f32()?;
+    let cs = reader.f32()?;
+    let od = reader.f32()?;
+    let aim_difficulty = reader.f32()? as f64;
+    let speed_difficulty = reader.f32()? as f64;
+    let beatmapset_id = reader.u32()? as i32;
+    let num_users = reader.u16()? as i32;
+    let flags = if format_version >= 3 {
+      reader.u16()?
+    } else {
+      0
+    };
+    let mut artist = String::new();
+    let mut source = String::new();
+    let mut tags = String::new();
+    let mut drain_seconds = 0;
+    let mut hp = 0.;
+    let mut ranked_status = 0;
+    let mut mode = 0;
+    let mut title_unicode = None;
+    let mut artist_unicode = None;
+    if let (4.., Some(strings)) = (format_version, &string_table) {
+      artist = reader.string_ref(strings)?;
+      source = reader.string_ref(strings)?;
+      tags = reader.string_ref(strings)?;
+      drain_seconds = reader.u16()? as i32;
+      hp = reader.f32()?;
+      ranked_status = reader.u8()? as i8 as i32;
+      mode = reader.u8()? as i32;
+      if format_version >= 5 {
+        title_unicode = reader.optional_string_ref(strings)?;
+        artist_unicode = reader.optional_string_ref(strings)?;
+      }
+    }
+
+    rows.push(CorpusRow {
+      score_id: score_id_from_parts(beatmap_id, mods_bits),
+      beatmap_id,
+      beatmapset_id,
+      mods_bits,
+      position,
+      avg_pp,
+      num_users,
+      stars,
+      aim_difficulty,
+      speed_difficulty,
+      title,
+      version,
+      creator,
+      title_unicode,
+      artist,
+      artist_unicode,
+      source,
+      tags,
+      ranked_status,
+      release_year,
+      length_seconds,
+      drain_seconds,
+      bpm,
+      ar,
+      cs,
+      od,
+      hp,
+      mode,
+      low_confidence: flags & ROW_FLAG_LOW_CONFIDENCE != 0,
+    });
+  }
+
+  Ok(rows)
+}
+
+/// Encode a couple of rows which share strings, decode them, and make sure everything survives the
+/// round trip
+#[test]
+fn corpus_encode_decode_roundtrip() {
+  let row = |beatmap_id: i32, mods_bits: u32, version: &str| CorpusRow {
+    score_id: score_id_from_parts(beatmap_id, mods_bits),
+    beatmap_id,
+    beatmapset_id: 39804,
+    mods_bits,
+    position: [1.5, -2.25],
+    avg_pp: 123.5,
+    num_users: 420,
+    stars: 5.25,
+    aim_difficulty: 2.5,
+    speed_difficulty: 2.,
+    title: "FREEDOM DiVE".to_owned(),
+    version: version.to_owned(),
+    creator: "Nakagawa-Kanon".to_owned(),
+    title_unicode: (mods_bits == 0).then(|| "FREEDOM DiVE↓".to_owned()),
+    artist: "xi".to_owned(),
+    artist_unicode: None,
+    source: "BMS".to_owned(),
+    tags: "parousia dive".to_owned(),
+    ranked_status: if mods_bits == 0 { 1 } else { -2 },
+    release_year: Some(2012),
+    length_seconds: 267,
+    drain_seconds: 257,
+    bpm: 222,
+    ar: 9.,
+    cs: 4.,
+    od: 8.,
+    hp: 6.,
+    mode: 0,
+    low_confidence: mods_bits != 0,
+  };
+  let rows = vec![
+    row(129891, 0, "FOUR DIMENSIONS"),
+    row(129891, 64 | 16, "Another"),
+  ];
+
+  let built = encode_corpus(rows);
+  let decoded = decode_corpus(&built.data).unwrap();
+
+  assert_eq!(decoded.len(), 2);
+  for (original, decoded) in built.rows.iter().zip(&decoded) {
+    assert_eq!(original.score_id, decoded.score_id);
+    assert_eq!(original.mods_bits, decoded.mods_bits);
+    assert_eq!(original.position, decoded.position);
+    assert_eq!(original.title, decoded.title);
+    assert_eq!(original.version, decoded.version);
+    assert_eq!(original.creator, decoded.creator);
+    assert_eq!(original.artist, decoded.artist);
+    assert_eq!(original.title_unicode, decoded.title_unicode);
+    assert_eq!(original.artist_unicode, decoded.artist_unicode);
+    assert_eq!(original.source, decoded.source);
+    assert_eq!(original.tags, decoded.tags);
+    assert_eq!(original.ranked_status, decoded.ranked_status);
+    assert_eq!(original.drain_seconds, decoded.drain_seconds);
+    assert_eq!(original.hp, decoded.hp);
+    assert_eq!(original.release_year, decoded.release_year);
+    assert_eq!(original.num_users, decoded.num_users);
+    assert_eq!(original.low_confidence, decoded.low_confidence);
+  }
+  assert_eq!(decoded[1].score_id, "129891_DTHR");
 }
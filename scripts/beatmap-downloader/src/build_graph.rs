@@ -0,0 +1,171 @@
+//! Builds the score co-occurrence graph that the embedding is generated from, replacing the pandas
+//! implementation in `embed.ipynb`.
+//!
+//! Each pair of scores in a user's top plays contributes a weight based on how close their pp
+//! values are.  Weights are summed over all users, and then each score keeps only its strongest
+//! connections.  The output is written twice: once with raw weights, which `build-corpus
+//! --cooccurrence` reads, and once with weights transformed into the distances that are passed to
+//! PyMDE.
+
+use std::path::Path;
+
+use foundations::telemetry::log::*;
+use fxhash::FxHashMap;
+
+use crate::{
+  cooccurrence::{write_cooccurrence_graph, CooccurrenceGraph},
+  hiscores::{dedupe_hiscores, read_hiscores, Hiscore},
+};
+
+/// Scores whose plays differ by more than this fraction of the larger pp value don't contribute
+/// any weight to each other
+const MAX_RELATIVE_PP_DIFF: f32 = 0.5;
+/// Summed co-occurrence weights at or below this value are pruned
+const MIN_WEIGHT: f32 = 2.;
+
+/// There are far fewer very high pp plays than low pp plays, so scores are filtered more strictly
+/// at low pp.  Scores need 50 unique users at 100pp and below, scaling linearly down to 5 users
+/// at 500pp.
+fn should_retain(avg_pp: f64, num_users: usize) -> bool {
+  let (edge0, edge1) = (100., 500.);
+  let activation = ((avg_pp - edge0) / (edge1 - edge0)).clamp(0., 1.);
+  let threshold = 50. - (50. - 5.) * activation;
+  num_users as f64 >= threshold
+}
+
+/// 0% pp difference -> 2, 25% difference -> 1, 50% or more difference -> 0
+fn pair_weight(pp_i: f32, pp_j: f32) -> f32 {
+  let relative_diff = (pp_i - pp_j).abs() / pp_i.max(pp_j);
+  if relative_diff > MAX_RELATIVE_PP_DIFF {
+    0.
+  } else {
+    (1. - relative_diff / MAX_RELATIVE_PP_DIFF) * 2.
+  }
+}
+
+/// Same transform as `embed.ipynb`: larger weights map to smaller distances
+pub(crate) fn weight_to_distance(weight: f32) -> f32 { weight.ln().recip() }
+
+/// Builds the pruned co-occurrence graph from deduplicated plays sorted by user.
+pub(crate) fn build_cooccurrence_graph(plays: &[Hiscore], top_n: usize) -> CooccurrenceGraph {
+  // (total pp, user count) for each score
+  let mut stats_by_score: FxHashMap<u64, (f64, usize)> = FxHashMap::default();
+  for play in plays {
+    let stats = stats_by_score.entry(play.score_key()).or_default();
+    stats.0 += play.pp as f64;
+    stats.1 += 1;
+  }
+
+  // Indices are assigned in order of first appearance, like `df['score_id'].unique()`
+  let mut index_by_score: FxHashMap<u64, u32> = FxHashMap::default();
+  let mut score_ids = Vec::new();
+  let mut retained_plays: Vec<(u32, u32, f32)> = Vec::new();
+  for play in plays {
+    let key = play.score_key();
+    let (total_pp, num_users) = stats_by_score[&key];
+    if !should_retain(total_pp / num_users as f64, num_users) {
+      continue;
+    }
+    let ix = *index_by_score.entry(key).or_insert_with(|| {
+      score_ids.push(play.score_id());
+      score_ids.len() as u32 - 1
+    });
+    retained_plays.push((play.user, ix, play.pp));
+  }
+  info!(
+    "Retained {} plays of {} unique scores after filtering",
+    retained_plays.len(),
+    score_ids.len()
+  );
+
+  // Weights for each unordered pair, which is equivalent to the symmetrized matrix
+  let mut pair_weights: FxHashMap<u64, f32> = FxHashMap::default();
+  for user_plays in retained_plays.chunk_by(|a, b| a.0 == b.0) {
+    for (i, &(_, ix_i, pp_i)) in user_plays.iter().enumerate() {
+      for &(_, ix_j, pp_j) in &user_plays[i + 1..] {
+        let weight = pair_weight(pp_i, pp_j);
+        if weight <= 0. {
+          continue;
+        }
+        let (lo, hi) = (ix_i.min(ix_j), ix_i.max(ix_j));
+        *pair_weights
+          .entry(((lo as u64) << 32) | hi as u64)
+          .or_default() += weight;
+      }
+    }
+  }
+  info!(
+    "Symmetrized co-occurrence matrix nnz: {}",
+    pair_weights.len() * 2
+  );
+
+  let mut adjacency: Vec<Vec<(u32, f32)>> = vec![Vec::new(); score_ids.len()];
+  for (key, weight) in pair_weights {
+    let (lo, hi) = ((key >> 32) as u32, key as u32);
+    adjacency[lo as usize].push((hi, weight));
+    adjacency[hi as usize].push((lo, weight));
+  }
+  for row in &mut adjacency {
+    row.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    row.truncate(top_n);
+    row.retain(|&(_, weight)| weight > MIN_WEIGHT);
+    row.sort_unstable_by_key(|&(ix, _)| ix);
+  }
+  info!(
+    "Top co-occurrence matrix nnz: {}",
+    adjacency.iter().map(Vec::len).sum::<usize>()
+  );
+
+  CooccurrenceGraph {
+    score_ids,
+    adjacency,
+  }
+}
+
+/// Reads hiscores from `hiscores_path` and writes the co-occurrence graph built from them as raw
+/// weights to `weights_path` and as PyMDE distances to `distances_path`.
+pub(crate) fn build_graph(
+  hiscores_path: &Path,
+  weights_path: &Path,
+  distances_path: &Path,
+  top_n: usize,
+) -> Result<(), String> {
+  let plays = dedupe_hiscores(read_hiscores(hiscores_path)?);
+  let graph = build_cooccurrence_graph(&plays, top_n);
+  write_cooccurrence_graph(weights_path, &graph, |weight| weight)?;
+  write_cooccurrence_graph(distances_path, &graph, weight_to_distance)
+}
+
+/// Checks filtering, pair weights, and pruning on a handful of synthetic plays
+#[test]
+fn cooccurrence_graph_matches_notebook_rules() {
+  let play = |user: u32, beatmap_id: i32, pp: f32| Hiscore {
+    user,
+    beatmap_id,
+    mods_bits: 0,
+    pp,
+    update_time: 0,
+    score_time: None,
+    grade: None,
+    accuracy: None,
+  };
+  // Maps 1 and 2 are played by 10 users at 600pp, so they pass the 5 user threshold.  Map 3 only
+  // has 4 users and is dropped.
+  let mut plays = Vec::new();
+  for user in 0..10 {
+    plays.push(play(user, 1, 600.));
+    plays.push(play(user, 2, if user < 5 { 600. } else { 750. }));
+    if user < 4 {
+      plays.push(play(user, 3, 600.));
+    }
+  }
+  let graph = build_cooccurrence_graph(&dedupe_hiscores(plays), 700);
+
+  assert_eq!(graph.score_ids, vec!["1_", "2_"]);
+  // 5 users * 2 + 5 users * (1 - 0.2 / 0.5) * 2
+  let expected = 10. + 5. * 1.2;
+  assert_eq!(graph.adjacency[0].len(), 1);
+  assert_eq!(graph.adjacency[0][0].0, 1);
+  assert!((graph.adjacency[0][0].1 - expected).abs() < 1e-4);
+  assert!((graph.adjacency[1][0].1 - expected).abs() < 1e-4);
+}
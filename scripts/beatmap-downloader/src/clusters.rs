@@ -0,0 +1,418 @@
+//! Detects dense regions of the embedding with DBSCAN and generates a descriptive label for each
+//! one based on the maps it contains, like "DT streams, 5.9★, 200 BPM, 2016-era".
+//!
+//! Two files are produced.  `clusters.json` contains metadata for each cluster.  `clusters` is a
+//! binary sidecar with the cluster ID of each corpus row:
+//!
+//! [u8; 4] magic bytes `OSUC`
+//! [u32] format version
+//! [u32] number of rows; matches the corpus that it was built alongside
+//! [u16] cluster ID for each row, in corpus row order.  Rows which don't belong to any cluster
+//! have a cluster ID of [`NOISE`].
+
+use foundations::telemetry::log::*;
+use fxhash::FxHashMap;
+use serde::Serialize;
+
+use crate::{build_corpus::CorpusRow, neighbors::embedding_neighbors};
+
+pub(crate) const CLUSTERS_MAGIC: [u8; 4] = *b"OSUC";
+pub(crate) const CLUSTERS_FORMAT_VERSION: u32 = 1;
+pub(crate) const NOISE: u16 = u16::MAX;
+
+const DT_BIT: u32 = 64;
+const HT_BIT: u32 = 256;
+
+/// Share of a cluster's rows that a mod combination or mapper needs to have in order to be
+/// mentioned in its label
+const DOMINANT_SHARE: f64 = 0.5;
+const TOP_MAPPER_SHARE: f64 = 0.2;
+const TOP_MAPPERS_COUNT: usize = 5;
+
+/// Aim/speed difficulty ratios above and below which a cluster is labeled as jumps or streams
+const JUMPS_RATIO: f64 = 1.15;
+const STREAMS_RATIO: f64 = 0.9;
+
+pub(crate) struct ClusterParams {
+  pub min_points: usize,
+  /// Neighborhood radius.  If not provided, it's set to the median distance from each point to
+  /// its `min_points`-th nearest neighbor.
+  pub eps: Option<f32>,
+}
+
+/// Parses a `--cluster-eps` value.  Anything but a positive, finite radius makes degenerate grid
+/// cells.
+pub(crate) fn parse_eps(value: &str) -> Result<f32, String> {
+  match value.parse::<f32>() {
+    Ok(eps) if eps > 0. && eps.is_finite() => Ok(eps),
+    _ => Err(format!("`{value}` isn't a positive, finite radius")),
+  }
+}
+
+/// Buckets points into square cells of size `eps` so that all points within `eps` of a given
+/// point can be found by only checking the 3x3 block of cells around it.
+struct Grid {
+  eps: f32,
+  cells: FxHashMap<(i32, i32), Vec<u32>>,
+}
+
+impl Grid {
+  fn new(positions: &[[f32; 2]], eps: f32) -> Self {
+    let mut cells: FxHashMap<(i32, i32), Vec<u32>> = FxHashMap::default();
+    for (ix, &pos) in positions.iter().enumerate() {
+      cells
+        .entry(Self::cell(pos, eps))
+        .or_default()
+        .push(ix as u32);
+    }
+    Grid { eps, cells }
+  }
+
+  fn cell([x, y]: [f32; 2], eps: f32) -> (i32, i32) {
+    ((x / eps).floor() as i32, (y / eps).floor() as i32)
+  }
+
+  fn neighbors(&self, positions: &[[f32; 2]], ix: usize, out: &mut Vec<u32>) {
+    out.clear();
+    let pos = positions[ix];
+    let (cx, cy) = Self::cell(pos, self.eps);
+    let eps_sq = self.eps * self.eps;
+    for dx in -1..=1 {
+      for dy in -1..=1 {
+        let Some(cell) = self.cells.get(&(cx + dx, cy + dy)) else {
+          continue;
+        };
+        out.extend(cell.iter().copied().filter(|&other| {
+          let other_pos = positions[other as usize];
+          let (ddx, ddy) = (other_pos[0] - pos[0], other_pos[1] - pos[1]);
+          ddx * ddx + ddy * ddy <= eps_sq
+        }));
+      }
+    }
+  }
+}
+
+fn default_eps(positions: &[[f32; 2]], min_points: usize) -> f32 {
+  let k = min_points.saturating_sub(1).max(1);
+  let mut k_distances: Vec<f32> = embedding_neighbors(positions, k)
+    .iter()
+    .zip(positions)
+    .filter_map(|(neighbors, &pos)| {
+      let furthest = positions[*neighbors.last()? as usize];
+      Some((furthest[0] - pos[0]).hypot(furthest[1] - pos[1]))
+    })
+    .collect();
+  if k_distances.is_empty() {
+    return 1.;
+  }
+  k_distances.sort_unstable_by(f32::total_cmp);
+  k_distances[k_distances.len() / 2].max(f32::EPSILON)
+}
+
+/// Runs DBSCAN and returns the cluster ID of each point, or [`NOISE`] for points which aren't
+/// part of any cluster.  Clusters are numbered in descending order of size.
+pub(crate) fn dbscan(positions: &[[f32; 2]], params: &ClusterParams) -> Vec<u16> {
+  let eps = params
+    .eps
+    .unwrap_or_else(|| default_eps(positions, params.min_points));
+  let grid = Grid::new(positions, eps);
+
+  const UNVISITED: u32 = u32::MAX;
+  const NOISE_LABEL: u32 = u32::MAX - 1;
+  let mut labels = vec![UNVISITED; positions.len()];
+  let mut cluster_count = 0u32;
+  let mut neighbors = Vec::new();
+  let mut frontier = Vec::new();
+
+  for ix in 0..positions.len() {
+    if labels[ix] != UNVISITED {
+      continue;
+    }
+    grid.neighbors(positions, ix, &mut neighbors);
+    if neighbors.len() < params.min_points {
+      labels[ix] = NOISE_LABEL;
+      continue;
+    }
+
+    let cluster_id = cluster_count;
+    cluster_count += 1;
+    labels[ix] = cluster_id;
+    frontier.clear();
+    frontier.extend_from_slice(&neighbors);
+
+    while let Some(other) = frontier.pop() {
+      let other = other as usize;
+      match labels[other] {
+        // border point previously thought to be noise
+        NOISE_LABEL => labels[other] = cluster_id,
+        UNVISITED => {
+          labels[other] = cluster_id;
+          grid.neighbors(positions, other, &mut neighbors);
+          if neighbors.len() >= params.min_points {
+            frontier.extend_from_slice(&neighbors);
+          }
+        },
+        _ => (),
+      }
+    }
+  }
+
+  // Renumber so that the largest cluster is 0, dropping any that don't fit in a u16
+  let mut sizes = vec![0usize; cluster_count as usize];
+  for &label in &labels {
+    if label < cluster_count {
+      sizes[label as usize] += 1;
+    }
+  }
+  let mut by_size: Vec<u32> = (0..cluster_count).collect();
+  by_size.sort_unstable_by_key(|&id| std::cmp::Reverse(sizes[id as usize]));
+  let mut new_ids = vec![NOISE; cluster_count as usize];
+  for (new_id, &old_id) in by_size.iter().take(NOISE as usize).enumerate() {
+    new_ids[old_id as usize] = new_id as u16;
+  }
+
+  labels
+    .into_iter()
+    .map(|label| {
+      if label < cluster_count {
+        new_ids[label as usize]
+      } else {
+        NOISE
+      }
+    })
+    .collect()
+}
+
+#[derive(Serialize)]
+struct MapperCount {
+  creator: String,
+  count: usize,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ClusterMetadata {
+  id: u16,
+  label: String,
+  size: usize,
+  centroid: [f32; 2],
+  /// Share of rows by mod combination, keyed by the mods portion of the score ID (`""` for
+  /// no mods)
+  mod_shares: FxHashMap<String, f64>,
+  mean_stars: f64,
+  /// Mean BPM after accounting for DT/HT speed changes
+  mean_bpm: f64,
+  median_release_year: Option<u16>,
+  mean_aim_speed_ratio: f64,
+  top_mappers: Vec<MapperCount>,
+  total_users: i64,
+}
+
+fn effective_bpm(row: &CorpusRow) -> f64 {
+  let multiplier = if row.mods_bits & DT_BIT != 0 {
+    1.5
+  } else if row.mods_bits & HT_BIT != 0 {
+    0.75
+  } else {
+    1.
+  };
+  row.bpm as f64 * multiplier
+}
+
+/// Mean ratio of aim to speed difficulty, ignoring rows without a speed difficulty.  Above 1 leans
+/// towards jumps and below towards streams.
+pub(crate) fn mean_aim_speed_ratio(rows: &[&CorpusRow]) -> f64 {
+  let ratios: Vec<f64> = rows
+    .iter()
+    .filter(|row| row.speed_difficulty > 0.)
+    .map(|row| row.aim_difficulty / row.speed_difficulty)
+    .collect();
+  if ratios.is_empty() {
+    1.
+  } else {
+    ratios.iter().sum::<f64>() / ratios.len() as f64
+  }
+}
+
+fn summarize_cluster(id: u16, members: &[&CorpusRow]) -> ClusterMetadata {
+  let n = members.len() as f64;
+  let mean = |f: &dyn Fn(&CorpusRow) -> f64| members.iter().map(|row| f(row)).sum::<f64>() / n;
+
+  let mut mod_counts: FxHashMap<String, usize> = FxHashMap::default();
+  let mut mapper_counts: FxHashMap<&str, usize> = FxHashMap::default();
+  for row in members {
+    *mod_counts.entry(row.mods_str().to_owned()).or_default() += 1;
+    *mapper_counts.entry(row.creator.as_str()).or_default() += 1;
+  }
+  let mut top_mappers: Vec<MapperCount> = mapper_counts
+    .into_iter()
+    .map(|(creator, count)| MapperCount {
+      creator: creator.to_owned(),
+      count,
+    })
+    .collect();
+  top_mappers.sort_unstable_by(|a, b| b.count.cmp(&a.count).then(a.creator.cmp(&b.creator)));
+  top_mappers.truncate(TOP_MAPPERS_COUNT);
+
+  let mut years: Vec<u16> = members.iter().filter_map(|row| row.release_year).collect();
+  years.sort_unstable();
+  let median_release_year = years.get(years.len() / 2).copied();
+
+  let mut metadata = ClusterMetadata {
+    id,
+    label: String::new(),
+    size: members.len(),
+    centroid: [
+      mean(&|row| row.position[0] as f64) as f32,
+      mean(&|row| row.position[1] as f64) as f32,
+    ],
+    mod_shares: mod_counts
+      .into_iter()
+      .map(|(mods, count)| (mods, count as f64 / n))
+      .collect(),
+    mean_stars: mean(&|row| row.stars),
+    mean_bpm: mean(&effective_bpm),
+    median_release_year,
+    mean_aim_speed_ratio: mean_aim_speed_ratio(members),
+    top_mappers,
+    total_users: members.iter().map(|row| row.num_users as i64).sum(),
+  };
+  metadata.label = generate_label(&metadata);
+  metadata
+}
+
+fn generate_label(cluster: &ClusterMetadata) -> String {
+  let dominant_mods = cluster
+    .mod_shares
+    .iter()
+    .filter(|&(_, &share)| share >= DOMINANT_SHARE)
+    // two combinations can each have exactly half of the rows, so break ties by name rather than
+    // by hash order
+    .max_by(|(a_mods, a_share), (b_mods, b_share)| {
+      a_share.total_cmp(b_share).then_with(|| b_mods.cmp(a_mods))
+    })
+    .map(|(mods, _)| if mods.is_empty() { "NoMod" } else { mods.as_str() })
+    .unwrap_or("mixed mods");
+  let style = if cluster.mean_aim_speed_ratio >= JUMPS_RATIO {
+    "jumps"
+  } else if cluster.mean_aim_speed_ratio <= STREAMS_RATIO {
+    "streams"
+  } else {
+    "mixed aim/speed"
+  };
+
+  let mut label = format!(
+    "{dominant_mods} {style}, {:.1}★, {:.0} BPM",
+    cluster.mean_stars, cluster.mean_bpm
+  );
+  if let Some(year) = cluster.median_release_year {
+    label.push_str(&format!(", {year}-era"));
+  }
+  if let Some(top_mapper) = cluster.top_mappers.first() {
+    if top_mapper.count as f64 / cluster.size as f64 >= TOP_MAPPER_SHARE {
+      label.push_str(&format!(", mostly by {}", top_mapper.creator));
+    }
+  }
+  label
+}
+
+pub(crate) struct Clustering {
+  pub cluster_ids: Vec<u16>,
+  pub clusters: Vec<ClusterMetadata>,
+}
+
+pub(crate) fn cluster_rows(rows: &[CorpusRow], params: &ClusterParams) -> Clustering {
+  let positions: Vec<[f32; 2]> = rows.iter().map(|row| row.position).collect();
+  let cluster_ids = dbscan(&positions, params);
+
+  let cluster_count = cluster_ids
+    .iter()
+    .filter(|&&id| id != NOISE)
+    .map(|&id| id as usize + 1)
+    .max()
+    .unwrap_or(0);
+  let mut members: Vec<Vec<&CorpusRow>> = vec![Vec::new(); cluster_count];
+  for (row, &id) in rows.iter().zip(&cluster_ids) {
+    if id != NOISE {
+      members[id as usize].push(row);
+    }
+  }
+
+  let clusters: Vec<ClusterMetadata> = members
+    .iter()
+    .enumerate()
+    .map(|(id, members)| summarize_cluster(id as u16, members))
+    .collect();
+
+  let noise_count = cluster_ids.iter().filter(|&&id| id == NOISE).count();
+  info!(
+    "Found {} clusters; {noise_count} of {} rows aren't in any cluster",
+    clusters.len(),
+    rows.len()
+  );
+  for cluster in clusters.iter().take(10) {
+    info!(
+      "  #{} ({} rows): {}",
+      cluster.id, cluster.size, cluster.label
+    );
+  }
+  Clustering {
+    cluster_ids,
+    clusters,
+  }
+}
+
+pub(crate) fn encode_cluster_ids(cluster_ids: &[u16]) -> Vec<u8> {
+  let mut buf = Vec::with_capacity(12 + cluster_ids.len() * 2);
+  buf.extend_from_slice(&CLUSTERS_MAGIC);
+  buf.extend_from_slice(&CLUSTERS_FORMAT_VERSION.to_le_bytes());
+  buf.extend_from_slice(&(cluster_ids.len() as u32).to_le_bytes());
+  for &id in cluster_ids {
+    buf.extend_from_slice(&id.to_le_bytes());
+  }
+  buf
+}
+
+/// Two well-separated blobs of different sizes plus a far-away outlier
+#[test]
+fn dbscan_separates_blobs() {
+  let mut positions = Vec::new();
+  for i in 0..10 {
+    for j in 0..10 {
+      positions.push([i as f32 * 0.1, j as f32 * 0.1]);
+    }
+  }
+  for i in 0..5 {
+    for j in 0..5 {
+      positions.push([50. + i as f32 * 0.1, j as f32 * 0.1]);
+    }
+  }
+  positions.push([-100., -100.]);
+
+  let params = ClusterParams {
+    min_points: 4,
+    eps: Some(0.15),
+  };
+  let ids = dbscan(&positions, &params);
+  assert!(ids[..100].iter().all(|&id| id == 0));
+  assert!(ids[100..125].iter().all(|&id| id == 1));
+  assert_eq!(ids[125], NOISE);
+}
+
+/// Radii which would make degenerate grid cells are rejected
+#[test]
+fn eps_must_be_positive_and_finite() {
+  assert_eq!(parse_eps("0.25"), Ok(0.25));
+  for value in ["0", "-1", "NaN", "inf", "wide"] {
+    assert!(parse_eps(value).is_err(), "{value}");
+  }
+}
+
+/// A cluster split evenly between two mod combinations gets the same label every time
+#[test]
+fn cluster_label_breaks_dominant_mod_ties() {
+  for mods_bits in [[0, 64], [64, 0]] {
+    let rows = mods_bits.map(|mods_bits| CorpusRow::test_row(1, mods_bits));
+    let metadata = summarize_cluster(0, &[&rows[0], &rows[1]]);
+    assert!(metadata.label.starts_with("NoMod "), "{}", metadata.label);
+  }
+}
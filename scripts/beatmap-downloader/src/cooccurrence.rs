@@ -0,0 +1,170 @@
+//! Loads the sparse score co-occurrence graph that the embedding is built from.
+//!
+//! The graph is stored as a [MatrixMarket](https://math.nist.gov/MatrixMarket/formats.html)
+//! coordinate file, which can be written and read directly by `scipy.io.mmwrite`/`mmread`,
+//! alongside a `{matrix file name}.csv` with `score_id,index` columns mapping matrix indices to
+//! score IDs.  This is the same mapping convention that `embed.ipynb` uses for its embeddings.
+//!
+//! Entries are co-occurrence weights where larger values mean the two scores show up together in
+//! users' top plays more often.  [`write_cooccurrence_graph`] can also write transformed values,
+//! such as the distances that PyMDE is given.
+
+use std::{
+  fs::File,
+  io::{BufRead, BufReader, BufWriter, Write},
+  path::{Path, PathBuf},
+};
+
+use foundations::telemetry::log::*;
+
+pub(crate) struct CooccurrenceGraph {
+  pub score_ids: Vec<String>,
+  /// `(neighbor index, weight)` pairs for each score
+  pub adjacency: Vec<Vec<(u32, f32)>>,
+}
+
+fn read_score_id_mapping(path: &Path) -> Result<Vec<String>, String> {
+  let mut rdr = csv::Reader::from_path(path)
+    .map_err(|err| format!("Failed to open {}: {err}", path.display()))?;
+  let headers = rdr.headers().map_err(|err| format!("{err}"))?.clone();
+  let col = |name: &str| crate::header_index(&headers, name, path);
+  let (score_id_col, index_col) = (col("score_id")?, col("index")?);
+
+  let mut entries = Vec::new();
+  for record in rdr.records() {
+    let record = record.map_err(|err| format!("{err}"))?;
+    let index: usize = record[index_col]
+      .parse()
+      .map_err(|err| format!("Invalid index in {}: {err}", path.display()))?;
+    entries.push((index, record[score_id_col].to_owned()));
+  }
+
+  let mut score_ids = vec![String::new(); entries.len()];
+  for (index, score_id) in entries {
+    *score_ids
+      .get_mut(index)
+      .ok_or_else(|| format!("Index {index} out of range in {}", path.display()))? = score_id;
+  }
+  Ok(score_ids)
+}
+
+pub(crate) fn score_id_mapping_path(matrix_path: &Path) -> PathBuf {
+  let mut path = matrix_path.as_os_str().to_owned();
+  path.push(".csv");
+  path.into()
+}
+
+pub(crate) fn read_cooccurrence_graph(matrix_path: &Path) -> Result<CooccurrenceGraph, String> {
+  let score_ids = read_score_id_mapping(&score_id_mapping_path(matrix_path))?;
+
+  let file = File::open(matrix_path)
+    .map_err(|err| format!("Failed to open {}: {err}", matrix_path.display()))?;
+  let mut lines = BufReader::new(file).lines();
+  let header = lines
+    .next()
+    .ok_or_else(|| format!("{} is empty", matrix_path.display()))?
+    .map_err(|err| format!("{err}"))?;
+  if !header.starts_with("%%MatrixMarket matrix coordinate") {
+    return Err(format!(
+      "{} is not a MatrixMarket coordinate file",
+      matrix_path.display()
+    ));
+  }
+  let symmetric = header.contains("symmetric");
+
+  let mut adjacency: Vec<Vec<(u32, f32)>> = vec![Vec::new(); score_ids.len()];
+  let mut seen_size_line = false;
+  for line in lines {
+    let line = line.map_err(|err| format!("{err}"))?;
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('%') {
+      continue;
+    }
+    let mut parts = line.split_ascii_whitespace();
+    if !seen_size_line {
+      let rows: usize = parts.next().and_then(|p| p.parse().ok()).unwrap_or(0);
+      if rows != score_ids.len() {
+        return Err(format!(
+          "{} has {rows} rows but there are {} score IDs",
+          matrix_path.display(),
+          score_ids.len()
+        ));
+      }
+      seen_size_line = true;
+      continue;
+    }
+
+    let (Some(i), Some(j), Some(weight)) = (
+      parts.next().and_then(|p| p.parse::<usize>().ok()),
+      parts.next().and_then(|p| p.parse::<usize>().ok()),
+      parts.next().and_then(|p| p.parse::<f32>().ok()),
+    ) else {
+      return Err(format!("Invalid matrix entry: {line}"));
+    };
+    // MatrixMarket indices are 1-based
+    let n = score_ids.len();
+    let (i, j) = match (i.checked_sub(1), j.checked_sub(1)) {
+      (Some(i), Some(j)) if i < n && j < n => (i, j),
+      _ => return Err(format!("Matrix entry out of range: {line}")),
+    };
+    adjacency[i].push((j as u32, weight));
+    if symmetric && i != j {
+      adjacency[j].push((i as u32, weight));
+    }
+  }
+
+  info!(
+    "Loaded co-occurrence graph with {} scores and {} edges",
+    score_ids.len(),
+    adjacency.iter().map(Vec::len).sum::<usize>()
+  );
+  Ok(CooccurrenceGraph {
+    score_ids,
+    adjacency,
+  })
+}
+
+/// Writes `graph` as a MatrixMarket coordinate file at `matrix_path` along with its score ID
+/// mapping.  Each stored weight is passed through `map_value` before being written.
+pub(crate) fn write_cooccurrence_graph(
+  matrix_path: &Path,
+  graph: &CooccurrenceGraph,
+  map_value: impl Fn(f32) -> f32,
+) -> Result<(), String> {
+  let write_err =
+    |path: &Path, err: std::io::Error| format!("Failed to write {}: {err}", path.display());
+
+  let file = File::create(matrix_path).map_err(|err| write_err(matrix_path, err))?;
+  let mut writer = BufWriter::new(file);
+  let n = graph.score_ids.len();
+  let nnz: usize = graph.adjacency.iter().map(Vec::len).sum();
+  writeln!(writer, "%%MatrixMarket matrix coordinate real general")
+    .and_then(|_| writeln!(writer, "{n} {n} {nnz}"))
+    .map_err(|err| write_err(matrix_path, err))?;
+  for (i, row) in graph.adjacency.iter().enumerate() {
+    for &(j, weight) in row {
+      writeln!(writer, "{} {} {}", i + 1, j + 1, map_value(weight))
+        .map_err(|err| write_err(matrix_path, err))?;
+    }
+  }
+  writer.flush().map_err(|err| write_err(matrix_path, err))?;
+
+  let mapping_path = score_id_mapping_path(matrix_path);
+  let mut wtr = csv::Writer::from_path(&mapping_path)
+    .map_err(|err| format!("Failed to create {}: {err}", mapping_path.display()))?;
+  wtr
+    .write_record(["score_id", "index"])
+    .map_err(|err| format!("{err}"))?;
+  for (index, score_id) in graph.score_ids.iter().enumerate() {
+    wtr
+      .write_record([score_id.as_str(), &index.to_string()])
+      .map_err(|err| format!("{err}"))?;
+  }
+  wtr.flush().map_err(|err| write_err(&mapping_path, err))?;
+
+  info!(
+    "Wrote co-occurrence matrix with {n} scores and {nnz} entries to {}",
+    matrix_path.display()
+  );
+  Ok(())
+}
@@ -0,0 +1,319 @@
+//! Compares two corpus releases and reports which scores were added or removed, which moved a
+//! significant distance in the embedding, and how their stats changed.
+
+use std::{fmt::Write as _, path::Path};
+
+use foundations::telemetry::log::*;
+use fxhash::FxHashMap;
+use serde::Serialize;
+
+use crate::{
+  align::align_rows,
+  build_corpus::{decode_corpus, CorpusRow},
+  manifest::compute_bounds,
+};
+
+/// Number of entries of each change type listed in the human-readable report.  The JSON output
+/// always contains every change.
+const REPORT_TOP_N: usize = 25;
+
+pub(crate) struct DiffThresholds {
+  /// Minimum distance moved, as a fraction of the new embedding's bounding box diagonal
+  pub move_fraction: f32,
+  /// Minimum absolute change in star rating
+  pub stars: f64,
+  /// Minimum relative change in average pp
+  pub avg_pp_fraction: f64,
+  /// Minimum relative change in user count
+  pub num_users_fraction: f64,
+}
+
+#[derive(Serialize)]
+struct CorpusSummary {
+  path: String,
+  row_count: usize,
+}
+
+#[derive(Serialize)]
+struct ScoreEntry {
+  score_id: String,
+  title: String,
+  version: String,
+  creator: String,
+  stars: f64,
+  avg_pp: f64,
+  num_users: i32,
+}
+
+impl From<&CorpusRow> for ScoreEntry {
+  fn from(row: &CorpusRow) -> Self {
+    ScoreEntry {
+      score_id: row.score_id.clone(),
+      title: row.title.clone(),
+      version: row.version.clone(),
+      creator: row.creator.clone(),
+      stars: row.stars,
+      avg_pp: row.avg_pp,
+      num_users: row.num_users,
+    }
+  }
+}
+
+#[derive(Serialize)]
+struct PositionMove {
+  score_id: String,
+  from: [f32; 2],
+  to: [f32; 2],
+  distance: f32,
+}
+
+#[derive(Serialize)]
+struct ValueChange<T> {
+  score_id: String,
+  old: T,
+  new: T,
+  delta: T,
+}
+
+#[derive(Serialize)]
+struct CorpusDiff {
+  old: CorpusSummary,
+  new: CorpusSummary,
+  added: Vec<ScoreEntry>,
+  removed: Vec<ScoreEntry>,
+  moved: Vec<PositionMove>,
+  star_rating_changes: Vec<ValueChange<f64>>,
+  avg_pp_changes: Vec<ValueChange<f64>>,
+  num_users_changes: Vec<ValueChange<i32>>,
+}
+
+fn relative_change(old: f64, new: f64) -> f64 { (new - old).abs() / old.abs().max(1.) }
+
+fn diff_rows(
+  old: CorpusSummary,
+  new: CorpusSummary,
+  old_rows: &[CorpusRow],
+  new_rows: &[CorpusRow],
+  thresholds: &DiffThresholds,
+) -> CorpusDiff {
+  let old_by_id: FxHashMap<&str, &CorpusRow> =
+    old_rows.iter().map(|r| (r.score_id.as_str(), r)).collect();
+  let new_by_id: FxHashMap<&str, &CorpusRow> =
+    new_rows.iter().map(|r| (r.score_id.as_str(), r)).collect();
+
+  let new_positions: Vec<[f32; 2]> = new_rows.iter().map(|r| r.position).collect();
+  let bounds = compute_bounds(&new_positions);
+  let diagonal = (bounds.max_x - bounds.min_x).hypot(bounds.max_y - bounds.min_y);
+  let min_move_distance = diagonal * thresholds.move_fraction;
+
+  let mut added: Vec<ScoreEntry> = new_rows
+    .iter()
+    .filter(|r| !old_by_id.contains_key(r.score_id.as_str()))
+    .map(ScoreEntry::from)
+    .collect();
+  added.sort_unstable_by_key(|e| -e.num_users);
+  let mut removed: Vec<ScoreEntry> = old_rows
+    .iter()
+    .filter(|r| !new_by_id.contains_key(r.score_id.as_str()))
+    .map(ScoreEntry::from)
+    .collect();
+  removed.sort_unstable_by_key(|e| -e.num_users);
+
+  let mut moved = Vec::new();
+  let mut star_rating_changes = Vec::new();
+  let mut avg_pp_changes = Vec::new();
+  let mut num_users_changes = Vec::new();
+  for new in new_rows {
+    let Some(old) = old_by_id.get(new.score_id.as_str()) else {
+      continue;
+    };
+
+    let distance = (new.position[0] - old.position[0]).hypot(new.position[1] - old.position[1]);
+    if distance >= min_move_distance {
+      moved.push(PositionMove {
+        score_id: new.score_id.clone(),
+        from: old.position,
+        to: new.position,
+        distance,
+      });
+    }
+    if (new.stars - old.stars).abs() >= thresholds.stars {
+      star_rating_changes.push(ValueChange {
+        score_id: new.score_id.clone(),
+        old: old.stars,
+        new: new.stars,
+        delta: new.stars - old.stars,
+      });
+    }
+    if relative_change(old.avg_pp, new.avg_pp) >= thresholds.avg_pp_fraction {
+      avg_pp_changes.push(ValueChange {
+        score_id: new.score_id.clone(),
+        old: old.avg_pp,
+        new: new.avg_pp,
+        delta: new.avg_pp - old.avg_pp,
+      });
+    }
+    if relative_change(old.num_users as f64, new.num_users as f64) >= thresholds.num_users_fraction
+    {
+      num_users_changes.push(ValueChange {
+        score_id: new.score_id.clone(),
+        old: old.num_users,
+        new: new.num_users,
+        delta: new.num_users - old.num_users,
+      });
+    }
+  }
+
+  moved.sort_unstable_by(|a, b| b.distance.total_cmp(&a.distance));
+  star_rating_changes.sort_unstable_by(|a, b| b.delta.abs().total_cmp(&a.delta.abs()));
+  avg_pp_changes.sort_unstable_by(|a, b| b.delta.abs().total_cmp(&a.delta.abs()));
+  num_users_changes.sort_unstable_by_key(|c| -c.delta.abs());
+
+  CorpusDiff {
+    old,
+    new,
+    added,
+    removed,
+    moved,
+    star_rating_changes,
+    avg_pp_changes,
+    num_users_changes,
+  }
+}
+
+fn render_report(diff: &CorpusDiff) -> String {
+  let mut out = String::new();
+  let _ = writeln!(out, "Corpus diff");
+  let _ = writeln!(
+    out,
+    "  old: {} ({} rows)",
+    diff.old.path, diff.old.row_count
+  );
+  let _ = writeln!(
+    out,
+    "  new: {} ({} rows)",
+    diff.new.path, diff.new.row_count
+  );
+
+  let mut section = |title: &str, count: usize, lines: Vec<String>| {
+    let _ = writeln!(out, "\n{title}: {count}");
+    for line in lines.iter().take(REPORT_TOP_N) {
+      let _ = writeln!(out, "  {line}");
+    }
+    if count > REPORT_TOP_N {
+      let _ = writeln!(out, "  ... and {} more", count - REPORT_TOP_N);
+    }
+  };
+  let describe = |e: &ScoreEntry| {
+    format!(
+      "{} - {} [{}] by {} ({:.2}*, {:.0}pp avg, {} users)",
+      e.score_id, e.title, e.version, e.creator, e.stars, e.avg_pp, e.num_users
+    )
+  };
+
+  section(
+    "Added",
+    diff.added.len(),
+    diff.added.iter().map(describe).collect(),
+  );
+  section(
+    "Removed",
+    diff.removed.len(),
+    diff.removed.iter().map(describe).collect(),
+  );
+  section(
+    "Moved",
+    diff.moved.len(),
+    diff
+      .moved
+      .iter()
+      .map(|m| {
+        format!(
+          "{}: ({:.2}, {:.2}) -> ({:.2}, {:.2}), distance {:.2}",
+          m.score_id, m.from[0], m.from[1], m.to[0], m.to[1], m.distance
+        )
+      })
+      .collect(),
+  );
+  section(
+    "Star rating changes",
+    diff.star_rating_changes.len(),
+    diff
+      .star_rating_changes
+      .iter()
+      .map(|c| {
+        format!(
+          "{}: {:.2}* -> {:.2}* ({:+.2})",
+          c.score_id, c.old, c.new, c.delta
+        )
+      })
+      .collect(),
+  );
+  section(
+    "Average pp changes",
+    diff.avg_pp_changes.len(),
+    diff
+      .avg_pp_changes
+      .iter()
+      .map(|c| {
+        format!(
+          "{}: {:.1}pp -> {:.1}pp ({:+.1})",
+          c.score_id, c.old, c.new, c.delta
+        )
+      })
+      .collect(),
+  );
+  section(
+    "User count changes",
+    diff.num_users_changes.len(),
+    diff
+      .num_users_changes
+      .iter()
+      .map(|c| format!("{}: {} -> {} ({:+})", c.score_id, c.old, c.new, c.delta))
+      .collect(),
+  );
+
+  out
+}
+
+/// Diffs the corpus at `old_path` against the one at `new_path`.  The old embedding is aligned to
+/// the new one before positions are compared.  Prints a human-readable report
+/// and writes the full diff as JSON to `json_out_path`.
+pub(crate) fn diff_corpus(
+  old_path: &Path,
+  new_path: &Path,
+  json_out_path: &Path,
+  thresholds: &DiffThresholds,
+) -> Result<(), String> {
+  let read = |path: &Path| -> Result<Vec<CorpusRow>, String> {
+    let data =
+      std::fs::read(path).map_err(|err| format!("Failed to read {}: {err}", path.display()))?;
+    decode_corpus(&data).map_err(|err| format!("Failed to parse {}: {err}", path.display()))
+  };
+  let mut old_rows = read(old_path)?;
+  let new_rows = read(new_path)?;
+
+  // Embeddings from different releases can be arbitrarily rotated and scaled relative to each
+  // other, so bring the old one into the new one's frame before measuring how far points moved
+  if let Err(err) = align_rows(&mut old_rows, &new_rows) {
+    warn!("{err}; comparing raw positions instead");
+  }
+
+  let old = CorpusSummary {
+    path: old_path.display().to_string(),
+    row_count: old_rows.len(),
+  };
+  let new = CorpusSummary {
+    path: new_path.display().to_string(),
+    row_count: new_rows.len(),
+  };
+  let diff = diff_rows(old, new, &old_rows, &new_rows, thresholds);
+
+  println!("{}", render_report(&diff));
+
+  let json = serde_json::to_string(&diff).map_err(|err| format!("{err}"))?;
+  std::fs::write(json_out_path, json)
+    .map_err(|err| format!("Failed to write {}: {err}", json_out_path.display()))?;
+  info!("Wrote corpus diff to {}", json_out_path.display());
+  Ok(())
+}
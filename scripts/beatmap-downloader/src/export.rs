@@ -0,0 +1,279 @@
+//! Exports the fully joined corpus rows in formats which are convenient to load into notebooks and
+//! other analysis tools.
+
+use std::{fs::File, io::Write, path::Path, sync::Arc};
+
+use clap::ValueEnum;
+use foundations::telemetry::log::*;
+use parquet::{
+  basic::{Compression, ZstdLevel},
+  data_type::{ByteArray, ByteArrayType, DataType, DoubleType, FloatType, Int32Type},
+  file::{
+    properties::WriterProperties,
+    writer::{SerializedFileWriter, SerializedRowGroupWriter},
+  },
+  schema::parser::parse_message_type,
+};
+use serde::Serialize;
+
+use crate::build_corpus::CorpusRow;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum ExportFormat {
+  Parquet,
+  Csv,
+  /// Newline-delimited JSON
+  Ndjson,
+}
+
+impl ExportFormat {
+  pub fn extension(self) -> &'static str {
+    match self {
+      ExportFormat::Parquet => "parquet",
+      ExportFormat::Csv => "csv",
+      ExportFormat::Ndjson => "ndjson",
+    }
+  }
+}
+
+const CORPUS_PARQUET_SCHEMA: &str = "
+  message corpus_row {
+    REQUIRED BYTE_ARRAY score_id (UTF8);
+    REQUIRED INT32 beatmap_id;
+    REQUIRED INT32 beatmapset_id;
+    REQUIRED INT32 mods_bitmask (INTEGER(32, false));
+    REQUIRED BYTE_ARRAY mods (UTF8);
+    REQUIRED FLOAT x;
+    REQUIRED FLOAT y;
+    REQUIRED DOUBLE avg_pp;
+    REQUIRED INT32 num_users;
+    REQUIRED DOUBLE stars;
+    REQUIRED DOUBLE aim_difficulty;
+    REQUIRED DOUBLE speed_difficulty;
+    REQUIRED BYTE_ARRAY title (UTF8);
+    REQUIRED BYTE_ARRAY version (UTF8);
+    REQUIRED BYTE_ARRAY creator (UTF8);
+    OPTIONAL BYTE_ARRAY title_unicode (UTF8);
+    REQUIRED BYTE_ARRAY artist (UTF8);
+    OPTIONAL BYTE_ARRAY artist_unicode (UTF8);
+    REQUIRED BYTE_ARRAY source (UTF8);
+    REQUIRED BYTE_ARRAY tags (UTF8);
+    REQUIRED INT32 ranked_status;
+    OPTIONAL INT32 release_year (INTEGER(16, false));
+    REQUIRED INT32 length_seconds;
+    REQUIRED INT32 drain_seconds;
+    REQUIRED INT32 bpm;
+    REQUIRED FLOAT ar;
+    REQUIRED FLOAT cs;
+    REQUIRED FLOAT od;
+    REQUIRED FLOAT hp;
+    REQUIRED INT32 mode;
+  }
+";
+
+/// Flattened representation of a corpus row used for the CSV and JSON exports.  Must be kept in
+/// sync with [`CORPUS_PARQUET_SCHEMA`] so that all formats have the same columns.
+#[derive(Serialize)]
+pub(crate) struct ExportRow<'a> {
+  score_id: &'a str,
+  beatmap_id: i32,
+  beatmapset_id: i32,
+  mods_bitmask: u32,
+  mods: &'a str,
+  x: f32,
+  y: f32,
+  avg_pp: f64,
+  num_users: i32,
+  stars: f64,
+  aim_difficulty: f64,
+  speed_difficulty: f64,
+  title: &'a str,
+  version: &'a str,
+  creator: &'a str,
+  title_unicode: Option<&'a str>,
+  artist: &'a str,
+  artist_unicode: Option<&'a str>,
+  source: &'a str,
+  tags: &'a str,
+  ranked_status: i32,
+  release_year: Option<u16>,
+  length_seconds: i32,
+  drain_seconds: i32,
+  bpm: i32,
+  ar: f32,
+  cs: f32,
+  od: f32,
+  hp: f32,
+  mode: i32,
+}
+
+impl<'a> From<&'a CorpusRow> for ExportRow<'a> {
+  fn from(row: &'a CorpusRow) -> Self {
+    ExportRow {
+      score_id: &row.score_id,
+      beatmap_id: row.beatmap_id,
+      beatmapset_id: row.beatmapset_id,
+      mods_bitmask: row.mods_bits,
+      mods: row.mods_str(),
+      x: row.position[0],
+      y: row.position[1],
+      avg_pp: row.avg_pp,
+      num_users: row.num_users,
+      stars: row.stars,
+      aim_difficulty: row.aim_difficulty,
+      speed_difficulty: row.speed_difficulty,
+      title: &row.title,
+      version: &row.version,
+      creator: &row.creator,
+      title_unicode: row.title_unicode.as_deref(),
+      artist: &row.artist,
+      artist_unicode: row.artist_unicode.as_deref(),
+      source: &row.source,
+      tags: &row.tags,
+      ranked_status: row.ranked_status,
+      release_year: row.release_year,
+      length_seconds: row.length_seconds,
+      drain_seconds: row.drain_seconds,
+      bpm: row.bpm,
+      ar: row.ar,
+      cs: row.cs,
+      od: row.od,
+      hp: row.hp,
+      mode: row.mode,
+    }
+  }
+}
+
+fn write_csv(rows: &[CorpusRow], out_path: &Path) -> Result<(), String> {
+  let mut wtr = csv::Writer::from_path(out_path).map_err(|err| format!("{err}"))?;
+  for row in rows {
+    wtr
+      .serialize(ExportRow::from(row))
+      .map_err(|err| format!("Failed to write CSV row: {err}"))?;
+  }
+  wtr.flush().map_err(|err| format!("{err}"))
+}
+
+fn write_ndjson(rows: &[CorpusRow], out_path: &Path) -> Result<(), String> {
+  let file = File::create(out_path).map_err(|err| format!("{err}"))?;
+  let mut wtr = std::io::BufWriter::new(file);
+  for row in rows {
+    serde_json::to_writer(&mut wtr, &ExportRow::from(row))
+      .map_err(|err| format!("Failed to write JSON row: {err}"))?;
+    wtr.write_all(b"\n").map_err(|err| format!("{err}"))?;
+  }
+  wtr.flush().map_err(|err| format!("{err}"))
+}
+
+fn write_parquet_column<T: DataType>(
+  row_group_writer: &mut SerializedRowGroupWriter<'_, File>,
+  values: &[T::T],
+  def_levels: Option<&[i16]>,
+) -> Result<(), String> {
+  let mut col_writer = row_group_writer
+    .next_column()
+    .map_err(|err| format!("{err}"))?
+    .ok_or_else(|| "Parquet schema has fewer columns than were written".to_owned())?;
+  col_writer
+    .typed::<T>()
+    .write_batch(values, def_levels, None)
+    .map_err(|err| format!("{err}"))?;
+  col_writer.close().map_err(|err| format!("{err}"))
+}
+
+fn write_parquet(rows: &[CorpusRow], out_path: &Path) -> Result<(), String> {
+  let schema = Arc::new(parse_message_type(CORPUS_PARQUET_SCHEMA).unwrap());
+  let props = Arc::new(
+    WriterProperties::builder()
+      .set_compression(Compression::ZSTD(ZstdLevel::default()))
+      .build(),
+  );
+  let file = File::create(out_path).map_err(|err| format!("{err}"))?;
+  let mut writer =
+    SerializedFileWriter::new(file, schema, props).map_err(|err| format!("{err}"))?;
+  let mut rg = writer.next_row_group().map_err(|err| format!("{err}"))?;
+
+  let strs = |f: fn(&CorpusRow) -> &str| -> Vec<ByteArray> {
+    rows.iter().map(|row| ByteArray::from(f(row))).collect()
+  };
+  let i32s = |f: fn(&CorpusRow) -> i32| -> Vec<i32> { rows.iter().map(f).collect() };
+  let f32s = |f: fn(&CorpusRow) -> f32| -> Vec<f32> { rows.iter().map(f).collect() };
+  let f64s = |f: fn(&CorpusRow) -> f64| -> Vec<f64> { rows.iter().map(f).collect() };
+  // Only non-null values are written; the definition levels mark which rows have one
+  let optional_strs = |f: fn(&CorpusRow) -> Option<&str>| -> (Vec<ByteArray>, Vec<i16>) {
+    let values = rows.iter().filter_map(f).map(ByteArray::from).collect();
+    let def_levels = rows.iter().map(|row| f(row).is_some() as i16).collect();
+    (values, def_levels)
+  };
+
+  write_parquet_column::<ByteArrayType>(&mut rg, &strs(|r| &r.score_id), None)?;
+  write_parquet_column::<Int32Type>(&mut rg, &i32s(|r| r.beatmap_id), None)?;
+  write_parquet_column::<Int32Type>(&mut rg, &i32s(|r| r.beatmapset_id), None)?;
+  write_parquet_column::<Int32Type>(&mut rg, &i32s(|r| r.mods_bits as i32), None)?;
+  write_parquet_column::<ByteArrayType>(&mut rg, &strs(CorpusRow::mods_str), None)?;
+  write_parquet_column::<FloatType>(&mut rg, &f32s(|r| r.position[0]), None)?;
+  write_parquet_column::<FloatType>(&mut rg, &f32s(|r| r.position[1]), None)?;
+  write_parquet_column::<DoubleType>(&mut rg, &f64s(|r| r.avg_pp), None)?;
+  write_parquet_column::<Int32Type>(&mut rg, &i32s(|r| r.num_users), None)?;
+  write_parquet_column::<DoubleType>(&mut rg, &f64s(|r| r.stars), None)?;
+  write_parquet_column::<DoubleType>(&mut rg, &f64s(|r| r.aim_difficulty), None)?;
+  write_parquet_column::<DoubleType>(&mut rg, &f64s(|r| r.speed_difficulty), None)?;
+  write_parquet_column::<ByteArrayType>(&mut rg, &strs(|r| &r.title), None)?;
+  write_parquet_column::<ByteArrayType>(&mut rg, &strs(|r| &r.version), None)?;
+  write_parquet_column::<ByteArrayType>(&mut rg, &strs(|r| &r.creator), None)?;
+  let (title_unicodes, title_unicode_def_levels) = optional_strs(|r| r.title_unicode.as_deref());
+  write_parquet_column::<ByteArrayType>(&mut rg, &title_unicodes, Some(&title_unicode_def_levels))?;
+  write_parquet_column::<ByteArrayType>(&mut rg, &strs(|r| &r.artist), None)?;
+  let (artist_unicodes, artist_unicode_def_levels) = optional_strs(|r| r.artist_unicode.as_deref());
+  write_parquet_column::<ByteArrayType>(
+    &mut rg,
+    &artist_unicodes,
+    Some(&artist_unicode_def_levels),
+  )?;
+  write_parquet_column::<ByteArrayType>(&mut rg, &strs(|r| &r.source), None)?;
+  write_parquet_column::<ByteArrayType>(&mut rg, &strs(|r| &r.tags), None)?;
+  write_parquet_column::<Int32Type>(&mut rg, &i32s(|r| r.ranked_status), None)?;
+
+  let release_years: Vec<i32> = rows
+    .iter()
+    .filter_map(|r| r.release_year.map(i32::from))
+    .collect();
+  let release_year_def_levels: Vec<i16> = rows
+    .iter()
+    .map(|r| r.release_year.is_some() as i16)
+    .collect();
+  write_parquet_column::<Int32Type>(&mut rg, &release_years, Some(&release_year_def_levels))?;
+
+  write_parquet_column::<Int32Type>(&mut rg, &i32s(|r| r.length_seconds), None)?;
+  write_parquet_column::<Int32Type>(&mut rg, &i32s(|r| r.drain_seconds), None)?;
+  write_parquet_column::<Int32Type>(&mut rg, &i32s(|r| r.bpm), None)?;
+  write_parquet_column::<FloatType>(&mut rg, &f32s(|r| r.ar), None)?;
+  write_parquet_column::<FloatType>(&mut rg, &f32s(|r| r.cs), None)?;
+  write_parquet_column::<FloatType>(&mut rg, &f32s(|r| r.od), None)?;
+  write_parquet_column::<FloatType>(&mut rg, &f32s(|r| r.hp), None)?;
+  write_parquet_column::<Int32Type>(&mut rg, &i32s(|r| r.mode), None)?;
+
+  rg.close().map_err(|err| format!("{err}"))?;
+  writer.close().map_err(|err| format!("{err}"))?;
+  Ok(())
+}
+
+pub(crate) fn export_corpus_rows(
+  rows: &[CorpusRow],
+  format: ExportFormat,
+  out_path: &Path,
+) -> Result<(), String> {
+  match format {
+    ExportFormat::Parquet => write_parquet(rows, out_path),
+    ExportFormat::Csv => write_csv(rows, out_path),
+    ExportFormat::Ndjson => write_ndjson(rows, out_path),
+  }
+  .map_err(|err| format!("Failed to export corpus to {}: {err}", out_path.display()))?;
+
+  info!(
+    "Exported {} corpus rows to {}",
+    rows.len(),
+    out_path.display()
+  );
+  Ok(())
+}
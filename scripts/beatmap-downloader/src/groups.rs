@@ -0,0 +1,289 @@
+//! Groups the rows of the corpus by beatmapset and by song so that the frontend can find the other
+//! difficulties and mod variants of a set, and other sets mapped to the same song, without
+//! scanning the whole corpus.
+//!
+//! Songs are identified by their artist and title after normalizing them with
+//! [`normalize_search_text`], so `FREEDOM DiVE` by `xi` and `Freedom Dive` by `Xi` are the same
+//! song.
+//!
+//! The groups sidecar is a binary file which starts with a header:
+//!
+//! [u8; 4] magic bytes `OSUG`
+//! [u32] format version
+//! [u32] number of rows; matches the corpus that it was built alongside
+//! [u32] number of beatmapsets
+//! [u32] number of songs
+//!
+//! This is followed by every corpus row index, grouped by beatmapset and ascending within each
+//! set.  Then come the beatmapsets in ascending ID order, each stored as a [u32] beatmapset ID, a
+//! [u32] offset of its first row in that list, a [u32] row count, and the [u32] index of its song.
+//! After that is every beatmapset index grouped by song, and finally the songs, each stored as a
+//! [u32] offset of its first beatmapset in that list and a [u32] beatmapset count.
+
+use std::ops::Range;
+
+use foundations::telemetry::log::*;
+use fxhash::FxHashMap;
+
+use crate::{build_corpus::CorpusRow, search_index::normalize_search_text};
+
+pub(crate) const GROUPS_MAGIC: [u8; 4] = *b"OSUG";
+pub(crate) const GROUPS_FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct BeatmapsetGroup {
+  pub beatmapset_id: u32,
+  /// Range of [`CorpusGroups::set_rows`] holding the set's rows
+  pub rows: Range<u32>,
+  pub song_ix: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct CorpusGroups {
+  /// Corpus row indices grouped by beatmapset
+  pub set_rows: Vec<u32>,
+  pub sets: Vec<BeatmapsetGroup>,
+  /// Indices into `sets` grouped by song
+  pub song_sets: Vec<u32>,
+  /// Range of `song_sets` holding each song's beatmapsets
+  pub songs: Vec<Range<u32>>,
+}
+
+/// Normalized artist and title which identify the song that a row was mapped to
+fn song_key(row: &CorpusRow) -> (String, String) {
+  let normalize = |text: &str| {
+    normalize_search_text(text)
+      .split_whitespace()
+      .collect::<Vec<_>>()
+      .join(" ")
+  };
+  (normalize(&row.artist), normalize(&row.title))
+}
+
+impl CorpusGroups {
+  /// Groups `rows`, which must be in the same order as the rows of the corpus
+  pub fn build(rows: &[CorpusRow]) -> Self {
+    let mut row_ixs_by_set: FxHashMap<i32, Vec<u32>> = FxHashMap::default();
+    for (row_ix, row) in rows.iter().enumerate() {
+      row_ixs_by_set
+        .entry(row.beatmapset_id)
+        .or_default()
+        .push(row_ix as u32);
+    }
+    let mut set_ids: Vec<i32> = row_ixs_by_set.keys().copied().collect();
+    set_ids.sort_unstable();
+
+    let mut set_ixs_by_song: FxHashMap<(String, String), Vec<u32>> = FxHashMap::default();
+    for (set_ix, set_id) in set_ids.iter().enumerate() {
+      let first_row = &rows[row_ixs_by_set[set_id][0] as usize];
+      set_ixs_by_song
+        .entry(song_key(first_row))
+        .or_default()
+        .push(set_ix as u32);
+    }
+    let mut songs_by_key: Vec<((String, String), Vec<u32>)> = set_ixs_by_song.into_iter().collect();
+    songs_by_key.sort_unstable();
+
+    let mut song_ix_by_set = vec![0; set_ids.len()];
+    let mut song_sets = Vec::with_capacity(set_ids.len());
+    let mut songs = Vec::with_capacity(songs_by_key.len());
+    for (song_ix, (_, set_ixs)) in songs_by_key.into_iter().enumerate() {
+      let start = song_sets.len() as u32;
+      for &set_ix in &set_ixs {
+        song_ix_by_set[set_ix as usize] = song_ix as u32;
+      }
+      song_sets.extend(set_ixs);
+      songs.push(start..song_sets.len() as u32);
+    }
+
+    let mut set_rows = Vec::with_capacity(rows.len());
+    let mut sets = Vec::with_capacity(set_ids.len());
+    for (set_id, song_ix) in set_ids.iter().zip(song_ix_by_set) {
+      let start = set_rows.len() as u32;
+      set_rows.extend(&row_ixs_by_set[set_id]);
+      sets.push(BeatmapsetGroup {
+        beatmapset_id: *set_id as u32,
+        rows: start..set_rows.len() as u32,
+        song_ix,
+      });
+    }
+
+    CorpusGroups {
+      set_rows,
+      sets,
+      song_sets,
+      songs,
+    }
+  }
+
+  /// Index of the beatmapset with ID `beatmapset_id`, if it has any rows in the corpus
+  pub fn set_ix(&self, beatmapset_id: i32) -> Option<usize> {
+    self
+      .sets
+      .binary_search_by_key(&(beatmapset_id as u32), |set| set.beatmapset_id)
+      .ok()
+  }
+
+  /// Rows of every difficulty and mod variant in a beatmapset
+  pub fn rows_of_set(&self, set_ix: usize) -> &[u32] {
+    let range = &self.sets[set_ix].rows;
+    &self.set_rows[range.start as usize..range.end as usize]
+  }
+
+  /// Indices of every beatmapset mapped to a song
+  pub fn sets_of_song(&self, song_ix: usize) -> &[u32] {
+    let range = &self.songs[song_ix];
+    &self.song_sets[range.start as usize..range.end as usize]
+  }
+}
+
+pub(crate) fn encode_groups(groups: &CorpusGroups) -> Vec<u8> {
+  let mut buf = Vec::new();
+  buf.extend_from_slice(&GROUPS_MAGIC);
+  buf.extend_from_slice(&GROUPS_FORMAT_VERSION.to_le_bytes());
+  for len in [groups.set_rows.len(), groups.sets.len(), groups.songs.len()] {
+    buf.extend_from_slice(&(len as u32).to_le_bytes());
+  }
+
+  for &row_ix in &groups.set_rows {
+    buf.extend_from_slice(&row_ix.to_le_bytes());
+  }
+  for set in &groups.sets {
+    for value in [
+      set.beatmapset_id,
+      set.rows.start,
+      set.rows.len() as u32,
+      set.song_ix,
+    ] {
+      buf.extend_from_slice(&value.to_le_bytes());
+    }
+  }
+  for &set_ix in &groups.song_sets {
+    buf.extend_from_slice(&set_ix.to_le_bytes());
+  }
+  for song in &groups.songs {
+    buf.extend_from_slice(&song.start.to_le_bytes());
+    buf.extend_from_slice(&(song.len() as u32).to_le_bytes());
+  }
+  buf
+}
+
+/// Builds the groups sidecar for `rows`, which must be in the same order as the rows of the corpus
+pub(crate) fn build_groups(rows: &[CorpusRow]) -> Vec<u8> {
+  let groups = CorpusGroups::build(rows);
+  let shared_song_count = groups.songs.iter().filter(|song| song.len() > 1).count();
+  info!(
+    "Grouped {} rows into {} beatmapsets and {} songs, {shared_song_count} of which were mapped \
+     more than once",
+    rows.len(),
+    groups.sets.len(),
+    groups.songs.len()
+  );
+  encode_groups(&groups)
+}
+
+/// Parses a groups sidecar, checking that it was built for a corpus with `num_rows` rows
+pub(crate) fn decode_groups(data: &[u8], num_rows: usize) -> Result<CorpusGroups, String> {
+  let truncated = || "Groups sidecar is truncated".to_owned();
+  if data.get(..GROUPS_MAGIC.len()) != Some(&GROUPS_MAGIC[..]) {
+    return Err("Not a groups sidecar".to_owned());
+  }
+  let mut words = data[GROUPS_MAGIC.len()..]
+    .chunks_exact(4)
+    .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()));
+  let mut next = || words.next().ok_or_else(truncated);
+
+  let format_version = next()?;
+  if format_version != GROUPS_FORMAT_VERSION {
+    return Err(format!(
+      "Unsupported groups format version {format_version}"
+    ));
+  }
+  let groups_num_rows = next()? as usize;
+  if groups_num_rows != num_rows {
+    return Err(format!(
+      "Groups were built for {groups_num_rows} rows but the corpus has {num_rows}"
+    ));
+  }
+  let num_sets = next()? as usize;
+  let num_songs = next()? as usize;
+
+  let set_rows = (0..num_rows)
+    .map(|_| next())
+    .collect::<Result<Vec<_>, _>>()?;
+  let mut sets = Vec::with_capacity(num_sets);
+  for _ in 0..num_sets {
+    let beatmapset_id = next()?;
+    let start = next()?;
+    let len = next()?;
+    sets.push(BeatmapsetGroup {
+      beatmapset_id,
+      rows: start..start + len,
+      song_ix: next()?,
+    });
+  }
+  let song_sets = (0..num_sets)
+    .map(|_| next())
+    .collect::<Result<Vec<_>, _>>()?;
+  let mut songs = Vec::with_capacity(num_songs);
+  for _ in 0..num_songs {
+    let start = next()?;
+    songs.push(start..start + next()?);
+  }
+
+  let in_bounds =
+    |range: &Range<u32>, len: usize| range.start <= range.end && range.end as usize <= len;
+  if set_rows.iter().any(|&row_ix| row_ix as usize >= num_rows)
+    || sets
+      .iter()
+      .any(|set| !in_bounds(&set.rows, num_rows) || set.song_ix as usize >= num_songs)
+    || song_sets.iter().any(|&set_ix| set_ix as usize >= num_sets)
+    || songs.iter().any(|song| !in_bounds(song, num_sets))
+  {
+    return Err("Groups reference rows, beatmapsets, or songs which don't exist".to_owned());
+  }
+
+  Ok(CorpusGroups {
+    set_rows,
+    sets,
+    song_sets,
+    songs,
+  })
+}
+
+/// Difficulties and mod variants are grouped by set, and sets are linked by song regardless of
+/// case and punctuation
+#[test]
+fn groups_link_sets_and_songs() {
+  let row =
+    |beatmap_id: i32, beatmapset_id: i32, mods_bits: u32, artist: &str, title: &str| CorpusRow {
+      beatmapset_id,
+      title: title.to_owned(),
+      artist: artist.to_owned(),
+      ..CorpusRow::test_row(beatmap_id, mods_bits)
+    };
+  let rows = vec![
+    row(10, 3, 0, "xi", "FREEDOM DiVE"),
+    row(20, 1, 0, "Ikimono-gakari", "Blue Bird"),
+    row(11, 3, 64, "xi", "FREEDOM DiVE"),
+    row(30, 2, 0, "Xi", "Freedom Dive"),
+    row(12, 3, 0, "xi", "FREEDOM DiVE"),
+  ];
+
+  let encoded = encode_groups(&CorpusGroups::build(&rows));
+  assert!(decode_groups(&encoded, rows.len() + 1).is_err());
+  let groups = decode_groups(&encoded, rows.len()).unwrap();
+  assert_eq!(groups, CorpusGroups::build(&rows));
+
+  let set_ids: Vec<u32> = groups.sets.iter().map(|set| set.beatmapset_id).collect();
+  assert_eq!(set_ids, [1, 2, 3]);
+  assert_eq!(groups.set_ix(3), Some(2));
+  assert_eq!(groups.set_ix(4), None);
+  assert_eq!(groups.rows_of_set(2), [0, 2, 4]);
+  assert_eq!(groups.rows_of_set(0), [1]);
+
+  assert_eq!(groups.songs.len(), 2);
+  assert_eq!(groups.sets[1].song_ix, groups.sets[2].song_ix);
+  assert_ne!(groups.sets[0].song_ix, groups.sets[1].song_ix);
+  assert_eq!(groups.sets_of_song(groups.sets[2].song_ix as usize), [1, 2]);
+}
@@ -0,0 +1,277 @@
+//! Loads the `hiscore_updates.parquet` dump produced by `extract-data.ipynb` and reduces it to the
+//! set of osu!standard top plays that the embedding is built from.
+//!
+//! Columns are looked up by name rather than position, and integer/timestamp columns are accepted
+//! in any of the widths and units that pandas is likely to write them in.
+
+use std::path::Path;
+
+use foundations::telemetry::log::*;
+use parquet::{
+  basic::{LogicalType, TimeUnit},
+  file::reader::{FileReader, SerializedFileReader},
+  record::Field,
+  schema::types::{ColumnDescPtr, Type},
+};
+
+use crate::build_corpus::{score_id_from_parts, SCORE_ID_MODS};
+
+/// Plays below this pp value are ignored entirely, matching `embed.ipynb`
+pub(crate) const MIN_PP: f32 = 75.;
+
+const REQUIRED_COLUMNS: [&str; 6] = ["user", "beatmap_id", "pp", "mods", "mode", "update_time"];
+/// Columns which are read if present.  The judgement counts aren't part of the osu!track dump, but
+/// accuracy is computed from them for dumps that do have them.
+const OPTIONAL_COLUMNS: [&str; 6] = [
+  "score_time",
+  "rank",
+  "count300",
+  "count100",
+  "count50",
+  "countmiss",
+];
+const COLUMN_COUNT: usize = REQUIRED_COLUMNS.len() + OPTIONAL_COLUMNS.len();
+
+/// Letter grade of a play, with the silver (HD/FL) variants merged into the regular ones
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Grade {
+  SS,
+  S,
+  A,
+  B,
+  C,
+  D,
+}
+
+impl Grade {
+  pub const ALL: [Grade; 6] = [Grade::SS, Grade::S, Grade::A, Grade::B, Grade::C, Grade::D];
+
+  fn parse(rank: &str) -> Option<Self> {
+    match rank {
+      "XH" | "X" => Some(Grade::SS),
+      "SH" | "S" => Some(Grade::S),
+      "A" => Some(Grade::A),
+      "B" => Some(Grade::B),
+      "C" => Some(Grade::C),
+      "D" => Some(Grade::D),
+      _ => None,
+    }
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Grade::SS => "ss",
+      Grade::S => "s",
+      Grade::A => "a",
+      Grade::B => "b",
+      Grade::C => "c",
+      Grade::D => "d",
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Hiscore {
+  pub user: u32,
+  pub beatmap_id: i32,
+  /// Only the mods that are part of score IDs are retained
+  pub mods_bits: u32,
+  pub pp: f32,
+  /// Unix timestamp in seconds of when the play was recorded
+  pub update_time: i64,
+  /// Unix timestamp in seconds of when the play was set
+  pub score_time: Option<i64>,
+  pub grade: Option<Grade>,
+  /// In [0, 1]; only available if the dump has judgement counts
+  pub accuracy: Option<f32>,
+}
+
+impl Hiscore {
+  pub fn score_id(&self) -> String { score_id_from_parts(self.beatmap_id, self.mods_bits) }
+
+  /// Compact key which uniquely identifies the score ID of this play
+  pub fn score_key(&self) -> u64 { score_key(self.beatmap_id, self.mods_bits) }
+}
+
+/// Compact key which uniquely identifies a score ID.  `mods_bits` must only contain the mods that
+/// are part of score IDs.
+pub(crate) fn score_key(beatmap_id: i32, mods_bits: u32) -> u64 {
+  ((beatmap_id as u32 as u64) << 32) | mods_bits as u64
+}
+
+fn field_as_i64(field: &Field) -> Option<i64> {
+  match *field {
+    Field::Bool(v) => Some(v as i64),
+    Field::Byte(v) => Some(v as i64),
+    Field::Short(v) => Some(v as i64),
+    Field::Int(v) => Some(v as i64),
+    Field::Long(v) => Some(v),
+    Field::UByte(v) => Some(v as i64),
+    Field::UShort(v) => Some(v as i64),
+    Field::UInt(v) => Some(v as i64),
+    Field::ULong(v) => Some(v as i64),
+    Field::Float(v) => Some(v as i64),
+    Field::Double(v) => Some(v as i64),
+    _ => None,
+  }
+}
+
+fn field_as_f64(field: &Field) -> Option<f64> {
+  match *field {
+    Field::Float(v) => Some(v as f64),
+    Field::Double(v) => Some(v),
+    _ => field_as_i64(field).map(|v| v as f64),
+  }
+}
+
+/// Converts a timestamp column value to unix seconds.  Nanosecond timestamps, which are what
+/// pandas writes by default, come through as plain longs and are identified by the column's
+/// logical type.
+fn field_as_unix_secs(field: &Field, descr: &ColumnDescPtr) -> Option<i64> {
+  match *field {
+    Field::TimestampMillis(v) => Some(v.div_euclid(1_000)),
+    Field::TimestampMicros(v) => Some(v.div_euclid(1_000_000)),
+    Field::Long(v) => match descr.logical_type() {
+      Some(LogicalType::Timestamp {
+        unit: TimeUnit::NANOS(_),
+        ..
+      }) => Some(v.div_euclid(1_000_000_000)),
+      Some(LogicalType::Timestamp {
+        unit: TimeUnit::MICROS(_),
+        ..
+      }) => Some(v.div_euclid(1_000_000)),
+      Some(LogicalType::Timestamp {
+        unit: TimeUnit::MILLIS(_),
+        ..
+      }) => Some(v.div_euclid(1_000)),
+      _ => Some(v),
+    },
+    _ => None,
+  }
+}
+
+fn accuracy(count300: i64, count100: i64, count50: i64, countmiss: i64) -> Option<f32> {
+  let total = count300 + count100 + count50 + countmiss;
+  if total <= 0 {
+    return None;
+  }
+  Some((300 * count300 + 100 * count100 + 50 * count50) as f32 / (300 * total) as f32)
+}
+
+/// Reads all osu!standard plays worth at least [`MIN_PP`] from the hiscores parquet file.
+pub(crate) fn read_hiscores(path: &Path) -> Result<Vec<Hiscore>, String> {
+  let file =
+    std::fs::File::open(path).map_err(|err| format!("Failed to open {}: {err}", path.display()))?;
+  let reader = SerializedFileReader::new(file)
+    .map_err(|err| format!("Failed to read {}: {err}", path.display()))?;
+  let schema_descr = reader.metadata().file_metadata().schema_descr_ptr();
+
+  // Only decode the columns we need.  `slots` maps each projected column to its index in
+  // `REQUIRED_COLUMNS` followed by `OPTIONAL_COLUMNS`.
+  let root = schema_descr.root_schema();
+  let mut projected_fields = Vec::with_capacity(COLUMN_COUNT);
+  let mut slots = Vec::with_capacity(COLUMN_COUNT);
+  for (slot, name) in REQUIRED_COLUMNS.iter().chain(&OPTIONAL_COLUMNS).enumerate() {
+    let Some(field) = root.get_fields().iter().find(|f| f.name() == *name) else {
+      if slot < REQUIRED_COLUMNS.len() {
+        return Err(format!("{} is missing the `{name}` column", path.display()));
+      }
+      continue;
+    };
+    projected_fields.push(field.clone());
+    slots.push(slot);
+  }
+  let projection = Type::group_type_builder(root.name())
+    .with_fields(projected_fields)
+    .build()
+    .map_err(|err| format!("{err}"))?;
+  let column_descr = |name: &str| {
+    schema_descr
+      .columns()
+      .iter()
+      .find(|c| c.name() == name)
+      .cloned()
+  };
+  let update_time_descr = column_descr("update_time").unwrap();
+  let score_time_descr = column_descr("score_time");
+
+  let mods_mask: u32 = SCORE_ID_MODS.iter().map(|&(_, bit)| bit).sum();
+  let mut hiscores = Vec::new();
+  let mut total_count = 0usize;
+  let rows = reader
+    .get_row_iter(Some(projection))
+    .map_err(|err| format!("{err}"))?;
+  for row in rows {
+    let row = row.map_err(|err| format!("Failed to read row: {err}"))?;
+    total_count += 1;
+    let mut values: [Option<&Field>; COLUMN_COUNT] = [None; COLUMN_COUNT];
+    for (&slot, (_, field)) in slots.iter().zip(row.get_column_iter()) {
+      values[slot] = Some(field);
+    }
+    let [user, beatmap_id, pp, mods, mode, update_time, score_time, rank, count300, count100, count50, countmiss] =
+      values;
+
+    // Rows with null pp or mode can't be used
+    if mode.and_then(field_as_i64) != Some(0) {
+      continue;
+    }
+    let Some(pp) = pp.and_then(field_as_f64).map(|pp| pp as f32) else {
+      continue;
+    };
+    if pp < MIN_PP {
+      continue;
+    }
+
+    let (Some(user), Some(beatmap_id), Some(mods), Some(update_time)) = (
+      user.and_then(field_as_i64),
+      beatmap_id.and_then(field_as_i64),
+      mods.and_then(field_as_i64),
+      update_time.and_then(|f| field_as_unix_secs(f, &update_time_descr)),
+    ) else {
+      warn!("Skipping hiscore row {total_count} with null values");
+      continue;
+    };
+    let grade = match rank {
+      Some(Field::Str(rank)) => Grade::parse(rank),
+      _ => None,
+    };
+    let counts = [count300, count100, count50, countmiss].map(|c| c.and_then(field_as_i64));
+    let accuracy = match counts {
+      [Some(c300), Some(c100), Some(c50), Some(cmiss)] => accuracy(c300, c100, c50, cmiss),
+      _ => None,
+    };
+
+    hiscores.push(Hiscore {
+      user: user as u32,
+      beatmap_id: beatmap_id as i32,
+      mods_bits: mods as u32 & mods_mask,
+      pp,
+      update_time,
+      score_time: score_time
+        .zip(score_time_descr.as_ref())
+        .and_then(|(f, descr)| field_as_unix_secs(f, descr)),
+      grade,
+      accuracy,
+    });
+  }
+
+  info!(
+    "Loaded {} of {total_count} hiscores from {}",
+    hiscores.len(),
+    path.display()
+  );
+  Ok(hiscores)
+}
+
+/// Keeps only the most recently updated play for each user and score ID, matching the
+/// `drop_duplicates` step in `embed.ipynb`.  Returned plays are sorted by user and then score ID.
+pub(crate) fn dedupe_hiscores(mut hiscores: Vec<Hiscore>) -> Vec<Hiscore> {
+  hiscores.sort_unstable_by(|a, b| {
+    (a.user, a.score_key())
+      .cmp(&(b.user, b.score_key()))
+      .then(b.update_time.cmp(&a.update_time))
+  });
+  hiscores.dedup_by(|b, a| a.user == b.user && a.score_key() == b.score_key());
+  info!("Retained {} plays after deduplication", hiscores.len());
+  hiscores
+}
@@ -2,12 +2,13 @@ extern crate foundations;
 
 use std::{
   io::{Read, Write},
+  path::{Path, PathBuf},
   time::Duration,
 };
 
-use clap::{Parser, Subcommand};
-use fxhash::FxHashSet;
-use lazy_static::lazy_static;
+use chrono::NaiveDate;
+use clap::{Args, Parser, Subcommand};
+use fxhash::{FxHashMap, FxHashSet};
 use once_cell::sync::OnceCell;
 
 use foundations::telemetry::{log::*, settings::LogVerbosity, TelemetryConfig};
@@ -15,30 +16,53 @@ use rosu_mods::GameMode;
 use rosu_pp::{any::DifficultyAttributes, osu::OsuDifficultyAttributes, Beatmap, Difficulty};
 use rosu_v2::prelude::{GameMod, GameMods};
 
+mod align;
 mod build_corpus;
-
-lazy_static! {
-  static ref DB_HOST: String = std::env::var("DB_HOST").expect("DB_HOST must be set");
-  static ref DB_USER: String = std::env::var("DB_USER").expect("DB_USER must be set");
-  static ref DB_PASSWORD: String = std::env::var("DB_PASSWORD").expect("DB_PASSWORD must be set");
-  static ref DB_DATABASE: String = std::env::var("DB_DATABASE").expect("DB_DATABASE must be set");
-}
+mod build_graph;
+mod clusters;
+mod config;
+mod cooccurrence;
+mod diff_corpus;
+mod export;
+mod groups;
+mod hiscores;
+mod manifest;
+mod mappers;
+mod neighbors;
+mod osu_metadata;
+mod pipeline;
+mod place_new;
+mod popularity;
+mod publish;
+mod recommend;
+mod score_metadata;
+mod search_index;
+mod serve;
+mod user_profile;
 
 static DB_POOL: OnceCell<sqlx::MySqlPool> = OnceCell::new();
 
 const FETCH_INTERVAL: Duration = Duration::from_millis(1200);
 
-async fn init_db_pool() {
-  let db_url = format!(
-    "mysql://{}:{}@{}/{}",
-    *DB_USER, *DB_PASSWORD, *DB_HOST, *DB_DATABASE
-  );
-  let pool = sqlx::MySqlPool::connect(&db_url)
+async fn init_db_pool(db_url: &str) {
+  let pool = sqlx::MySqlPool::connect(db_url)
     .await
     .expect("Failed to connect to MySQL");
   DB_POOL.set(pool).unwrap();
 }
 
+/// Connects to the configured database.  Only called by subcommands which need it, so the rest
+/// work without a database.
+async fn connect_db(database: &config::DatabaseSettings) {
+  if DB_POOL.get().is_some() {
+    return;
+  }
+  let database_url = database
+    .connection_url()
+    .unwrap_or_else(|err| panic!("{err}"));
+  init_db_pool(&database_url).await;
+}
+
 struct ScoreMetadata {
   score_id: String,
   #[allow(dead_code)]
@@ -47,21 +71,47 @@ struct ScoreMetadata {
   num_users: i32,
 }
 
-fn parse_score_metadata(file_path: &str) -> Vec<ScoreMetadata> {
-  let mut rdr = csv::Reader::from_path(file_path).unwrap();
+/// Index of the column called `name` in the headers of the CSV file at `path`
+pub(crate) fn header_index(
+  headers: &csv::StringRecord,
+  name: &str,
+  path: &Path,
+) -> Result<usize, String> {
+  headers
+    .iter()
+    .position(|h| h == name)
+    .ok_or_else(|| format!("{} is missing the `{name}` column", path.display()))
+}
+
+/// Reads `score_metadata.csv`.  Columns are looked up by name, and any columns other than
+/// `score_id`, `avg_pp`, and `num_users` are ignored.
+fn parse_score_metadata(path: &Path) -> Result<Vec<ScoreMetadata>, String> {
+  let file_path = path.display();
+  let mut rdr =
+    csv::Reader::from_path(path).map_err(|err| format!("Failed to open {file_path}: {err}"))?;
+  let headers = rdr.headers().map_err(|err| format!("{err}"))?.clone();
+  let col = |name: &str| header_index(&headers, name, path);
+  let (score_id_col, avg_pp_col, num_users_col) =
+    (col("score_id")?, col("avg_pp")?, col("num_users")?);
+
   let mut score_metadata = Vec::new();
-  for result in rdr.records() {
-    let record = result.unwrap();
-    let score_id = record[0].to_string();
-    let avg_pp = record[1].parse::<f64>().unwrap();
-    let num_users = record[2].parse::<i32>().unwrap();
+  for (line_ix, result) in rdr.records().enumerate() {
+    let record = result.map_err(|err| format!("{err}"))?;
+    let invalid = |name: &str| format!("Invalid `{name}` on row {} of {file_path}", line_ix + 1);
+    let avg_pp = record[avg_pp_col]
+      .parse::<f64>()
+      .map_err(|_| invalid("avg_pp"))?;
+    // pandas may write integer columns as floats
+    let num_users = record[num_users_col]
+      .parse::<f64>()
+      .map_err(|_| invalid("num_users"))? as i32;
     score_metadata.push(ScoreMetadata {
-      score_id,
+      score_id: record[score_id_col].to_string(),
       avg_pp,
       num_users,
     });
   }
-  score_metadata
+  Ok(score_metadata)
 }
 
 // fetched_beatmaps:
@@ -228,19 +278,21 @@ async fn load_beatmap(beatmap_id: i32) -> Result<Option<Vec<u8>>, String> {
   Ok(Some(decompressed))
 }
 
-async fn get_score_ids_needing_difficulty(all_score_ids: &FxHashSet<String>) -> Vec<String> {
+async fn get_score_ids_needing_difficulty(
+  all_score_ids: &FxHashSet<String>,
+) -> Result<Vec<String>, String> {
   let pool = DB_POOL.get().expect("DB pool not initialized");
   let score_ids: Vec<String> = sqlx::query_scalar("SELECT score_id FROM beatmap_difficulties")
     .fetch_all(pool)
     .await
-    .expect("Failed to fetch score IDs");
+    .map_err(|err| format!("Failed to fetch score IDs: {err}"))?;
 
   let score_ids_set: FxHashSet<String> = score_ids.into_iter().collect();
   let score_ids_needing_difficulty: Vec<String> = all_score_ids
     .difference(&score_ids_set)
     .cloned()
     .collect::<Vec<_>>();
-  score_ids_needing_difficulty
+  Ok(score_ids_needing_difficulty)
 }
 
 fn compute_difficulty_inner(
@@ -255,6 +307,12 @@ fn compute_difficulty_inner(
       return Err(format!("{err}"));
     },
   };
+  if map.mode != rosu_pp::model::mode::GameMode::Osu {
+    return Err(format!(
+      "Only osu!standard beatmaps are supported, not {:?}",
+      map.mode
+    ));
+  }
   let DifficultyAttributes::Osu(diff_attrs) = calc.calculate(&map) else {
     unreachable!("Fond no osu! difficulty attributes");
   };
@@ -286,10 +344,11 @@ async fn store_difficulty(
   .map(drop)
 }
 
-async fn compute_difficulty(score_id: &str) -> Result<OsuDifficultyAttributes, String> {
-  let (beatmap_id, mods_string) = score_id
-    .split_once('_')
-    .expect("Failed to split score ID into beatmap ID and mods");
+/// Parses concatenated mod acronyms like `HDDT`
+fn parse_mods(mods_string: &str) -> Result<GameMods, String> {
+  if !mods_string.is_ascii() || !mods_string.len().is_multiple_of(2) {
+    return Err(format!("Invalid mods: {mods_string}"));
+  }
   let mut game_mods = GameMods::new();
   let mod_count = mods_string.len() / 2;
   for i in 0..mod_count {
@@ -297,13 +356,24 @@ async fn compute_difficulty(score_id: &str) -> Result<OsuDifficultyAttributes, S
     let game_mod = GameMod::new(acronym, GameMode::Osu);
     game_mods.insert(game_mod);
   }
+  Ok(game_mods)
+}
+
+async fn compute_difficulty(score_id: &str) -> Result<OsuDifficultyAttributes, String> {
+  let (beatmap_id, mods_string) = score_id
+    .split_once('_')
+    .ok_or_else(|| format!("Invalid score ID: {score_id}"))?;
+  let game_mods = parse_mods(mods_string)?;
+  let beatmap_id: i32 = beatmap_id
+    .parse()
+    .map_err(|_| format!("Invalid score ID: {score_id}"))?;
 
-  let raw_beatmap = match load_beatmap(beatmap_id.parse().unwrap()).await {
+  let raw_beatmap = match load_beatmap(beatmap_id).await {
     Ok(Some(raw_beatmap)) => raw_beatmap,
     Ok(None) => {
       info!("Missing beatmap {beatmap_id}; downloading and storing...");
 
-      let raw_beatmap = match fetch_beatmap(beatmap_id.parse().unwrap()).await {
+      let raw_beatmap = match fetch_beatmap(beatmap_id).await {
         Ok(raw_beatmap) => raw_beatmap,
         Err(err) => {
           error!("{err}");
@@ -311,7 +381,7 @@ async fn compute_difficulty(score_id: &str) -> Result<OsuDifficultyAttributes, S
         },
       };
 
-      let _ = compress_and_insert_beatmap(beatmap_id.parse().unwrap(), &raw_beatmap).await;
+      let _ = compress_and_insert_beatmap(beatmap_id, &raw_beatmap).await;
 
       raw_beatmap
     },
@@ -321,16 +391,18 @@ async fn compute_difficulty(score_id: &str) -> Result<OsuDifficultyAttributes, S
     },
   };
 
-  let difficulty = compute_difficulty_inner(&raw_beatmap, game_mods).unwrap();
-  Ok(difficulty)
+  compute_difficulty_inner(&raw_beatmap, game_mods)
 }
 
-async fn compute_all_difficulties(score_metadata: Vec<ScoreMetadata>) {
+/// Computes and stores the difficulty of every score which doesn't have one yet.  Scores that fail
+/// are skipped so that the rest still get computed, but are reported as an error at the end so
+/// that they're retried.
+async fn compute_all_difficulties(score_metadata: Vec<ScoreMetadata>) -> Result<(), String> {
   let all_score_ids: FxHashSet<String> = score_metadata
     .iter()
     .map(|metadata| metadata.score_id.clone())
     .collect();
-  let score_ids_needing_difficulty = get_score_ids_needing_difficulty(&all_score_ids).await;
+  let score_ids_needing_difficulty = get_score_ids_needing_difficulty(&all_score_ids).await?;
 
   info!(
     "Need to compute difficulties for {} scores",
@@ -363,6 +435,13 @@ async fn compute_all_difficulties(score_metadata: Vec<ScoreMetadata>) {
   }
 
   info!("Finished computing difficulties: {success_count} successes, {failure_count} failures");
+  if failure_count > 0 {
+    return Err(format!(
+      "Failed to compute difficulties for {failure_count} of {} scores",
+      success_count + failure_count
+    ));
+  }
+  Ok(())
 }
 
 struct DifficultyRecord {
@@ -385,13 +464,114 @@ async fn load_difficulties() -> Vec<DifficultyRecord> {
   difficulties
 }
 
-async fn dump_difficulties() {
+/// Reads difficulties from a CSV file written by `dump-difficulties`.  Columns are looked up by
+/// name.
+fn read_difficulties_csv(path: &Path) -> Result<Vec<DifficultyRecord>, String> {
+  let file_path = path.display();
+  let mut rdr =
+    csv::Reader::from_path(path).map_err(|err| format!("Failed to open {file_path}: {err}"))?;
+  let headers = rdr.headers().map_err(|err| format!("{err}"))?.clone();
+  let col = |name: &str| header_index(&headers, name, path);
+  let score_id_col = col("score_id")?;
+  let value_cols = [
+    col("difficulty_aim")?,
+    col("difficulty_speed")?,
+    col("difficulty_flashlight")?,
+    col("speed_note_count")?,
+    col("slider_factor")?,
+    col("stars")?,
+  ];
+
+  let mut difficulties = Vec::new();
+  for (line_ix, result) in rdr.records().enumerate() {
+    let record = result.map_err(|err| format!("{err}"))?;
+    let mut values = [0.; 6];
+    for (value, &col_ix) in values.iter_mut().zip(&value_cols) {
+      *value = record[col_ix].parse::<f64>().map_err(|_| {
+        format!(
+          "Invalid `{}` on row {} of {file_path}",
+          &headers[col_ix],
+          line_ix + 1
+        )
+      })?;
+    }
+    let [difficulty_aim, difficulty_speed, difficulty_flashlight, speed_note_count, slider_factor, stars] =
+      values;
+    difficulties.push(DifficultyRecord {
+      score_id: record[score_id_col].to_string(),
+      difficulty_aim,
+      difficulty_speed,
+      difficulty_flashlight,
+      speed_note_count,
+      slider_factor,
+      stars,
+    });
+  }
+  Ok(difficulties)
+}
+
+/// Loads difficulties for building the corpus, either from the database or, if `offline`, from
+/// the CSV file at `difficulties_path`
+async fn load_build_difficulties(
+  offline: bool,
+  difficulties_path: &Path,
+  database: &config::DatabaseSettings,
+) -> Vec<DifficultyRecord> {
+  if offline {
+    let difficulties =
+      read_difficulties_csv(difficulties_path).unwrap_or_else(|err| panic!("{err}"));
+    info!(
+      "Read {} difficulties from {}",
+      difficulties.len(),
+      difficulties_path.display()
+    );
+    difficulties
+  } else {
+    connect_db(database).await;
+    load_difficulties().await
+  }
+}
+
+/// Loads the metadata parsed from `.osu` files for building the corpus, either from the database
+/// or, if `offline`, from the CSV file at `osu_metadata_path`.  Offline builds without that file
+/// leave the tags and unicode names empty.
+async fn load_build_osu_metadata(
+  offline: bool,
+  osu_metadata_path: &Path,
+  database: &config::DatabaseSettings,
+) -> FxHashMap<i32, osu_metadata::OsuFileMetadata> {
+  if !offline {
+    connect_db(database).await;
+    return osu_metadata::load_osu_metadata()
+      .await
+      .unwrap_or_else(|err| panic!("{err}"));
+  }
+
+  if !osu_metadata_path.exists() {
+    warn!(
+      "{} doesn't exist, so the corpus won't include tags or unicode names; run \
+       `dump-difficulties` to create it",
+      osu_metadata_path.display()
+    );
+    return FxHashMap::default();
+  }
+  let metadata =
+    osu_metadata::read_osu_metadata_csv(osu_metadata_path).unwrap_or_else(|err| panic!("{err}"));
+  info!(
+    "Read metadata for {} beatmaps from {}",
+    metadata.len(),
+    osu_metadata_path.display()
+  );
+  metadata
+}
+
+async fn dump_difficulties(out_path: &Path) {
   let difficulties = load_difficulties().await;
 
-  let out_filename = "../../data/difficulties.csv";
-  let mut wtr = csv::Writer::from_path(out_filename).unwrap();
+  let mut wtr = csv::Writer::from_path(out_path)
+    .unwrap_or_else(|err| panic!("Failed to create {}: {err}", out_path.display()));
   wtr
-    .write_record(&[
+    .write_record([
       "score_id",
       "difficulty_aim",
       "difficulty_speed",
@@ -425,9 +605,68 @@ async fn dump_difficulties() {
   }
   wtr.flush().expect("Failed to flush writer");
 
-  info!("Dumped difficulties to {out_filename}");
+  info!("Dumped difficulties to {}", out_path.display());
+}
+
+/// Writes the corpus along with its compressed variants, manifest, sidecars, and a local corpus
+/// index listing it to `out_dir`
+async fn write_corpus_dir(
+  corpus: &build_corpus::BuiltCorpus,
+  out_dir: &Path,
+  neighbors_k: usize,
+  cooccurrence: Option<&Path>,
+  cluster_params: &clusters::ClusterParams,
+) {
+  tokio::fs::create_dir_all(out_dir)
+    .await
+    .expect("Failed to create output directory");
+
+  let neighbors = tokio::task::block_in_place(|| {
+    neighbors::build_neighbors(&corpus.rows, neighbors_k, cooccurrence)
+  })
+  .unwrap_or_else(|err| panic!("{err}"));
+  let clustering =
+    tokio::task::block_in_place(|| clusters::cluster_rows(&corpus.rows, cluster_params));
+  let clusters_json =
+    serde_json::to_vec(&clustering.clusters).expect("Failed to serialize clusters");
+  let search_index = tokio::task::block_in_place(|| search_index::build_search_index(&corpus.rows));
+  let groups = tokio::task::block_in_place(|| groups::build_groups(&corpus.rows));
+  let mappers_json = serde_json::to_vec(&mappers::aggregate_mappers(&corpus.rows))
+    .expect("Failed to serialize mappers");
+
+  let sidecars = [
+    manifest::Sidecar {
+      file_name: "neighbors",
+      data: neighbors,
+    },
+    manifest::Sidecar {
+      file_name: "clusters",
+      data: clusters::encode_cluster_ids(&clustering.cluster_ids),
+    },
+    manifest::Sidecar {
+      file_name: "clusters.json",
+      data: clusters_json,
+    },
+    manifest::Sidecar {
+      file_name: "search_index",
+      data: search_index,
+    },
+    manifest::Sidecar {
+      file_name: "mappers.json",
+      data: mappers_json,
+    },
+    manifest::Sidecar {
+      file_name: "groups",
+      data: groups,
+    },
+  ];
+  manifest::write_corpus_artifacts(corpus, &sidecars, out_dir).await;
+  publish::write_local_corpus_index(out_dir).unwrap_or_else(|err| panic!("{err}"));
 }
 
+const DEFAULT_NEIGHBORS_K: usize = 10;
+const DEFAULT_CLUSTER_MIN_POINTS: usize = 25;
+
 #[derive(Subcommand)]
 enum Command {
   #[clap(name = "download")]
@@ -439,11 +678,301 @@ enum Command {
   #[clap(name = "dump-difficulties")]
   DumpDifficulties,
   #[clap(name = "build-corpus")]
-  BuildCorpus,
+  BuildCorpus {
+    /// Embedding to build the corpus from, as a JSON object mapping score IDs to positions.
+    /// Defaults to the configured embedding.
+    #[clap(long)]
+    embedding: Option<PathBuf>,
+    /// Placements file written by `place-new`.  Rows for the placed score IDs are flagged as low
+    /// confidence.
+    #[clap(long)]
+    placements: Option<PathBuf>,
+    /// Reference corpus to align the new embedding to so that familiar regions stay in the same
+    /// place between releases
+    #[clap(long)]
+    align_to: Option<PathBuf>,
+    /// Number of nearest neighbors to store for each row in the neighbors sidecar
+    #[clap(
+      long,
+      default_value_t = DEFAULT_NEIGHBORS_K,
+      value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    neighbors_k: usize,
+    /// Co-occurrence graph in MatrixMarket format, with a `{path}.csv` score ID mapping next to
+    /// it.  If provided, co-occurrence neighbors are included in the neighbors sidecar.
+    #[clap(long)]
+    cooccurrence: Option<PathBuf>,
+    /// Minimum number of points within `cluster_eps` of a point for it to be a cluster core point
+    #[clap(long, default_value_t = DEFAULT_CLUSTER_MIN_POINTS)]
+    cluster_min_points: usize,
+    /// Neighborhood radius used for cluster detection, in embedding units.  Defaults to the median
+    /// distance from each point to its `cluster_min_points`-th nearest neighbor.
+    #[clap(long, value_parser = clusters::parse_eps)]
+    cluster_eps: Option<f32>,
+    /// Builds a snapshot of the atlas as of this date (YYYY-MM-DD).  Score stats and the set of
+    /// included scores are derived from the hiscores recorded before it rather than from
+    /// `score_metadata.csv`.  Embedding positions are unchanged.
+    #[clap(long)]
+    as_of: Option<NaiveDate>,
+    /// Hiscores to derive score stats from when `--as-of` is set
+    #[clap(long)]
+    hiscores: Option<PathBuf>,
+    /// Directory to write the corpus and its sidecars to.  Defaults to the configured corpus
+    /// directory.
+    #[clap(long)]
+    out_dir: Option<PathBuf>,
+    /// Reads difficulties from the file written by `dump-difficulties` instead of the database
+    #[clap(long)]
+    offline: bool,
+  },
+  /// Builds the score co-occurrence graph that the embedding is generated from out of users' top
+  /// plays
+  #[clap(name = "build-graph")]
+  BuildGraph {
+    #[clap(long)]
+    hiscores: Option<PathBuf>,
+    /// Output path for the raw co-occurrence weights, which can be passed to `build-corpus
+    /// --cooccurrence`.  The score ID mapping is written to `{path}.csv`.
+    #[clap(long)]
+    out: Option<PathBuf>,
+    /// Output path for the weights transformed into distances, which is what PyMDE embeds
+    #[clap(long)]
+    distances_out: Option<PathBuf>,
+    /// Number of strongest co-occurrences retained for each score
+    #[clap(long, default_value_t = 700)]
+    top_n: usize,
+  },
+  /// Positions score IDs which aren't in the embedding yet, such as newly ranked maps, using their
+  /// co-occurrence neighbors which are already embedded
+  #[clap(name = "place-new")]
+  PlaceNew {
+    #[clap(long)]
+    embedding: Option<PathBuf>,
+    /// Co-occurrence weights written by `build-graph`, including edges for the new score IDs
+    #[clap(long)]
+    cooccurrence: Option<PathBuf>,
+    /// Output path for the embedding with the new score IDs added
+    #[clap(long)]
+    out: Option<PathBuf>,
+    /// Output path for placement details, which can be passed to `build-corpus --placements`
+    #[clap(long)]
+    placements_out: Option<PathBuf>,
+    #[clap(long, default_value_t = 20)]
+    max_neighbors: usize,
+    #[clap(long, default_value_t = 3)]
+    min_neighbors: usize,
+  },
+  /// Generates `score_metadata.csv` with per-score statistics from the hiscores parquet file
+  #[clap(name = "score-metadata")]
+  ScoreMetadata {
+    #[clap(long)]
+    hiscores: Option<PathBuf>,
+    /// Defaults to the configured score metadata path
+    #[clap(long)]
+    out: Option<PathBuf>,
+    /// Only include plays recorded before this date (YYYY-MM-DD)
+    #[clap(long)]
+    as_of: Option<NaiveDate>,
+  },
+  /// Computes monthly popularity series for each corpus row from the timestamped hiscores
+  #[clap(name = "popularity")]
+  Popularity {
+    #[clap(long)]
+    corpus: Option<PathBuf>,
+    #[clap(long)]
+    hiscores: Option<PathBuf>,
+    /// Defaults to `popularity` in the corpus directory
+    #[clap(long)]
+    out: Option<PathBuf>,
+  },
+  /// Fetches a user's top plays from the osu! API and summarizes where they sit in the atlas.
+  /// Needs `OSU_CLIENT_ID` and `OSU_CLIENT_SECRET` to be set.
+  #[clap(name = "user-profile")]
+  UserProfile {
+    /// User ID or username
+    user: String,
+    #[clap(long)]
+    corpus: Option<PathBuf>,
+    /// Hiscores of all tracked players, which the user's percentile is computed against
+    #[clap(long)]
+    hiscores: Option<PathBuf>,
+    /// Defaults to `user_profile_<user>.json` in the data directory
+    #[clap(long)]
+    out: Option<PathBuf>,
+  },
+  /// Recommends unplayed maps near a player's top plays which are a bit harder than what they've
+  /// played so far
+  #[clap(name = "recommend")]
+  Recommend {
+    /// Score IDs of the player's top plays
+    score_ids: Vec<String>,
+    /// File with more score IDs of the player's top plays, one per line
+    #[clap(long)]
+    plays: Option<PathBuf>,
+    #[clap(long)]
+    corpus: Option<PathBuf>,
+    /// Co-occurrence graph written by `build-graph`.  If provided, maps that are often played
+    /// alongside the player's plays are recommended as well as ones that are close in the
+    /// embedding.
+    #[clap(long)]
+    cooccurrence: Option<PathBuf>,
+    #[clap(long, default_value_t = 20)]
+    count: usize,
+    /// Number of neighbors of each play that are considered
+    #[clap(long, default_value_t = 25)]
+    neighbors_k: usize,
+    #[clap(long, default_value_t = 0.5)]
+    max_star_gain: f64,
+    /// Maximum relative increase in average pp
+    #[clap(long, default_value_t = 0.2)]
+    max_pp_gain: f64,
+    /// Writes the recommendations as JSON to this path instead of printing them
+    #[clap(long)]
+    out: Option<PathBuf>,
+  },
+  /// Serves an HTTP API over the corpus with score lookups, neighbor queries, and on-demand
+  /// difficulty calculation
+  #[clap(name = "serve")]
+  Serve {
+    /// Directory containing the corpus and its sidecars.  Defaults to the configured corpus
+    /// directory.
+    #[clap(long)]
+    corpus_dir: Option<PathBuf>,
+    #[clap(long, default_value = "127.0.0.1:4800")]
+    listen: std::net::SocketAddr,
+  },
+  /// Exports the joined corpus rows for use in notebooks and other analysis tools
+  #[clap(name = "export")]
+  Export {
+    #[clap(long)]
+    embedding: Option<PathBuf>,
+    #[clap(long, value_enum, default_value = "parquet")]
+    format: export::ExportFormat,
+    /// Defaults to `corpus.<format extension>` in the data directory
+    #[clap(long)]
+    out: Option<PathBuf>,
+    /// Reads difficulties from the file written by `dump-difficulties` instead of the database
+    #[clap(long)]
+    offline: bool,
+  },
+  /// Runs the stages that produce a release in dependency order: `score-metadata`, `download`,
+  /// `compute-all`, `dump-difficulties`, `build-corpus`, and `validate`.  Stages whose inputs
+  /// haven't changed since they last succeeded are skipped.
+  #[clap(name = "pipeline")]
+  Pipeline {
+    /// Only run this stage and the ones that depend on it
+    #[clap(long, value_enum)]
+    from: Option<pipeline::Stage>,
+    /// Only run this stage and the ones it depends on
+    #[clap(long, value_enum)]
+    until: Option<pipeline::Stage>,
+    /// Rerun the selected stages even if their inputs are unchanged
+    #[clap(long)]
+    force: bool,
+  },
+  /// Uploads the corpus and its manifest to S3-compatible object storage, verifies them by reading
+  /// them back, and adds them to the corpus index as the latest release
+  #[clap(name = "publish")]
+  Publish {
+    /// Directory containing the corpus and its manifest.  Defaults to the configured corpus
+    /// directory.
+    #[clap(long)]
+    corpus_dir: Option<PathBuf>,
+    #[clap(flatten)]
+    storage: StorageArgs,
+    /// Writes the public URL of the corpus index to this file.  Needs `publish.public_url` to be
+    /// configured.
+    #[clap(long)]
+    index_url_out: Option<PathBuf>,
+  },
+  /// Adds a corpus which is already hosted elsewhere to the corpus index without uploading it
+  #[clap(name = "register-release")]
+  RegisterRelease {
+    /// Local copy of the hosted corpus
+    corpus: PathBuf,
+    /// URL that the corpus is hosted at
+    #[clap(long)]
+    url: String,
+    /// ID used to link to the release
+    #[clap(long)]
+    id: String,
+    /// When the corpus was built, as a Unix timestamp in seconds
+    #[clap(long)]
+    built_at: i64,
+    /// The corpus's positions are mirrored through the origin compared to other releases
+    #[clap(long)]
+    mirrored: bool,
+    /// Makes this the release that's shown by default
+    #[clap(long)]
+    latest: bool,
+    #[clap(flatten)]
+    storage: StorageArgs,
+  },
+  /// Reports what changed between two corpus files
+  #[clap(name = "diff-corpus")]
+  DiffCorpus {
+    old: PathBuf,
+    new: PathBuf,
+    /// Defaults to `corpus_diff.json` in the data directory
+    #[clap(long)]
+    json_out: Option<PathBuf>,
+    /// Minimum distance for a score to count as moved, as a fraction of the new embedding's
+    /// bounding box diagonal
+    #[clap(long, default_value_t = 0.05)]
+    move_threshold: f32,
+    #[clap(long, default_value_t = 0.1)]
+    stars_threshold: f64,
+    /// Minimum relative change in average pp
+    #[clap(long, default_value_t = 0.1)]
+    avg_pp_threshold: f64,
+    /// Minimum relative change in user count
+    #[clap(long, default_value_t = 0.25)]
+    num_users_threshold: f64,
+  },
+}
+
+/// Overrides for the `[publish]` config settings
+#[derive(Args)]
+struct StorageArgs {
+  #[clap(long)]
+  bucket: Option<String>,
+  /// Endpoint URL for storage other than AWS, like `http://localhost:9000` for a local MinIO
+  #[clap(long)]
+  endpoint: Option<String>,
+  /// Key prefix that releases and the corpus index are stored under
+  #[clap(long)]
+  prefix: Option<String>,
+}
+
+impl StorageArgs {
+  fn apply(self, settings: &mut config::PublishSettings) {
+    settings.bucket = self.bucket.or(settings.bucket.take());
+    settings.endpoint = self.endpoint.or(settings.endpoint.take());
+    settings.prefix = self.prefix.or(settings.prefix.take());
+  }
 }
 
 #[derive(Parser)]
 struct Cli {
+  /// TOML config file with data paths and database settings.  Defaults to `atlas.toml` in the
+  /// working directory if it exists.
+  #[clap(long, global = true)]
+  config: Option<PathBuf>,
+  /// Directory that the default input and output paths are in.  Defaults to `../../data`.
+  #[clap(long, global = true)]
+  data_dir: Option<PathBuf>,
+  #[clap(long, global = true)]
+  score_metadata: Option<PathBuf>,
+  /// Beatmap metadata parquet file
+  #[clap(long, global = true)]
+  beatmaps: Option<PathBuf>,
+  /// Output path for `dump-difficulties`, which `build-corpus` and `export` read with `--offline`
+  #[clap(long, global = true)]
+  difficulties: Option<PathBuf>,
+  /// MySQL connection URL.  Takes precedence over all other database settings, including
+  /// `DATABASE_URL` in the environment or `.env`.
+  #[clap(long, global = true)]
+  database_url: Option<String>,
   #[clap(subcommand)]
   command: Command,
 }
@@ -462,26 +991,355 @@ async fn main() {
   .expect("Failed to initialize telemetry");
 
   let cli = Cli::parse();
-
-  let score_metadata_filename = "../../data/score_metadata.csv";
-  let score_metadata = parse_score_metadata(score_metadata_filename);
-
-  init_db_pool().await;
+  let mut config = config::Config::load(cli.config.as_deref(), cli.data_dir.as_deref())
+    .unwrap_or_else(|err| panic!("{err}"));
+  if let Some(score_metadata) = cli.score_metadata {
+    config.paths.score_metadata = score_metadata;
+  }
+  if let Some(beatmaps) = cli.beatmaps {
+    config.paths.beatmaps = beatmaps;
+  }
+  if let Some(difficulties) = cli.difficulties {
+    config.paths.difficulties = difficulties;
+  }
+  if let Some(database_url) = cli.database_url {
+    config.database.url_override = Some(database_url);
+  }
+  let paths = &config.paths;
+  let load_score_metadata =
+    || parse_score_metadata(&paths.score_metadata).unwrap_or_else(|err| panic!("{err}"));
 
   match cli.command {
-    Command::DownloadAllBeatmaps => download_all_beatmaps(score_metadata).await,
-    Command::ComputeAllDifficulties => compute_all_difficulties(score_metadata).await,
+    Command::DownloadAllBeatmaps => {
+      let score_metadata = load_score_metadata();
+      connect_db(&config.database).await;
+      download_all_beatmaps(score_metadata).await
+    },
+    Command::ComputeAllDifficulties => {
+      let score_metadata = load_score_metadata();
+      connect_db(&config.database).await;
+      compute_all_difficulties(score_metadata)
+        .await
+        .unwrap_or_else(|err| panic!("{err}"))
+    },
     Command::Compute { score_id } => {
+      connect_db(&config.database).await;
       let difficulty = compute_difficulty(&score_id).await.unwrap();
       println!("{difficulty:?}");
     },
-    Command::DumpDifficulties => dump_difficulties().await,
-    Command::BuildCorpus => {
-      let corpus = build_corpus::build_corpus(score_metadata).await;
-      let out_filename = "../../data/corpus";
-      tokio::fs::write(out_filename, corpus)
+    Command::DumpDifficulties => {
+      connect_db(&config.database).await;
+      dump_difficulties(&paths.difficulties).await;
+      osu_metadata::dump_osu_metadata(&paths.osu_metadata)
+        .await
+        .unwrap_or_else(|err| panic!("{err}"));
+    },
+    Command::BuildCorpus {
+      embedding,
+      placements,
+      align_to,
+      neighbors_k,
+      cooccurrence,
+      cluster_min_points,
+      cluster_eps,
+      as_of,
+      hiscores,
+      out_dir,
+      offline,
+    } => {
+      let embedding = embedding.unwrap_or_else(|| paths.embedding.clone());
+      let hiscores = hiscores.unwrap_or_else(|| paths.hiscores.clone());
+      let out_dir = out_dir.unwrap_or_else(|| paths.corpus_dir.clone());
+      let score_metadata = match as_of {
+        Some(as_of) =>
+          tokio::task::block_in_place(|| score_metadata::score_metadata_as_of(&hiscores, as_of))
+            .unwrap_or_else(|err| panic!("{err}")),
+        None => load_score_metadata(),
+      };
+      let difficulties =
+        load_build_difficulties(offline, &paths.difficulties, &config.database).await;
+      let osu_metadata =
+        load_build_osu_metadata(offline, &paths.osu_metadata, &config.database).await;
+      let corpus = build_corpus::build_corpus(
+        score_metadata,
+        difficulties,
+        osu_metadata,
+        &embedding,
+        &paths.beatmaps,
+        align_to.as_deref(),
+        placements.as_deref(),
+        as_of.is_some(),
+      )
+      .await;
+      let cluster_params = clusters::ClusterParams {
+        min_points: cluster_min_points,
+        eps: cluster_eps,
+      };
+      write_corpus_dir(
+        &corpus,
+        &out_dir,
+        neighbors_k,
+        cooccurrence.as_deref(),
+        &cluster_params,
+      )
+      .await;
+    },
+    Command::BuildGraph {
+      hiscores,
+      out,
+      distances_out,
+      top_n,
+    } => {
+      let hiscores = hiscores.unwrap_or_else(|| paths.hiscores.clone());
+      let out = out.unwrap_or_else(|| paths.cooccurrence.clone());
+      let distances_out = distances_out.unwrap_or_else(|| paths.cooccurrence_distances.clone());
+      tokio::task::block_in_place(|| {
+        build_graph::build_graph(&hiscores, &out, &distances_out, top_n)
+      })
+      .unwrap_or_else(|err| panic!("{err}"));
+    },
+    Command::PlaceNew {
+      embedding,
+      cooccurrence,
+      out,
+      placements_out,
+      max_neighbors,
+      min_neighbors,
+    } => {
+      let embedding = embedding.unwrap_or_else(|| paths.embedding.clone());
+      let cooccurrence = cooccurrence.unwrap_or_else(|| paths.cooccurrence.clone());
+      let out = out.unwrap_or_else(|| paths.embedding_placed.clone());
+      let placements_out = placements_out.unwrap_or_else(|| paths.placements.clone());
+      let params = place_new::PlacementParams {
+        max_neighbors,
+        min_neighbors,
+      };
+      tokio::task::block_in_place(|| {
+        place_new::place_new(&embedding, &cooccurrence, &out, &placements_out, &params)
+      })
+      .unwrap_or_else(|err| panic!("{err}"));
+    },
+    Command::ScoreMetadata {
+      hiscores,
+      out,
+      as_of,
+    } => {
+      let hiscores = hiscores.unwrap_or_else(|| paths.hiscores.clone());
+      let out = out.unwrap_or_else(|| paths.score_metadata.clone());
+      tokio::task::block_in_place(|| {
+        score_metadata::generate_score_metadata(&hiscores, &out, as_of)
+      })
+      .unwrap_or_else(|err| panic!("{err}"));
+    },
+    Command::Popularity {
+      corpus,
+      hiscores,
+      out,
+    } => {
+      let corpus = corpus.unwrap_or_else(|| paths.corpus());
+      let hiscores = hiscores.unwrap_or_else(|| paths.hiscores.clone());
+      let out = out.unwrap_or_else(|| paths.corpus_dir.join("popularity"));
+      tokio::task::block_in_place(|| popularity::build_popularity(&corpus, &hiscores, &out))
+        .unwrap_or_else(|err| panic!("{err}"));
+    },
+    Command::UserProfile {
+      user,
+      corpus,
+      hiscores,
+      out,
+    } => {
+      let client_id = std::env::var("OSU_CLIENT_ID")
+        .expect("OSU_CLIENT_ID must be set")
+        .parse()
+        .expect("OSU_CLIENT_ID must be a number");
+      let client_secret =
+        std::env::var("OSU_CLIENT_SECRET").expect("OSU_CLIENT_SECRET must be set");
+      let api = user_profile::RosuApi::new(client_id, client_secret)
+        .await
+        .unwrap_or_else(|err| panic!("{err}"));
+      let out = out.unwrap_or_else(|| paths.data_dir.join(format!("user_profile_{user}.json")));
+      let corpus = corpus.unwrap_or_else(|| paths.corpus());
+      let hiscores = hiscores.unwrap_or_else(|| paths.hiscores.clone());
+      user_profile::user_profile(&api, &user, &corpus, &hiscores, &out)
+        .await
+        .unwrap_or_else(|err| panic!("{err}"));
+    },
+    Command::Recommend {
+      mut score_ids,
+      plays,
+      corpus,
+      cooccurrence,
+      count,
+      neighbors_k,
+      max_star_gain,
+      max_pp_gain,
+      out,
+    } => {
+      if let Some(plays) = plays {
+        let plays = std::fs::read_to_string(&plays)
+          .unwrap_or_else(|err| panic!("Failed to read {}: {err}", plays.display()));
+        score_ids.extend(
+          plays
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from),
+        );
+      }
+      let params = recommend::RecommendParams {
+        count,
+        neighbors_k,
+        max_star_gain,
+        max_pp_gain,
+      };
+      let corpus = corpus.unwrap_or_else(|| paths.corpus());
+      let recommendations = tokio::task::block_in_place(|| {
+        recommend::recommend_from_files(&corpus, cooccurrence.as_deref(), &score_ids, &params)
+      })
+      .unwrap_or_else(|err| panic!("{err}"));
+      match out {
+        Some(out) => {
+          let json =
+            serde_json::to_string(&recommendations).expect("Failed to serialize recommendations");
+          std::fs::write(&out, json).expect("Failed to write recommendations");
+          info!(
+            "Wrote {} recommendations to {}",
+            recommendations.len(),
+            out.display()
+          );
+        },
+        None =>
+          for recommendation in &recommendations {
+            println!("{}: {}", recommendation.score_id, recommendation.reason);
+          },
+      }
+    },
+    Command::Serve { corpus_dir, listen } => {
+      let corpus_dir = corpus_dir.unwrap_or_else(|| paths.corpus_dir.clone());
+      // Only needed for ranked beatmap difficulty lookups, so the rest of the API works without it.
+      // The pool connects lazily so that an unreachable database only fails those lookups.
+      match config.database.connection_url().and_then(|database_url| {
+        sqlx::MySqlPool::connect_lazy(&database_url)
+          .map_err(|err| format!("Invalid database URL: {err}"))
+      }) {
+        Ok(pool) => DB_POOL.set(pool).unwrap(),
+        Err(err) => warn!("{err}; difficulty lookups by beatmap ID are disabled"),
+      }
+      serve::serve(&corpus_dir, listen)
+        .await
+        .unwrap_or_else(|err| panic!("{err}"));
+    },
+    Command::Export {
+      embedding,
+      format,
+      out,
+      offline,
+    } => {
+      let embedding = embedding.unwrap_or_else(|| paths.embedding.clone());
+      let score_metadata = load_score_metadata();
+      let difficulties =
+        load_build_difficulties(offline, &paths.difficulties, &config.database).await;
+      let osu_metadata =
+        load_build_osu_metadata(offline, &paths.osu_metadata, &config.database).await;
+      let rows = build_corpus::build_corpus_rows(
+        score_metadata,
+        difficulties,
+        osu_metadata,
+        &embedding,
+        &paths.beatmaps,
+        false,
+      )
+      .await;
+      let out = out.unwrap_or_else(|| {
+        paths
+          .data_dir
+          .join(format!("corpus.{}", format.extension()))
+      });
+      tokio::task::block_in_place(|| export::export_corpus_rows(&rows, format, &out))
+        .unwrap_or_else(|err| panic!("{err}"));
+    },
+    Command::Pipeline { from, until, force } => {
+      let mut stages = pipeline::AtlasStages {
+        paths,
+        database: &config.database,
+      };
+      let options = pipeline::PipelineOptions { from, until, force };
+      pipeline::run_pipeline(&mut stages, &paths.pipeline_state, &options)
+        .await
+        .unwrap_or_else(|err| panic!("{err}"));
+    },
+    Command::Publish {
+      corpus_dir,
+      storage,
+      index_url_out,
+    } => {
+      let corpus_dir = corpus_dir.unwrap_or_else(|| paths.corpus_dir.clone());
+      let mut settings = config.publish;
+      storage.apply(&mut settings);
+      let prefix = settings.prefix.as_deref().unwrap_or_default();
+      let store = publish::s3_store(&settings).unwrap_or_else(|err| panic!("{err}"));
+      publish::publish_corpus(&store, prefix, &corpus_dir)
         .await
-        .expect("Failed to write corpus");
+        .unwrap_or_else(|err| panic!("{err}"));
+      if let Some(index_url_out) = index_url_out {
+        let public_url = settings
+          .public_url
+          .as_deref()
+          .expect("`publish.public_url` must be configured to write the corpus index URL");
+        let index_key = publish::object_key(prefix, publish::CORPUS_INDEX_KEY);
+        std::fs::write(
+          &index_url_out,
+          format!("{}/{index_key}", public_url.trim_end_matches('/')),
+        )
+        .unwrap_or_else(|err| panic!("Failed to write {}: {err}", index_url_out.display()));
+      }
+    },
+    Command::RegisterRelease {
+      corpus,
+      url,
+      id,
+      built_at,
+      mirrored,
+      latest,
+      storage,
+    } => {
+      let mut settings = config.publish;
+      storage.apply(&mut settings);
+      let store = publish::s3_store(&settings).unwrap_or_else(|err| panic!("{err}"));
+      let release = publish::ExternalRelease {
+        id: &id,
+        url: &url,
+        corpus_path: &corpus,
+        built_at,
+        mirrored,
+        latest,
+      };
+      publish::register_release(
+        &store,
+        settings.prefix.as_deref().unwrap_or_default(),
+        &release,
+      )
+      .await
+      .unwrap_or_else(|err| panic!("{err}"));
+    },
+    Command::DiffCorpus {
+      old,
+      new,
+      json_out,
+      move_threshold,
+      stars_threshold,
+      avg_pp_threshold,
+      num_users_threshold,
+    } => {
+      let thresholds = diff_corpus::DiffThresholds {
+        move_fraction: move_threshold,
+        stars: stars_threshold,
+        avg_pp_fraction: avg_pp_threshold,
+        num_users_fraction: num_users_threshold,
+      };
+      let json_out = json_out.unwrap_or_else(|| paths.data_dir.join("corpus_diff.json"));
+      diff_corpus::diff_corpus(&old, &new, &json_out, &thresholds)
+        .unwrap_or_else(|err| panic!("{err}"));
     },
   }
 }
@@ -499,7 +1357,10 @@ async fn main() {
 async fn beatmap_download_sanity() {
   let _ = dotenv::dotenv();
 
-  init_db_pool().await;
+  let database_url = config::DatabaseSettings::default()
+    .connection_url()
+    .unwrap();
+  init_db_pool(&database_url).await;
 
   let beatmap_id = 150057;
   sqlx::query!(
@@ -519,3 +1380,33 @@ async fn beatmap_download_sanity() {
 
   assert_eq!(raw_beatmap, raw_beatmap_from_db);
 }
+
+/// Columns are found by header name, and the accuracy columns which are left blank when the dump
+/// has no judgement counts don't get in the way
+#[test]
+fn score_metadata_columns_are_looked_up_by_name() {
+  let path = std::env::temp_dir().join(format!("score-metadata-test-{}.csv", std::process::id()));
+  std::fs::write(
+    &path,
+    "num_users,median_pp,score_id,mean_accuracy,median_accuracy,avg_pp,grade_ss\n12.0,150,\
+     129891_DT,,,151.5,3\n4,80,75_,,,79.25,0\n",
+  )
+  .unwrap();
+  let score_metadata = parse_score_metadata(&path).unwrap();
+  let parsed: Vec<(&str, f64, i32)> = score_metadata
+    .iter()
+    .map(|metadata| {
+      (
+        metadata.score_id.as_str(),
+        metadata.avg_pp,
+        metadata.num_users,
+      )
+    })
+    .collect();
+  assert_eq!(parsed, [("129891_DT", 151.5, 12), ("75_", 79.25, 4)]);
+
+  std::fs::write(&path, "score_id,num_users\n75_,4\n").unwrap();
+  let err = parse_score_metadata(&path).err().unwrap();
+  std::fs::remove_file(&path).unwrap();
+  assert!(err.contains("`avg_pp`"), "{err}");
+}
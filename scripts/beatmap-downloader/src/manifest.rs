@@ -0,0 +1,223 @@
+//! Writes pre-compressed variants of the built corpus and its sidecars along with a
+//! `manifest.json` describing them.  Clients can use the manifest to pick the best encoding they
+//! support and to verify that what they downloaded is complete and matches the release.
+
+use std::path::Path;
+
+use foundations::telemetry::log::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::build_corpus::{BuiltCorpus, CORPUS_FORMAT_VERSION};
+
+const BROTLI_QUALITY: u32 = 11;
+const BROTLI_WINDOW_BITS: u32 = 22;
+const ZSTD_LEVEL: i32 = 19;
+
+/// Fraction of points on each side which are ignored when computing the default view so that a
+/// handful of outliers don't force the initial view to be zoomed way out.
+const DEFAULT_VIEW_OUTLIER_QUANTILE: f32 = 0.01;
+const DEFAULT_VIEW_PADDING: f32 = 1.1;
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct CorpusArtifact {
+  /// Value of the `Content-Encoding` that the artifact is stored with
+  pub encoding: String,
+  pub file_name: String,
+  pub size: usize,
+  pub sha256: String,
+}
+
+/// File built alongside the corpus which refers to its rows by index, like the neighbors
+pub(crate) struct Sidecar {
+  pub file_name: &'static str,
+  pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct CorpusBounds {
+  pub min_x: f32,
+  pub max_x: f32,
+  pub min_y: f32,
+  pub max_y: f32,
+}
+
+/// Initial view into the embedding, in the same coordinate space as the positions stored in the
+/// corpus.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct DefaultView {
+  pub center: [f32; 2],
+  pub span_x: f32,
+  pub span_y: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct CorpusManifest {
+  pub format_version: u32,
+  pub row_count: u32,
+  /// Unix timestamp in seconds
+  pub built_at: i64,
+  /// SHA-256 of the uncompressed corpus
+  pub content_sha256: String,
+  pub bounds: CorpusBounds,
+  pub default_view: DefaultView,
+  pub artifacts: Vec<CorpusArtifact>,
+  /// Only stored uncompressed.  Not listed by manifests from before sidecars were added.
+  #[serde(default)]
+  pub sidecars: Vec<CorpusArtifact>,
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String { format!("{:x}", Sha256::digest(data)) }
+
+fn compress_brotli(data: &[u8]) -> Vec<u8> {
+  let mut out = Vec::new();
+  {
+    let mut writer =
+      brotli::CompressorWriter::new(&mut out, 4096, BROTLI_QUALITY, BROTLI_WINDOW_BITS);
+    std::io::Write::write_all(&mut writer, data).expect("Failed to brotli-compress corpus");
+  }
+  out
+}
+
+fn compress_zstd(data: &[u8]) -> Vec<u8> {
+  zstd::bulk::compress(data, ZSTD_LEVEL).expect("Failed to zstd-compress corpus")
+}
+
+pub(crate) fn compute_bounds(positions: &[[f32; 2]]) -> CorpusBounds {
+  // infinite bounds would be written as `null` and then fail to parse
+  if positions.is_empty() {
+    return CorpusBounds {
+      min_x: 0.,
+      max_x: 0.,
+      min_y: 0.,
+      max_y: 0.,
+    };
+  }
+
+  let mut bounds = CorpusBounds {
+    min_x: f32::INFINITY,
+    max_x: f32::NEG_INFINITY,
+    min_y: f32::INFINITY,
+    max_y: f32::NEG_INFINITY,
+  };
+  for &[x, y] in positions {
+    bounds.min_x = bounds.min_x.min(x);
+    bounds.max_x = bounds.max_x.max(x);
+    bounds.min_y = bounds.min_y.min(y);
+    bounds.max_y = bounds.max_y.max(y);
+  }
+  bounds
+}
+
+fn quantile_range(mut vals: Vec<f32>) -> (f32, f32) {
+  if vals.is_empty() {
+    return (0., 0.);
+  }
+
+  vals.sort_unstable_by(f32::total_cmp);
+  let last_ix = vals.len() - 1;
+  let lo_ix = (last_ix as f32 * DEFAULT_VIEW_OUTLIER_QUANTILE).round() as usize;
+  let hi_ix = (last_ix as f32 * (1. - DEFAULT_VIEW_OUTLIER_QUANTILE)).round() as usize;
+  (vals[lo_ix], vals[hi_ix])
+}
+
+pub(crate) fn compute_default_view(positions: &[[f32; 2]]) -> DefaultView {
+  let (lo_x, hi_x) = quantile_range(positions.iter().map(|p| p[0]).collect());
+  let (lo_y, hi_y) = quantile_range(positions.iter().map(|p| p[1]).collect());
+  DefaultView {
+    center: [(lo_x + hi_x) / 2., (lo_y + hi_y) / 2.],
+    span_x: (hi_x - lo_x) * DEFAULT_VIEW_PADDING,
+    span_y: (hi_y - lo_y) * DEFAULT_VIEW_PADDING,
+  }
+}
+
+/// Writes `corpus`, `corpus.br`, `corpus.zst`, `sidecars`, and `manifest.json` into `out_dir`.
+pub(crate) async fn write_corpus_artifacts(
+  corpus: &BuiltCorpus,
+  sidecars: &[Sidecar],
+  out_dir: &Path,
+) {
+  let (brotli_data, zstd_data) =
+    tokio::task::block_in_place(|| (compress_brotli(&corpus.data), compress_zstd(&corpus.data)));
+
+  let content_sha256 = sha256_hex(&corpus.data);
+  let variants: [(&'static str, &str, &[u8]); 3] = [
+    ("identity", "corpus", &corpus.data),
+    ("br", "corpus.br", &brotli_data),
+    ("zstd", "corpus.zst", &zstd_data),
+  ];
+
+  let mut artifacts = Vec::with_capacity(variants.len());
+  for (encoding, file_name, data) in variants {
+    tokio::fs::write(out_dir.join(file_name), data)
+      .await
+      .unwrap_or_else(|err| panic!("Failed to write {file_name}: {err}"));
+    info!("Wrote {file_name} ({} bytes)", data.len());
+
+    artifacts.push(CorpusArtifact {
+      encoding: encoding.to_owned(),
+      file_name: file_name.to_owned(),
+      size: data.len(),
+      sha256: sha256_hex(data),
+    });
+  }
+
+  let mut sidecar_artifacts = Vec::with_capacity(sidecars.len());
+  for Sidecar { file_name, data } in sidecars {
+    tokio::fs::write(out_dir.join(file_name), data)
+      .await
+      .unwrap_or_else(|err| panic!("Failed to write {file_name}: {err}"));
+    info!("Wrote {file_name} ({} bytes)", data.len());
+
+    sidecar_artifacts.push(CorpusArtifact {
+      encoding: "identity".to_owned(),
+      file_name: (*file_name).to_owned(),
+      size: data.len(),
+      sha256: sha256_hex(data),
+    });
+  }
+
+  let positions: Vec<[f32; 2]> = corpus.rows.iter().map(|row| row.position).collect();
+  let manifest = CorpusManifest {
+    format_version: CORPUS_FORMAT_VERSION,
+    row_count: corpus.num_items,
+    built_at: chrono::Utc::now().timestamp(),
+    content_sha256,
+    bounds: compute_bounds(&positions),
+    default_view: compute_default_view(&positions),
+    artifacts,
+    sidecars: sidecar_artifacts,
+  };
+  let manifest_json =
+    serde_json::to_string_pretty(&manifest).expect("Failed to serialize corpus manifest");
+  tokio::fs::write(out_dir.join("manifest.json"), manifest_json)
+    .await
+    .expect("Failed to write corpus manifest");
+
+  info!(
+    "Wrote corpus manifest to {}",
+    out_dir.join("manifest.json").display()
+  );
+}
+
+/// An empty corpus gets finite bounds so that its manifest can be read back
+#[test]
+fn empty_corpus_bounds_roundtrip() {
+  let bounds = compute_bounds(&[]);
+  let json = serde_json::to_string(&bounds).unwrap();
+  let read_back: CorpusBounds = serde_json::from_str(&json).unwrap();
+  assert_eq!(
+    [
+      read_back.min_x,
+      read_back.max_x,
+      read_back.min_y,
+      read_back.max_y
+    ],
+    [0.; 4]
+  );
+
+  let bounds = compute_bounds(&[[1., -2.], [-3., 4.]]);
+  assert_eq!([bounds.min_x, bounds.max_x, bounds.min_y, bounds.max_y], [
+    -3., 1., -2., 4.
+  ]);
+}
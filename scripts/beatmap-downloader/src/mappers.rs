@@ -0,0 +1,131 @@
+//! Aggregates the corpus per mapper so that each mapper's style can be placed on the atlas.
+//!
+//! Written to the `mappers.json` sidecar as an array of [`MapperStats`], most prolific mappers
+//! first.  Mappers are identified by their `creator` name since that's all the corpus has.
+
+use foundations::telemetry::log::*;
+use fxhash::{FxHashMap, FxHashSet};
+use serde::Serialize;
+
+use crate::{build_corpus::CorpusRow, clusters::mean_aim_speed_ratio};
+
+#[derive(Debug, Serialize)]
+pub(crate) struct MapperStats {
+  pub creator: String,
+  /// Number of distinct beatmaps, not counting mod variants separately
+  pub map_count: usize,
+  pub row_count: usize,
+  /// Mean position of the mapper's rows in the embedding
+  pub centroid: [f32; 2],
+  /// Root mean square distance of the mapper's rows from their centroid
+  pub spread: f32,
+  pub mean_stars: f64,
+  pub mean_aim_speed_ratio: f64,
+  pub first_release_year: Option<u16>,
+  pub last_release_year: Option<u16>,
+  pub total_users: i64,
+}
+
+fn summarize_mapper(creator: &str, rows: &[&CorpusRow]) -> MapperStats {
+  let n = rows.len() as f64;
+  let centroid = [0, 1].map(|axis| {
+    (rows
+      .iter()
+      .map(|row| row.position[axis] as f64)
+      .sum::<f64>()
+      / n) as f32
+  });
+  let mean_sq_distance = rows
+    .iter()
+    .map(|row| {
+      let (dx, dy) = (
+        (row.position[0] - centroid[0]) as f64,
+        (row.position[1] - centroid[1]) as f64,
+      );
+      dx * dx + dy * dy
+    })
+    .sum::<f64>()
+    / n;
+  let beatmap_ids: FxHashSet<i32> = rows.iter().map(|row| row.beatmap_id).collect();
+  let years = rows.iter().filter_map(|row| row.release_year);
+
+  MapperStats {
+    creator: creator.to_owned(),
+    map_count: beatmap_ids.len(),
+    row_count: rows.len(),
+    centroid,
+    spread: mean_sq_distance.sqrt() as f32,
+    mean_stars: rows.iter().map(|row| row.stars).sum::<f64>() / n,
+    mean_aim_speed_ratio: mean_aim_speed_ratio(rows),
+    first_release_year: years.clone().min(),
+    last_release_year: years.max(),
+    total_users: rows.iter().map(|row| row.num_users as i64).sum(),
+  }
+}
+
+pub(crate) fn aggregate_mappers(rows: &[CorpusRow]) -> Vec<MapperStats> {
+  let mut rows_by_creator: FxHashMap<&str, Vec<&CorpusRow>> = FxHashMap::default();
+  for row in rows {
+    rows_by_creator
+      .entry(row.creator.as_str())
+      .or_default()
+      .push(row);
+  }
+
+  let mut mappers: Vec<MapperStats> = rows_by_creator
+    .into_iter()
+    .map(|(creator, rows)| summarize_mapper(creator, &rows))
+    .collect();
+  mappers.sort_unstable_by(|a, b| {
+    b.row_count
+      .cmp(&a.row_count)
+      .then_with(|| a.creator.cmp(&b.creator))
+  });
+  info!(
+    "Aggregated {} rows into {} mappers",
+    rows.len(),
+    mappers.len()
+  );
+  mappers
+}
+
+/// Two mappers with a couple of maps each, one of which has a DT variant
+#[test]
+fn mappers_aggregate_rows_by_creator() {
+  let row = |beatmap_id: i32,
+             mods_bits: u32,
+             creator: &str,
+             position: [f32; 2],
+             release_year: u16,
+             num_users: i32| CorpusRow {
+    position,
+    num_users,
+    stars: beatmap_id as f64,
+    aim_difficulty: 3.,
+    creator: creator.to_owned(),
+    release_year: Some(release_year),
+    ..CorpusRow::test_row(beatmap_id, mods_bits)
+  };
+  let rows = vec![
+    row(1, 0, "Sotarks", [0., 0.], 2017, 10),
+    row(2, 0, "Mapper", [10., 10.], 2010, 5),
+    row(1, 64, "Sotarks", [4., 0.], 2017, 20),
+    row(3, 0, "Sotarks", [2., 3.], 2019, 30),
+  ];
+
+  let mappers = aggregate_mappers(&rows);
+  assert_eq!(mappers.len(), 2);
+  let sotarks = &mappers[0];
+  assert_eq!(sotarks.creator, "Sotarks");
+  assert_eq!((sotarks.map_count, sotarks.row_count), (2, 3));
+  assert_eq!(sotarks.centroid, [2., 1.]);
+  assert!((sotarks.spread - (14f32 / 3.).sqrt()).abs() < 1e-5);
+  assert!((sotarks.mean_stars - 5. / 3.).abs() < 1e-9);
+  assert_eq!(sotarks.mean_aim_speed_ratio, 1.5);
+  assert_eq!(
+    (sotarks.first_release_year, sotarks.last_release_year),
+    (Some(2017), Some(2019))
+  );
+  assert_eq!(sotarks.total_users, 60);
+  assert_eq!(mappers[1].spread, 0.);
+}
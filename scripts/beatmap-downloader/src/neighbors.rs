@@ -0,0 +1,249 @@
+//! Precomputes the nearest neighbors of every corpus row so that the frontend can list similar maps
+//! for the selected beatmap without searching the embedding itself.
+//!
+//! The neighbors sidecar is a binary file which starts with a header:
+//!
+//! [u8; 4] magic bytes `OSUN`
+//! [u32] format version
+//! [u32] number of rows; matches the corpus that it was built alongside
+//! [u32] k, the number of neighbors stored for each row
+//! [u32] number of neighbor tables; 1 if only embedding neighbors are present, 2 if co-occurrence
+//! neighbors are present as well
+//!
+//! The header is followed by the embedding neighbors table and then the optional co-occurrence
+//! neighbors table.  Each table contains (number of rows) * k [u32] corpus row indices, with the k
+//! neighbors of row `i` stored starting at index `i * k`, closest first.  Rows with fewer than k
+//! neighbors are padded with [`NO_NEIGHBOR`].
+
+use std::{collections::BinaryHeap, path::Path};
+
+use foundations::telemetry::log::*;
+use fxhash::FxHashMap;
+
+use crate::{
+  build_corpus::CorpusRow,
+  cooccurrence::{read_cooccurrence_graph, CooccurrenceGraph},
+};
+
+pub(crate) const NEIGHBORS_MAGIC: [u8; 4] = *b"OSUN";
+pub(crate) const NEIGHBORS_FORMAT_VERSION: u32 = 1;
+pub(crate) const NO_NEIGHBOR: u32 = u32::MAX;
+
+fn sq_dist(a: [f32; 2], b: [f32; 2]) -> f32 {
+  let (dx, dy) = (a[0] - b[0], a[1] - b[1]);
+  dx * dx + dy * dy
+}
+
+/// Finds the `k` closest points to each point in 2D embedding space.
+///
+/// Points are sorted by x coordinate and, for each point, we scan outwards in both directions
+/// until the x distance alone exceeds the distance to the k-th best candidate found so far.
+pub(crate) fn embedding_neighbors(positions: &[[f32; 2]], k: usize) -> Vec<Vec<u32>> {
+  if k == 0 {
+    return vec![Vec::new(); positions.len()];
+  }
+  let mut order: Vec<u32> = (0..positions.len() as u32).collect();
+  order.sort_unstable_by(|&a, &b| positions[a as usize][0].total_cmp(&positions[b as usize][0]));
+  let mut rank_by_ix = vec![0usize; positions.len()];
+  for (rank, &ix) in order.iter().enumerate() {
+    rank_by_ix[ix as usize] = rank;
+  }
+
+  let mut all_neighbors = Vec::with_capacity(positions.len());
+  // Max-heap of `(squared distance bits, index)`.  Squared distances are non-negative, so their
+  // bit patterns sort the same way as the values themselves.
+  let mut heap: BinaryHeap<(u32, u32)> = BinaryHeap::with_capacity(k + 1);
+  for (ix, &pos) in positions.iter().enumerate() {
+    heap.clear();
+    let rank = rank_by_ix[ix];
+
+    let consider = |candidate_ix: u32, heap: &mut BinaryHeap<(u32, u32)>| -> bool {
+      let candidate_pos = positions[candidate_ix as usize];
+      let dx = candidate_pos[0] - pos[0];
+      if heap.len() == k && dx * dx > f32::from_bits(heap.peek().unwrap().0) {
+        return false;
+      }
+      heap.push((sq_dist(pos, candidate_pos).to_bits(), candidate_ix));
+      if heap.len() > k {
+        heap.pop();
+      }
+      true
+    };
+
+    for &candidate_ix in order[rank + 1..].iter() {
+      if !consider(candidate_ix, &mut heap) {
+        break;
+      }
+    }
+    for &candidate_ix in order[..rank].iter().rev() {
+      if !consider(candidate_ix, &mut heap) {
+        break;
+      }
+    }
+
+    let mut neighbors: Vec<(u32, u32)> = heap.drain().collect();
+    neighbors.sort_unstable();
+    all_neighbors.push(neighbors.into_iter().map(|(_, ix)| ix).collect());
+  }
+
+  all_neighbors
+}
+
+/// Finds the `k` rows closest to `pos`, closest first, by checking every row.  This is for one-off
+/// queries; use [`embedding_neighbors`] to find the neighbors of every row.
+pub(crate) fn nearest_rows(
+  rows: &[CorpusRow],
+  pos: [f32; 2],
+  k: usize,
+  exclude_ix: Option<usize>,
+) -> Vec<usize> {
+  let mut by_dist: Vec<(f32, usize)> = rows
+    .iter()
+    .enumerate()
+    .filter(|&(ix, _)| Some(ix) != exclude_ix)
+    .map(|(ix, row)| (sq_dist(pos, row.position), ix))
+    .collect();
+  let k = k.min(by_dist.len());
+  if k == 0 {
+    return Vec::new();
+  }
+  by_dist.select_nth_unstable_by(k - 1, |a, b| a.0.total_cmp(&b.0));
+  by_dist.truncate(k);
+  by_dist.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+  by_dist.into_iter().map(|(_, ix)| ix).collect()
+}
+
+/// Finds the `k` rows which co-occur most strongly with each row in users' top plays.  Rows which
+/// aren't present in the graph get no neighbors.
+pub(crate) fn cooccurrence_neighbors(
+  rows: &[CorpusRow],
+  graph: &CooccurrenceGraph,
+  k: usize,
+) -> Vec<Vec<u32>> {
+  let row_ix_by_score_id: FxHashMap<&str, u32> = rows
+    .iter()
+    .enumerate()
+    .map(|(ix, row)| (row.score_id.as_str(), ix as u32))
+    .collect();
+  let graph_ix_by_score_id: FxHashMap<&str, usize> = graph
+    .score_ids
+    .iter()
+    .enumerate()
+    .map(|(ix, score_id)| (score_id.as_str(), ix))
+    .collect();
+
+  rows
+    .iter()
+    .enumerate()
+    .map(|(row_ix, row)| {
+      let Some(&graph_ix) = graph_ix_by_score_id.get(row.score_id.as_str()) else {
+        return Vec::new();
+      };
+
+      let mut candidates: Vec<(f32, u32)> = graph.adjacency[graph_ix]
+        .iter()
+        .filter_map(|&(neighbor_graph_ix, weight)| {
+          let neighbor_score_id = graph.score_ids[neighbor_graph_ix as usize].as_str();
+          row_ix_by_score_id
+            .get(neighbor_score_id)
+            .filter(|&&neighbor_row_ix| neighbor_row_ix != row_ix as u32)
+            .map(|&neighbor_row_ix| (weight, neighbor_row_ix))
+        })
+        .collect();
+      candidates.sort_unstable_by(|a, b| b.0.total_cmp(&a.0));
+      candidates.truncate(k);
+      candidates.into_iter().map(|(_, ix)| ix).collect()
+    })
+    .collect()
+}
+
+fn write_table(buf: &mut Vec<u8>, neighbors: &[Vec<u32>], k: usize) {
+  for row_neighbors in neighbors {
+    for i in 0..k {
+      let ix = row_neighbors.get(i).copied().unwrap_or(NO_NEIGHBOR);
+      buf.extend_from_slice(&ix.to_le_bytes());
+    }
+  }
+}
+
+pub(crate) fn encode_neighbors(
+  embedding_neighbors: &[Vec<u32>],
+  cooccurrence_neighbors: Option<&[Vec<u32>]>,
+  k: usize,
+) -> Vec<u8> {
+  let num_tables = 1 + cooccurrence_neighbors.is_some() as u32;
+  let mut buf = Vec::with_capacity(20 + embedding_neighbors.len() * k * 4 * num_tables as usize);
+  buf.extend_from_slice(&NEIGHBORS_MAGIC);
+  buf.extend_from_slice(&NEIGHBORS_FORMAT_VERSION.to_le_bytes());
+  buf.extend_from_slice(&(embedding_neighbors.len() as u32).to_le_bytes());
+  buf.extend_from_slice(&(k as u32).to_le_bytes());
+  buf.extend_from_slice(&num_tables.to_le_bytes());
+  write_table(&mut buf, embedding_neighbors, k);
+  if let Some(cooccurrence_neighbors) = cooccurrence_neighbors {
+    write_table(&mut buf, cooccurrence_neighbors, k);
+  }
+  buf
+}
+
+/// Builds the neighbors sidecar for `rows`, which must be in the same order as the rows of the
+/// corpus.  Co-occurrence neighbors are included if a co-occurrence graph is provided.
+pub(crate) fn build_neighbors(
+  rows: &[CorpusRow],
+  k: usize,
+  cooccurrence_path: Option<&Path>,
+) -> Result<Vec<u8>, String> {
+  let positions: Vec<[f32; 2]> = rows.iter().map(|row| row.position).collect();
+  let embedding_neighbors = embedding_neighbors(&positions, k);
+
+  let cooccurrence_neighbors = match cooccurrence_path {
+    Some(path) => {
+      let graph = read_cooccurrence_graph(path)?;
+      let neighbors = cooccurrence_neighbors(rows, &graph, k);
+      let missing_count = neighbors.iter().filter(|n| n.is_empty()).count();
+      if missing_count > 0 {
+        warn!("{missing_count} corpus rows have no co-occurrence neighbors");
+      }
+      Some(neighbors)
+    },
+    None => None,
+  };
+
+  info!("Computed {k} nearest neighbors for {} rows", rows.len());
+  Ok(encode_neighbors(
+    &embedding_neighbors,
+    cooccurrence_neighbors.as_deref(),
+    k,
+  ))
+}
+
+/// Compare the sweep against brute force on a pseudo-random point cloud
+#[test]
+fn embedding_neighbors_match_brute_force() {
+  let mut state = 12345u32;
+  let mut rand = || {
+    state ^= state << 13;
+    state ^= state >> 17;
+    state ^= state << 5;
+    (state % 10_000) as f32 / 100.
+  };
+  let positions: Vec<[f32; 2]> = (0..500).map(|_| [rand(), rand()]).collect();
+  let k = 7;
+
+  let neighbors = embedding_neighbors(&positions, k);
+  for (ix, &pos) in positions.iter().enumerate() {
+    let mut brute: Vec<(f32, usize)> = positions
+      .iter()
+      .enumerate()
+      .filter(|&(other_ix, _)| other_ix != ix)
+      .map(|(other_ix, &other)| (sq_dist(pos, other), other_ix))
+      .collect();
+    brute.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let expected: Vec<f32> = brute[..k].iter().map(|&(d, _)| d).collect();
+    let actual: Vec<f32> = neighbors[ix]
+      .iter()
+      .map(|&n| sq_dist(pos, positions[n as usize]))
+      .collect();
+    assert_eq!(expected, actual);
+  }
+  assert!(embedding_neighbors(&positions, 0).iter().all(Vec::is_empty));
+}
@@ -0,0 +1,201 @@
+//! Metadata which is only available from the `.osu` files themselves rather than the beatmaps
+//! parquet, like the tags that mappers add to their maps and the original, non-romanized title and
+//! artist.
+//!
+//! The `.osu` files are stored gzipped in the `fetched_beatmaps` table.  `dump-difficulties` also
+//! writes the metadata parsed from them to a CSV file so that corpora can be built offline.
+
+use std::{io::Read, path::Path};
+
+use foundations::telemetry::log::*;
+use fxhash::FxHashMap;
+
+/// Number of `.osu` files loaded from the database at a time, which keeps memory use bounded since
+/// there's one for every beatmap in the corpus
+const LOAD_BATCH_SIZE: i64 = 2000;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct OsuFileMetadata {
+  /// Space-separated search terms set by the mapper
+  pub tags: String,
+  /// Title in its original script, like `ブルーバード`, if set
+  pub title_unicode: Option<String>,
+  pub artist_unicode: Option<String>,
+}
+
+/// Parses the `[Metadata]` section of a `.osu` file.  Anything missing is left empty.
+pub(crate) fn parse_osu_metadata(raw_beatmap: &[u8]) -> OsuFileMetadata {
+  let mut metadata = OsuFileMetadata::default();
+  let text = String::from_utf8_lossy(raw_beatmap);
+  let mut in_metadata_section = false;
+  for line in text.lines() {
+    let line = line.trim();
+    if line.starts_with('[') {
+      if in_metadata_section {
+        break;
+      }
+      in_metadata_section = line == "[Metadata]";
+      continue;
+    }
+    if !in_metadata_section {
+      continue;
+    }
+
+    let Some((key, value)) = line.split_once(':') else {
+      continue;
+    };
+    let value = value.trim();
+    let non_empty = || Some(value.to_owned()).filter(|value| !value.is_empty());
+    match key.trim() {
+      "Tags" => metadata.tags = value.to_owned(),
+      "TitleUnicode" => metadata.title_unicode = non_empty(),
+      "ArtistUnicode" => metadata.artist_unicode = non_empty(),
+      _ => (),
+    }
+  }
+  metadata
+}
+
+/// Parses the metadata out of every `.osu` file stored in the database
+pub(crate) async fn load_osu_metadata() -> Result<FxHashMap<i32, OsuFileMetadata>, String> {
+  let pool = crate::DB_POOL.get().expect("DB pool not initialized");
+  let mut metadata_by_beatmap_id = FxHashMap::default();
+  let mut last_beatmap_id = i32::MIN;
+  loop {
+    let batch: Vec<(i32, Vec<u8>)> = sqlx::query_as(
+      "SELECT beatmap_id, raw_beatmap_gzipped FROM fetched_beatmaps WHERE beatmap_id > ? ORDER BY \
+       beatmap_id LIMIT ?",
+    )
+    .bind(last_beatmap_id)
+    .bind(LOAD_BATCH_SIZE)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| format!("Failed to load beatmaps: {err}"))?;
+    let Some(&(batch_last_beatmap_id, _)) = batch.last() else {
+      break;
+    };
+    last_beatmap_id = batch_last_beatmap_id;
+
+    for (beatmap_id, raw_beatmap_gzipped) in batch {
+      let mut raw_beatmap = Vec::new();
+      if let Err(err) =
+        flate2::read::GzDecoder::new(&raw_beatmap_gzipped[..]).read_to_end(&mut raw_beatmap)
+      {
+        warn!("Failed to decompress beatmap {beatmap_id}: {err}");
+        continue;
+      }
+      metadata_by_beatmap_id.insert(beatmap_id, parse_osu_metadata(&raw_beatmap));
+    }
+  }
+
+  info!(
+    "Loaded metadata from {} .osu files",
+    metadata_by_beatmap_id.len()
+  );
+  Ok(metadata_by_beatmap_id)
+}
+
+pub(crate) fn write_osu_metadata_csv(
+  metadata_by_beatmap_id: &FxHashMap<i32, OsuFileMetadata>,
+  out_path: &Path,
+) -> Result<(), String> {
+  let file_path = out_path.display();
+  let mut wtr = csv::Writer::from_path(out_path)
+    .map_err(|err| format!("Failed to create {file_path}: {err}"))?;
+  let mut beatmap_ids: Vec<i32> = metadata_by_beatmap_id.keys().copied().collect();
+  beatmap_ids.sort_unstable();
+
+  wtr
+    .write_record(["beatmap_id", "tags", "title_unicode", "artist_unicode"])
+    .map_err(|err| format!("Failed to write {file_path}: {err}"))?;
+  for beatmap_id in beatmap_ids {
+    let OsuFileMetadata {
+      tags,
+      title_unicode,
+      artist_unicode,
+    } = &metadata_by_beatmap_id[&beatmap_id];
+    wtr
+      .write_record([
+        beatmap_id.to_string().as_str(),
+        tags,
+        title_unicode.as_deref().unwrap_or(""),
+        artist_unicode.as_deref().unwrap_or(""),
+      ])
+      .map_err(|err| format!("Failed to write {file_path}: {err}"))?;
+  }
+  wtr
+    .flush()
+    .map_err(|err| format!("Failed to write {file_path}: {err}"))
+}
+
+/// Reads metadata written by [`write_osu_metadata_csv`].  Columns are looked up by name, and the
+/// unicode names are left unset for files dumped before they were added.
+pub(crate) fn read_osu_metadata_csv(
+  path: &Path,
+) -> Result<FxHashMap<i32, OsuFileMetadata>, String> {
+  let file_path = path.display();
+  let mut rdr =
+    csv::Reader::from_path(path).map_err(|err| format!("Failed to open {file_path}: {err}"))?;
+  let headers = rdr.headers().map_err(|err| format!("{err}"))?.clone();
+  let col = |name: &str| crate::header_index(&headers, name, path);
+  let beatmap_id_col = col("beatmap_id")?;
+  let tags_col = col("tags")?;
+  let title_unicode_col = col("title_unicode").ok();
+  let artist_unicode_col = col("artist_unicode").ok();
+
+  let mut metadata_by_beatmap_id = FxHashMap::default();
+  for (line_ix, result) in rdr.records().enumerate() {
+    let record = result.map_err(|err| format!("{err}"))?;
+    let beatmap_id = record[beatmap_id_col]
+      .parse::<i32>()
+      .map_err(|_| format!("Invalid `beatmap_id` on row {} of {file_path}", line_ix + 1))?;
+    let optional = |col_ix: Option<usize>| {
+      col_ix
+        .map(|col_ix| &record[col_ix])
+        .filter(|value| !value.is_empty())
+        .map(str::to_owned)
+    };
+    metadata_by_beatmap_id.insert(beatmap_id, OsuFileMetadata {
+      tags: record[tags_col].to_owned(),
+      title_unicode: optional(title_unicode_col),
+      artist_unicode: optional(artist_unicode_col),
+    });
+  }
+  Ok(metadata_by_beatmap_id)
+}
+
+/// Dumps the metadata of every `.osu` file in the database to `out_path`
+pub(crate) async fn dump_osu_metadata(out_path: &Path) -> Result<(), String> {
+  let metadata_by_beatmap_id = load_osu_metadata().await?;
+  write_osu_metadata_csv(&metadata_by_beatmap_id, out_path)?;
+  info!("Dumped .osu metadata to {}", out_path.display());
+  Ok(())
+}
+
+/// Tags and unicode names are read from the `[Metadata]` section only, and survive a round trip
+/// through the CSV dump
+#[test]
+fn osu_metadata_parses_and_roundtrips() {
+  let raw_beatmap = "\u{feff}osu file format v14\r\n\r\n[General]\r\nAudioFilename: \
+                     audio.mp3\r\nTags: not these\r\n\r\n[Metadata]\r\nTitle:Blue \
+                     Bird\r\nTitleUnicode:ブルーバード\r\nArtistUnicode: \
+                     \r\nArtist:Ikimono-gakari\r\nTags:parousia \"BMS\" dive, \
+                     2012\r\nBeatmapID:129891\r\n\r\n[Difficulty]\r\nHPDrainRate:5\r\n";
+  let metadata = parse_osu_metadata(raw_beatmap.as_bytes());
+  assert_eq!(metadata.tags, "parousia \"BMS\" dive, 2012");
+  assert_eq!(metadata.title_unicode.as_deref(), Some("ブルーバード"));
+  assert_eq!(metadata.artist_unicode, None);
+  assert_eq!(
+    parse_osu_metadata(b"[Metadata]\nTitle:x\n"),
+    OsuFileMetadata::default()
+  );
+
+  let mut metadata_by_beatmap_id = FxHashMap::default();
+  metadata_by_beatmap_id.insert(129891, metadata);
+  metadata_by_beatmap_id.insert(75, OsuFileMetadata::default());
+  let path = std::env::temp_dir().join(format!("osu-metadata-test-{}.csv", std::process::id()));
+  write_osu_metadata_csv(&metadata_by_beatmap_id, &path).unwrap();
+  let read_back = read_osu_metadata_csv(&path).unwrap();
+  std::fs::remove_file(&path).unwrap();
+  assert_eq!(read_back, metadata_by_beatmap_id);
+}
@@ -0,0 +1,541 @@
+//! Runs the stages that produce a corpus release in dependency order, skipping the ones whose
+//! inputs haven't changed since they last succeeded.
+//!
+//! A stage's fingerprint is a hash of the contents of its input files and of the fingerprints of
+//! the stages it depends on, so a change to any input reruns everything downstream of it.
+//! Fingerprints are recorded after each stage succeeds, so a failed run picks up where it stopped.
+//! Stages which only change the database, like `download`, have no outputs to check and are
+//! skipped whenever their fingerprint matches; use `--force` to rerun them anyway.
+
+use std::{
+  collections::BTreeMap,
+  path::{Path, PathBuf},
+  time::Instant,
+};
+
+use clap::ValueEnum;
+use foundations::telemetry::log::*;
+use fxhash::FxHashSet;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+  build_corpus::decode_corpus,
+  config::{DataPaths, DatabaseSettings},
+  groups::decode_groups,
+  manifest::{sha256_hex, CorpusManifest},
+  search_index::decode_search_index,
+};
+
+/// Bumped when what a stage does changes in a way that should invalidate previous runs
+const PIPELINE_VERSION: u32 = 2;
+
+/// Fraction of rows which can be missing difficulties before a corpus fails validation
+const MAX_MISSING_DIFFICULTY_FRACTION: f64 = 0.01;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub(crate) enum Stage {
+  ScoreMetadata,
+  Download,
+  ComputeAll,
+  DumpDifficulties,
+  BuildCorpus,
+  Validate,
+}
+
+impl Stage {
+  /// All stages, in an order where each comes after its dependencies
+  const ALL: [Stage; 6] = [
+    Stage::ScoreMetadata,
+    Stage::Download,
+    Stage::ComputeAll,
+    Stage::DumpDifficulties,
+    Stage::BuildCorpus,
+    Stage::Validate,
+  ];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Stage::ScoreMetadata => "score-metadata",
+      Stage::Download => "download",
+      Stage::ComputeAll => "compute-all",
+      Stage::DumpDifficulties => "dump-difficulties",
+      Stage::BuildCorpus => "build-corpus",
+      Stage::Validate => "validate",
+    }
+  }
+
+  fn dependencies(self) -> &'static [Stage] {
+    match self {
+      Stage::ScoreMetadata => &[],
+      Stage::Download => &[Stage::ScoreMetadata],
+      Stage::ComputeAll => &[Stage::Download],
+      Stage::DumpDifficulties => &[Stage::ComputeAll],
+      Stage::BuildCorpus => &[Stage::ScoreMetadata, Stage::DumpDifficulties],
+      Stage::Validate => &[Stage::BuildCorpus],
+    }
+  }
+
+  /// Whether this is `other` or transitively depends on it
+  fn depends_on(self, other: Stage) -> bool {
+    self == other
+      || self
+        .dependencies()
+        .iter()
+        .any(|dependency| dependency.depends_on(other))
+  }
+}
+
+/// What each stage reads, writes, and does.  Kept separate from the scheduling so that it can be
+/// swapped out in tests.
+pub(crate) trait StageRunner {
+  /// Files whose contents determine what the stage produces
+  fn inputs(&self, stage: Stage) -> Vec<PathBuf>;
+  /// Files that the stage writes.  The stage is rerun if any of them are missing.
+  fn outputs(&self, stage: Stage) -> Vec<PathBuf>;
+  async fn run(&mut self, stage: Stage) -> Result<(), String>;
+}
+
+#[derive(Serialize, Deserialize)]
+struct StageRecord {
+  fingerprint: String,
+  /// Unix timestamp in seconds
+  completed_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct PipelineState {
+  stages: BTreeMap<String, StageRecord>,
+}
+
+impl PipelineState {
+  fn read(path: &Path) -> Result<Self, String> {
+    if !path.exists() {
+      return Ok(PipelineState::default());
+    }
+    let state =
+      std::fs::read(path).map_err(|err| format!("Failed to read {}: {err}", path.display()))?;
+    serde_json::from_slice(&state)
+      .map_err(|err| format!("Failed to parse {}: {err}", path.display()))
+  }
+
+  fn write(&self, path: &Path) -> Result<(), String> {
+    let state = serde_json::to_string_pretty(self).expect("Failed to serialize pipeline state");
+    std::fs::write(path, state).map_err(|err| format!("Failed to write {}: {err}", path.display()))
+  }
+
+  fn fingerprint(&self, stage: Stage) -> &str {
+    self
+      .stages
+      .get(stage.name())
+      .map_or("", |record| record.fingerprint.as_str())
+  }
+}
+
+fn fingerprint(stage: Stage, inputs: &[PathBuf], state: &PipelineState) -> Result<String, String> {
+  let mut hasher = Sha256::new();
+  hasher.update(PIPELINE_VERSION.to_le_bytes());
+  hasher.update(stage.name());
+  for &dependency in stage.dependencies() {
+    hasher.update([0]);
+    hasher.update(state.fingerprint(dependency));
+  }
+  for input in inputs {
+    let mut file = std::fs::File::open(input).map_err(|err| {
+      format!(
+        "Failed to open {}, which is an input to `{}`: {err}",
+        input.display(),
+        stage.name()
+      )
+    })?;
+    hasher.update([0]);
+    std::io::copy(&mut file, &mut hasher)
+      .map_err(|err| format!("Failed to read {}: {err}", input.display()))?;
+  }
+  Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Stages which are downstream of `from` and upstream of `until`, in dependency order
+fn selected_stages(from: Option<Stage>, until: Option<Stage>) -> Vec<Stage> {
+  Stage::ALL
+    .into_iter()
+    .filter(|&stage| {
+      from.is_none_or(|from| stage.depends_on(from))
+        && until.is_none_or(|until| until.depends_on(stage))
+    })
+    .collect()
+}
+
+fn render_summary(outcomes: &[(Stage, String)]) -> String {
+  outcomes
+    .iter()
+    .map(|(stage, outcome)| format!("  {:<18} {outcome}", stage.name()))
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+pub(crate) struct PipelineOptions {
+  /// Only run this stage and the ones that depend on it
+  pub from: Option<Stage>,
+  /// Only run this stage and the ones it depends on
+  pub until: Option<Stage>,
+  /// Rerun stages even if their inputs are unchanged
+  pub force: bool,
+}
+
+/// Runs the selected stages, recording their fingerprints in the state file at `state_path`.  On
+/// failure, the error includes a summary of which stages ran.
+pub(crate) async fn run_pipeline(
+  runner: &mut impl StageRunner,
+  state_path: &Path,
+  options: &PipelineOptions,
+) -> Result<(), String> {
+  let stages = selected_stages(options.from, options.until);
+  if stages.is_empty() {
+    return Err(format!(
+      "No stages to run; `{}` doesn't depend on `{}`",
+      options.until.map_or("", Stage::name),
+      options.from.map_or("", Stage::name)
+    ));
+  }
+
+  let mut state = PipelineState::read(state_path)?;
+  let mut outcomes: Vec<(Stage, String)> = Vec::with_capacity(stages.len());
+  for stage in stages {
+    let fail = |outcomes: &mut Vec<(Stage, String)>, err: String| {
+      outcomes.push((stage, "failed".to_owned()));
+      format!(
+        "Pipeline stopped at `{}`:\n{err}\n\n{}",
+        stage.name(),
+        render_summary(outcomes)
+      )
+    };
+
+    let fingerprint = match fingerprint(stage, &runner.inputs(stage), &state) {
+      Ok(fingerprint) => fingerprint,
+      Err(err) => return Err(fail(&mut outcomes, err)),
+    };
+    let unchanged = !options.force
+      && state.fingerprint(stage) == fingerprint
+      && runner.outputs(stage).iter().all(|output| output.exists());
+    if unchanged {
+      info!("Skipping `{}`; its inputs are unchanged", stage.name());
+      outcomes.push((stage, "skipped (unchanged)".to_owned()));
+      continue;
+    }
+
+    info!("Running `{}`", stage.name());
+    let start = Instant::now();
+    if let Err(err) = runner.run(stage).await {
+      return Err(fail(&mut outcomes, err));
+    }
+    outcomes.push((
+      stage,
+      format!("ran in {:.1}s", start.elapsed().as_secs_f64()),
+    ));
+    state.stages.insert(stage.name().to_owned(), StageRecord {
+      fingerprint,
+      completed_at: chrono::Utc::now().timestamp(),
+    });
+    state.write(state_path)?;
+  }
+
+  info!("Pipeline finished:\n{}", render_summary(&outcomes));
+  Ok(())
+}
+
+/// Checks that the corpus in `corpus_dir` is complete and consistent with its manifest, returning
+/// a description of each problem found
+pub(crate) fn validate_corpus_dir(corpus_dir: &Path) -> Vec<String> {
+  let corpus_path = corpus_dir.join("corpus");
+  let corpus_data = match std::fs::read(&corpus_path) {
+    Ok(data) => data,
+    Err(err) => return vec![format!("Failed to read {}: {err}", corpus_path.display())],
+  };
+  let rows = match decode_corpus(&corpus_data) {
+    Ok(rows) => rows,
+    Err(err) => return vec![format!("Corpus doesn't decode: {err}")],
+  };
+
+  let mut problems = Vec::new();
+  if rows.is_empty() {
+    problems.push("Corpus has no rows".to_owned());
+  }
+  let non_finite_count = rows
+    .iter()
+    .filter(|row| !row.position.iter().all(|v| v.is_finite()))
+    .count();
+  if non_finite_count > 0 {
+    problems.push(format!("{non_finite_count} rows have non-finite positions"));
+  }
+  let missing_difficulty_count = rows.iter().filter(|row| row.stars <= 0.).count();
+  if missing_difficulty_count as f64 > rows.len() as f64 * MAX_MISSING_DIFFICULTY_FRACTION {
+    problems.push(format!(
+      "{missing_difficulty_count} of {} rows are missing difficulties; run `compute-all` and \
+       `dump-difficulties`",
+      rows.len()
+    ));
+  }
+  let mut seen_score_ids = FxHashSet::default();
+  let duplicate_count = rows
+    .iter()
+    .filter(|row| !seen_score_ids.insert(row.score_id.as_str()))
+    .count();
+  if duplicate_count > 0 {
+    problems.push(format!("{duplicate_count} rows have duplicate score IDs"));
+  }
+
+  let manifest_path = corpus_dir.join("manifest.json");
+  let manifest = std::fs::read(&manifest_path)
+    .map_err(|err| format!("Failed to read {}: {err}", manifest_path.display()))
+    .and_then(|manifest| {
+      serde_json::from_slice::<CorpusManifest>(&manifest)
+        .map_err(|err| format!("Failed to parse {}: {err}", manifest_path.display()))
+    });
+  match manifest {
+    Ok(manifest) => {
+      if manifest.row_count as usize != rows.len() {
+        problems.push(format!(
+          "Manifest lists {} rows but the corpus has {}",
+          manifest.row_count,
+          rows.len()
+        ));
+      }
+      if manifest.content_sha256 != sha256_hex(&corpus_data) {
+        problems.push("Manifest content hash doesn't match the corpus".to_owned());
+      }
+      for artifact in manifest.artifacts.iter().chain(&manifest.sidecars) {
+        match std::fs::read(corpus_dir.join(&artifact.file_name)) {
+          Ok(data) if data.len() == artifact.size && sha256_hex(&data) == artifact.sha256 => (),
+          Ok(_) => problems.push(format!(
+            "{} doesn't match its manifest entry",
+            artifact.file_name
+          )),
+          Err(err) => problems.push(format!("Failed to read {}: {err}", artifact.file_name)),
+        }
+      }
+    },
+    Err(err) => problems.push(err),
+  }
+
+  for sidecar in [
+    "neighbors",
+    "clusters",
+    "clusters.json",
+    "search_index",
+    "mappers.json",
+    "groups",
+  ] {
+    if !corpus_dir.join(sidecar).exists() {
+      problems.push(format!("The `{sidecar}` sidecar is missing"));
+    }
+  }
+  if let Ok(search_index) = std::fs::read(corpus_dir.join("search_index")) {
+    if let Err(err) = decode_search_index(&search_index, rows.len()) {
+      problems.push(format!("The search index doesn't match the corpus: {err}"));
+    }
+  }
+  if let Ok(groups) = std::fs::read(corpus_dir.join("groups")) {
+    if let Err(err) = decode_groups(&groups, rows.len()) {
+      problems.push(format!("The groups don't match the corpus: {err}"));
+    }
+  }
+  problems
+}
+
+/// Runs the real stages against the configured data paths and database
+pub(crate) struct AtlasStages<'a> {
+  pub paths: &'a DataPaths,
+  pub database: &'a DatabaseSettings,
+}
+
+impl StageRunner for AtlasStages<'_> {
+  fn inputs(&self, stage: Stage) -> Vec<PathBuf> {
+    let paths = self.paths;
+    match stage {
+      Stage::ScoreMetadata => vec![paths.hiscores.clone()],
+      Stage::Download | Stage::ComputeAll => vec![paths.score_metadata.clone()],
+      // Depends on the database, which is covered by the `compute-all` fingerprint
+      Stage::DumpDifficulties => Vec::new(),
+      Stage::BuildCorpus => vec![
+        paths.score_metadata.clone(),
+        paths.difficulties.clone(),
+        paths.osu_metadata.clone(),
+        paths.embedding.clone(),
+        paths.beatmaps.clone(),
+      ],
+      Stage::Validate => vec![paths.corpus(), paths.corpus_dir.join("manifest.json")],
+    }
+  }
+
+  fn outputs(&self, stage: Stage) -> Vec<PathBuf> {
+    let paths = self.paths;
+    match stage {
+      Stage::ScoreMetadata => vec![paths.score_metadata.clone()],
+      Stage::DumpDifficulties => vec![paths.difficulties.clone(), paths.osu_metadata.clone()],
+      Stage::BuildCorpus => [
+        "corpus",
+        "manifest.json",
+        "corpus-index.json",
+        "neighbors",
+        "clusters",
+        "search_index",
+        "mappers.json",
+        "groups",
+      ]
+      .into_iter()
+      .map(|file_name| paths.corpus_dir.join(file_name))
+      .collect(),
+      Stage::Download | Stage::ComputeAll | Stage::Validate => Vec::new(),
+    }
+  }
+
+  async fn run(&mut self, stage: Stage) -> Result<(), String> {
+    let paths = self.paths;
+    match stage {
+      Stage::ScoreMetadata => tokio::task::block_in_place(|| {
+        crate::score_metadata::generate_score_metadata(&paths.hiscores, &paths.score_metadata, None)
+      }),
+      Stage::Download => {
+        let score_metadata = crate::parse_score_metadata(&paths.score_metadata)?;
+        crate::connect_db(self.database).await;
+        crate::download_all_beatmaps(score_metadata).await;
+        Ok(())
+      },
+      Stage::ComputeAll => {
+        let score_metadata = crate::parse_score_metadata(&paths.score_metadata)?;
+        crate::connect_db(self.database).await;
+        crate::compute_all_difficulties(score_metadata).await
+      },
+      Stage::DumpDifficulties => {
+        crate::connect_db(self.database).await;
+        crate::dump_difficulties(&paths.difficulties).await;
+        crate::osu_metadata::dump_osu_metadata(&paths.osu_metadata).await
+      },
+      // Built from the dumped difficulties and `.osu` metadata so that this stage doesn't need the
+      // database
+      Stage::BuildCorpus => {
+        let score_metadata = crate::parse_score_metadata(&paths.score_metadata)?;
+        let difficulties = crate::read_difficulties_csv(&paths.difficulties)?;
+        let osu_metadata = crate::osu_metadata::read_osu_metadata_csv(&paths.osu_metadata)?;
+        let corpus = crate::build_corpus::build_corpus(
+          score_metadata,
+          difficulties,
+          osu_metadata,
+          &paths.embedding,
+          &paths.beatmaps,
+          None,
+          None,
+          false,
+        )
+        .await;
+        let cluster_params = crate::clusters::ClusterParams {
+          min_points: crate::DEFAULT_CLUSTER_MIN_POINTS,
+          eps: None,
+        };
+        crate::write_corpus_dir(
+          &corpus,
+          &paths.corpus_dir,
+          crate::DEFAULT_NEIGHBORS_K,
+          None,
+          &cluster_params,
+        )
+        .await;
+        Ok(())
+      },
+      Stage::Validate => {
+        let problems = tokio::task::block_in_place(|| validate_corpus_dir(&paths.corpus_dir));
+        if problems.is_empty() {
+          return Ok(());
+        }
+        Err(format!(
+          "Corpus validation found {} problem(s):\n{}",
+          problems.len(),
+          problems
+            .iter()
+            .map(|problem| format!("  - {problem}"))
+            .collect::<Vec<_>>()
+            .join("\n")
+        ))
+      },
+    }
+  }
+}
+
+/// Stages are skipped when their inputs are unchanged, reran along with everything downstream
+/// when an input changes, and failures stop the run and are reported with a summary
+#[tokio::test]
+async fn pipeline_skips_unchanged_stages() {
+  struct RecordingStages {
+    embedding_path: PathBuf,
+    ran: Vec<Stage>,
+    fail: Option<Stage>,
+  }
+
+  impl StageRunner for RecordingStages {
+    fn inputs(&self, stage: Stage) -> Vec<PathBuf> {
+      match stage {
+        Stage::BuildCorpus => vec![self.embedding_path.clone()],
+        _ => Vec::new(),
+      }
+    }
+
+    fn outputs(&self, _stage: Stage) -> Vec<PathBuf> { Vec::new() }
+
+    async fn run(&mut self, stage: Stage) -> Result<(), String> {
+      self.ran.push(stage);
+      match self.fail {
+        Some(fail) if fail == stage => Err("2 rows have non-finite positions".to_owned()),
+        _ => Ok(()),
+      }
+    }
+  }
+
+  let dir = std::env::temp_dir().join(format!("atlas-pipeline-test-{}", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+  let state_path = dir.join("pipeline_state.json");
+  let mut runner = RecordingStages {
+    embedding_path: dir.join("embedding.json"),
+    ran: Vec::new(),
+    fail: None,
+  };
+  let all = PipelineOptions {
+    from: None,
+    until: None,
+    force: false,
+  };
+  std::fs::write(&runner.embedding_path, "{}").unwrap();
+
+  run_pipeline(&mut runner, &state_path, &all).await.unwrap();
+  assert_eq!(runner.ran, Stage::ALL);
+
+  runner.ran.clear();
+  run_pipeline(&mut runner, &state_path, &all).await.unwrap();
+  assert!(runner.ran.is_empty());
+
+  std::fs::write(&runner.embedding_path, "{\"1_\": [0, 0]}").unwrap();
+  run_pipeline(&mut runner, &state_path, &all).await.unwrap();
+  assert_eq!(runner.ran, [Stage::BuildCorpus, Stage::Validate]);
+
+  runner.ran.clear();
+  let partial = PipelineOptions {
+    from: Some(Stage::ComputeAll),
+    until: Some(Stage::DumpDifficulties),
+    force: true,
+  };
+  run_pipeline(&mut runner, &state_path, &partial)
+    .await
+    .unwrap();
+  assert_eq!(runner.ran, [Stage::ComputeAll, Stage::DumpDifficulties]);
+
+  runner.ran.clear();
+  runner.fail = Some(Stage::Validate);
+  std::fs::write(&runner.embedding_path, "{}").unwrap();
+  let err = run_pipeline(&mut runner, &state_path, &all)
+    .await
+    .unwrap_err();
+  std::fs::remove_dir_all(&dir).unwrap();
+  assert!(err.contains("Pipeline stopped at `validate`"));
+  assert!(err.contains("non-finite positions"));
+  assert!(err.contains("build-corpus"));
+}
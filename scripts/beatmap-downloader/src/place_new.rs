@@ -0,0 +1,180 @@
+//! Places score IDs which aren't part of an existing embedding, such as newly ranked maps, without
+//! re-running the full embedding.
+//!
+//! Each new score ID is positioned at the weighted mean of the positions of its strongest
+//! co-occurrence neighbors which are already embedded.  These positions are only approximate, so
+//! they're recorded in a placements file which `build-corpus --placements` uses to flag the
+//! corresponding rows as low confidence until the next full re-embedding.
+
+use std::path::Path;
+
+use foundations::telemetry::log::*;
+use fxhash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
+
+use crate::cooccurrence::{read_cooccurrence_graph, CooccurrenceGraph};
+
+pub(crate) struct PlacementParams {
+  /// Maximum number of embedded neighbors used to position each new score
+  pub max_neighbors: usize,
+  /// New scores with fewer embedded neighbors than this are left out
+  pub min_neighbors: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Placement {
+  pub score_id: String,
+  pub position: [f32; 2],
+  pub neighbor_count: usize,
+  pub total_weight: f32,
+  /// Weighted RMS distance from the placed position to the neighbors it was placed from.  Larger
+  /// values mean that the neighbors are spread across different regions of the embedding.
+  pub spread: f32,
+}
+
+/// Computes placements for every score in `graph` which isn't in `embedding`.  Returns the
+/// placements along with the number of new scores that couldn't be placed.
+pub(crate) fn place_new_scores(
+  embedding: &FxHashMap<String, [f32; 2]>,
+  graph: &CooccurrenceGraph,
+  params: &PlacementParams,
+) -> (Vec<Placement>, usize) {
+  let mut placements = Vec::new();
+  let mut unplaced_count = 0usize;
+
+  for (graph_ix, score_id) in graph.score_ids.iter().enumerate() {
+    if embedding.contains_key(score_id) {
+      continue;
+    }
+
+    let mut neighbors: Vec<([f32; 2], f32)> = graph.adjacency[graph_ix]
+      .iter()
+      .filter(|&&(_, weight)| weight > 0.)
+      .filter_map(|&(neighbor_ix, weight)| {
+        embedding
+          .get(&graph.score_ids[neighbor_ix as usize])
+          .map(|&pos| (pos, weight))
+      })
+      .collect();
+    neighbors.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+    neighbors.truncate(params.max_neighbors);
+    if neighbors.is_empty() || neighbors.len() < params.min_neighbors {
+      unplaced_count += 1;
+      continue;
+    }
+
+    let total_weight: f32 = neighbors.iter().map(|&(_, weight)| weight).sum();
+    let mut position = [0f32; 2];
+    for &(pos, weight) in &neighbors {
+      position[0] += pos[0] * weight / total_weight;
+      position[1] += pos[1] * weight / total_weight;
+    }
+    let spread = (neighbors
+      .iter()
+      .map(|&(pos, weight)| {
+        let (dx, dy) = (pos[0] - position[0], pos[1] - position[1]);
+        (dx * dx + dy * dy) * weight
+      })
+      .sum::<f32>()
+      / total_weight)
+      .sqrt();
+
+    placements.push(Placement {
+      score_id: score_id.clone(),
+      position,
+      neighbor_count: neighbors.len(),
+      total_weight,
+      spread,
+    });
+  }
+
+  (placements, unplaced_count)
+}
+
+pub(crate) fn read_placed_score_ids(path: &Path) -> Result<FxHashSet<String>, String> {
+  let data =
+    std::fs::read(path).map_err(|err| format!("Failed to read {}: {err}", path.display()))?;
+  let placements: Vec<Placement> = serde_json::from_slice(&data)
+    .map_err(|err| format!("Failed to parse {}: {err}", path.display()))?;
+  Ok(placements.into_iter().map(|p| p.score_id).collect())
+}
+
+/// Places new scores from the co-occurrence graph at `cooccurrence_path` into the embedding at
+/// `embedding_path`.  Writes the embedding with the placed scores added to `embedding_out_path`,
+/// in the same format as the input, and the placement details to `placements_out_path`.
+pub(crate) fn place_new(
+  embedding_path: &Path,
+  cooccurrence_path: &Path,
+  embedding_out_path: &Path,
+  placements_out_path: &Path,
+  params: &PlacementParams,
+) -> Result<(), String> {
+  let embedding_data = std::fs::read(embedding_path)
+    .map_err(|err| format!("Failed to read {}: {err}", embedding_path.display()))?;
+  // Embedding is in format score_id -> [x, y]
+  let mut embedding: FxHashMap<String, [f32; 2]> = serde_json::from_slice(&embedding_data)
+    .map_err(|err| format!("Failed to parse {}: {err}", embedding_path.display()))?;
+  let graph = read_cooccurrence_graph(cooccurrence_path)?;
+
+  let (placements, unplaced_count) = place_new_scores(&embedding, &graph, params);
+  info!(
+    "Placed {} new scores into an embedding of {}",
+    placements.len(),
+    embedding.len()
+  );
+  if unplaced_count > 0 {
+    warn!(
+      "{unplaced_count} new scores have fewer than {} embedded neighbors and weren't placed",
+      params.min_neighbors
+    );
+  }
+
+  for placement in &placements {
+    embedding.insert(placement.score_id.clone(), placement.position);
+  }
+
+  let write_json = |path: &Path, json: Result<String, serde_json::Error>| {
+    let json = json.map_err(|err| format!("{err}"))?;
+    std::fs::write(path, json).map_err(|err| format!("Failed to write {}: {err}", path.display()))
+  };
+  write_json(embedding_out_path, serde_json::to_string(&embedding))?;
+  write_json(placements_out_path, serde_json::to_string(&placements))?;
+  info!(
+    "Wrote updated embedding to {} and placements to {}",
+    embedding_out_path.display(),
+    placements_out_path.display()
+  );
+  Ok(())
+}
+
+/// A new score is placed at the weighted mean of its embedded neighbors, and one whose only
+/// neighbor is another new score is left out
+#[test]
+fn new_scores_placed_at_weighted_mean() {
+  let embedding: FxHashMap<String, [f32; 2]> =
+    [("1_", [0., 0.]), ("2_", [4., 0.]), ("3_", [0., 8.])]
+      .into_iter()
+      .map(|(id, pos)| (id.to_owned(), pos))
+      .collect();
+  let graph = CooccurrenceGraph {
+    score_ids: ["1_", "2_", "3_", "4_", "5_"].map(String::from).to_vec(),
+    adjacency: vec![
+      vec![],
+      vec![],
+      vec![],
+      vec![(0, 2.), (1, 1.), (2, 1.), (4, 10.)],
+      vec![(3, 10.)],
+    ],
+  };
+  let params = PlacementParams {
+    max_neighbors: 20,
+    min_neighbors: 3,
+  };
+
+  let (placements, unplaced_count) = place_new_scores(&embedding, &graph, &params);
+  assert_eq!(unplaced_count, 1);
+  assert_eq!(placements.len(), 1);
+  assert_eq!(placements[0].score_id, "4_");
+  assert_eq!(placements[0].neighbor_count, 3);
+  assert_eq!(placements[0].position, [1., 2.]);
+}
@@ -0,0 +1,199 @@
+//! Computes how many tracked users had each corpus entry in their top plays over time, month by
+//! month.
+//!
+//! osu!track records a play when it first shows up in a user's top plays and again whenever its pp
+//! changes, such as after a pp rework, but there's no record of when a play drops out.  So a play
+//! counts towards every month from when it was set through the last month it was recorded in.
+//!
+//! The popularity sidecar is a binary file which starts with a header:
+//!
+//! [u8; 4] magic bytes `OSUP`
+//! [u32] format version
+//! [u32] number of rows; matches the corpus that it was built alongside
+//! [u32] number of months in each series
+//! [u16] year of the first month
+//! [u16] first month, 1-12
+//!
+//! This is followed by (number of rows) * (number of months) [u16] user counts, with the series
+//! for row `i` stored starting at index `i * number of months`.  Counts saturate at `u16::MAX`.
+
+use std::path::Path;
+
+use chrono::{DateTime, Datelike};
+use foundations::telemetry::log::*;
+use fxhash::FxHashMap;
+
+use crate::{
+  build_corpus::{decode_corpus, CorpusRow},
+  hiscores::{read_hiscores, score_key, Hiscore},
+};
+
+pub(crate) const POPULARITY_MAGIC: [u8; 4] = *b"OSUP";
+pub(crate) const POPULARITY_FORMAT_VERSION: u32 = 1;
+
+/// Months since year 0, so that consecutive months are consecutive integers
+fn month_index(unix_secs: i64) -> i32 {
+  let dt = DateTime::from_timestamp(unix_secs, 0).unwrap_or_default();
+  dt.year() * 12 + dt.month0() as i32
+}
+
+pub(crate) struct PopularitySeries {
+  /// Month index of the first entry in each series
+  pub first_month: i32,
+  pub num_months: usize,
+  /// One series per corpus row, in corpus row order
+  pub counts: Vec<Vec<u16>>,
+}
+
+pub(crate) fn compute_popularity(rows: &[CorpusRow], mut plays: Vec<Hiscore>) -> PopularitySeries {
+  let row_ix_by_key: FxHashMap<u64, usize> = rows
+    .iter()
+    .enumerate()
+    .map(|(ix, row)| (score_key(row.beatmap_id, row.mods_bits), ix))
+    .collect();
+
+  // `(row index, first month, last month)` for each user's play on each corpus entry
+  plays.sort_unstable_by_key(|play| (play.user, play.score_key()));
+  let mut intervals: Vec<(usize, i32, i32)> = Vec::new();
+  for user_score_plays in plays.chunk_by(|a, b| a.user == b.user && a.score_key() == b.score_key())
+  {
+    let Some(&row_ix) = row_ix_by_key.get(&user_score_plays[0].score_key()) else {
+      continue;
+    };
+    let first_seen = user_score_plays
+      .iter()
+      .map(|play| play.score_time.unwrap_or(play.update_time))
+      .min()
+      .unwrap();
+    let last_seen = user_score_plays
+      .iter()
+      .map(|play| play.update_time)
+      .max()
+      .unwrap();
+    let (first, last) = (month_index(first_seen), month_index(last_seen));
+    intervals.push((row_ix, first.min(last), last.max(first)));
+  }
+
+  let Some(first_month) = intervals.iter().map(|&(_, first, _)| first).min() else {
+    return PopularitySeries {
+      first_month: 0,
+      num_months: 0,
+      counts: vec![Vec::new(); rows.len()],
+    };
+  };
+  let last_month = intervals.iter().map(|&(_, _, last)| last).max().unwrap();
+  let num_months = (last_month - first_month + 1) as usize;
+
+  // Difference arrays, prefix-summed into counts below
+  let mut deltas = vec![vec![0i32; num_months + 1]; rows.len()];
+  for &(row_ix, first, last) in &intervals {
+    deltas[row_ix][(first - first_month) as usize] += 1;
+    deltas[row_ix][(last - first_month) as usize + 1] -= 1;
+  }
+  let counts = deltas
+    .into_iter()
+    .map(|row_deltas| {
+      let mut running = 0i32;
+      row_deltas[..num_months]
+        .iter()
+        .map(|&delta| {
+          running += delta;
+          running.clamp(0, u16::MAX as i32) as u16
+        })
+        .collect()
+    })
+    .collect();
+
+  PopularitySeries {
+    first_month,
+    num_months,
+    counts,
+  }
+}
+
+pub(crate) fn encode_popularity(series: &PopularitySeries) -> Vec<u8> {
+  let mut buf = Vec::with_capacity(20 + series.counts.len() * series.num_months * 2);
+  buf.extend_from_slice(&POPULARITY_MAGIC);
+  buf.extend_from_slice(&POPULARITY_FORMAT_VERSION.to_le_bytes());
+  buf.extend_from_slice(&(series.counts.len() as u32).to_le_bytes());
+  buf.extend_from_slice(&(series.num_months as u32).to_le_bytes());
+  buf.extend_from_slice(&(series.first_month.div_euclid(12) as u16).to_le_bytes());
+  buf.extend_from_slice(&(series.first_month.rem_euclid(12) as u16 + 1).to_le_bytes());
+  for row_counts in &series.counts {
+    for &count in row_counts {
+      buf.extend_from_slice(&count.to_le_bytes());
+    }
+  }
+  buf
+}
+
+/// Computes the popularity series for each row of the corpus at `corpus_path` from the hiscores
+/// at `hiscores_path` and writes the sidecar to `out_path`.
+pub(crate) fn build_popularity(
+  corpus_path: &Path,
+  hiscores_path: &Path,
+  out_path: &Path,
+) -> Result<(), String> {
+  let corpus_data = std::fs::read(corpus_path)
+    .map_err(|err| format!("Failed to read {}: {err}", corpus_path.display()))?;
+  let rows = decode_corpus(&corpus_data)?;
+  let plays = read_hiscores(hiscores_path)?;
+
+  let series = compute_popularity(&rows, plays);
+  let untracked_count = series
+    .counts
+    .iter()
+    .filter(|counts| counts.iter().all(|&count| count == 0))
+    .count();
+  if untracked_count > 0 {
+    warn!("{untracked_count} corpus rows don't have any tracked plays");
+  }
+
+  std::fs::write(out_path, encode_popularity(&series))
+    .map_err(|err| format!("Failed to write {}: {err}", out_path.display()))?;
+  info!(
+    "Wrote {}-month popularity series for {} rows to {}",
+    series.num_months,
+    rows.len(),
+    out_path.display()
+  );
+  Ok(())
+}
+
+/// Each user's play counts from the month it was set through the month it was last seen, and
+/// repeated records of the same play are merged
+#[test]
+fn popularity_counts_users_per_month() {
+  use chrono::{TimeZone, Utc};
+
+  let ts = |year: i32, month: u32| {
+    Utc
+      .with_ymd_and_hms(year, month, 15, 0, 0, 0)
+      .unwrap()
+      .timestamp()
+  };
+  let play = |user: u32, score_time: i64, update_time: i64| Hiscore {
+    user,
+    beatmap_id: 129891,
+    mods_bits: 0,
+    pp: 500.,
+    update_time,
+    score_time: Some(score_time),
+    grade: None,
+    accuracy: None,
+  };
+  let row = CorpusRow {
+    beatmapset_id: 39804,
+    num_users: 2,
+    ..CorpusRow::test_row(129891, 0)
+  };
+  let plays = vec![
+    play(1, ts(2020, 1), ts(2020, 2)),
+    play(1, ts(2020, 1), ts(2020, 3)),
+    play(2, ts(2020, 3), ts(2020, 4)),
+  ];
+
+  let series = compute_popularity(&[row], plays);
+  assert_eq!(series.first_month, 2020 * 12);
+  assert_eq!(series.counts, vec![vec![1, 1, 2, 1]]);
+}
@@ -0,0 +1,640 @@
+//! Publishes built corpora to S3-compatible object storage.
+//!
+//! Each release is uploaded under `{prefix}/corpora/{version}/`, where the version is made of the
+//! build time and the start of the corpus content hash, so published objects are never
+//! overwritten and can be cached forever.  Every object is read back and checked against the
+//! hashes in the manifest before the release is added to `{prefix}/corpus-index.json`.  If
+//! anything fails before then, the objects uploaded so far are deleted and the index is left as it
+//! was.  The index is only written if it hasn't changed since it was read, and the update is
+//! retried against the new index otherwise, so concurrent publishes don't drop each other's
+//! releases.
+//!
+//! The corpus index lists every release along with what the frontend needs to show it, like its
+//! URL and default view, so that adding a release doesn't need a frontend change.  Corpora which
+//! are hosted elsewhere, like the ones from before publishing was built in, can be added to it
+//! with `register-release`.  `build-corpus` also writes a `corpus-index.json` listing just the
+//! corpus that it built next to it, so that local builds can be loaded the same way.
+
+use std::{collections::BTreeMap, path::Path};
+
+use foundations::telemetry::log::*;
+use object_store::{
+  aws::AmazonS3Builder, path::Path as ObjectPath, Attribute, Attributes, ObjectStore, PutMode,
+  PutOptions, PutPayload, UpdateVersion,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  build_corpus::{corpus_format_version, decode_corpus},
+  config::PublishSettings,
+  manifest::{
+    compute_bounds, compute_default_view, sha256_hex, CorpusArtifact, CorpusBounds, CorpusManifest,
+    DefaultView,
+  },
+};
+
+pub(crate) const CORPUS_INDEX_KEY: &str = "corpus-index.json";
+/// Bumped when the structure of the corpus index changes incompatibly
+const CORPUS_INDEX_VERSION: u32 = 1;
+/// Number of characters of the content hash included in version names
+const VERSION_HASH_LENGTH: usize = 12;
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+const INDEX_CACHE_CONTROL: &str = "no-cache";
+/// Number of times that updating the corpus index is attempted when it keeps being changed by
+/// someone else in between reading and writing it
+const MAX_INDEX_UPDATE_ATTEMPTS: usize = 5;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct PublishedObject {
+  /// Encoding of the artifact's contents, as listed in the manifest
+  pub encoding: String,
+  pub key: String,
+  pub size: usize,
+  pub sha256: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct CorpusRelease {
+  /// Identifies the release in the frontend's `?version=` parameter
+  pub id: String,
+  /// URL of the uncompressed corpus, either absolute or relative to the index
+  pub url: String,
+  pub format_version: u32,
+  pub row_count: u32,
+  /// Unix timestamp in seconds
+  pub built_at: i64,
+  /// Unix timestamp in seconds
+  pub published_at: i64,
+  /// SHA-256 of the uncompressed corpus
+  pub content_sha256: String,
+  pub bounds: CorpusBounds,
+  pub default_view: DefaultView,
+  /// Set for releases whose positions are mirrored through the origin compared to the others, so
+  /// that they can be shown the same way around
+  #[serde(default)]
+  pub mirrored: bool,
+  /// Not set for releases which are hosted elsewhere
+  #[serde(default)]
+  pub manifest_key: Option<String>,
+  #[serde(default)]
+  pub artifacts: Vec<PublishedObject>,
+  /// URLs of the sidecars listed in the manifest, like `search_index`, relative to the index and
+  /// keyed by file name
+  #[serde(default)]
+  pub sidecars: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct CorpusIndex {
+  pub index_version: u32,
+  /// ID of the release which is shown by default, which is the most recently published one
+  pub latest: Option<String>,
+  /// Oldest first
+  pub releases: Vec<CorpusRelease>,
+}
+
+impl Default for CorpusIndex {
+  fn default() -> Self {
+    CorpusIndex {
+      index_version: CORPUS_INDEX_VERSION,
+      latest: None,
+      releases: Vec::new(),
+    }
+  }
+}
+
+/// Builds a client for the configured bucket
+pub(crate) fn s3_store(settings: &PublishSettings) -> Result<impl ObjectStore, String> {
+  let mut builder = AmazonS3Builder::from_env();
+  if let Some(bucket) = &settings.bucket {
+    builder = builder.with_bucket_name(bucket);
+  }
+  if let Some(endpoint) = &settings.endpoint {
+    builder = builder
+      .with_endpoint(endpoint)
+      .with_allow_http(endpoint.starts_with("http://"));
+  }
+  if let Some(region) = &settings.region {
+    builder = builder.with_region(region);
+  }
+  builder
+    .build()
+    .map_err(|err| format!("Failed to configure object storage: {err}"))
+}
+
+pub(crate) fn object_key(prefix: &str, key: &str) -> String {
+  match prefix.trim_matches('/') {
+    "" => key.to_owned(),
+    prefix => format!("{prefix}/{key}"),
+  }
+}
+
+/// Uploads `data` to `key` and reads it back to make sure that it arrived intact
+async fn put_verified(
+  store: &impl ObjectStore,
+  key: &str,
+  data: Vec<u8>,
+  content_type: &'static str,
+  cache_control: &'static str,
+) -> Result<(), String> {
+  let sha256 = sha256_hex(&data);
+  let mut attributes = Attributes::new();
+  attributes.insert(Attribute::ContentType, content_type.into());
+  attributes.insert(Attribute::CacheControl, cache_control.into());
+  attributes.insert(Attribute::Metadata("sha256".into()), sha256.clone().into());
+  let location = ObjectPath::from(key);
+  store
+    .put_opts(&location, PutPayload::from(data), PutOptions {
+      attributes,
+      ..Default::default()
+    })
+    .await
+    .map_err(|err| format!("Failed to upload {key}: {err}"))?;
+
+  let read_back = store
+    .get(&location)
+    .await
+    .map_err(|err| format!("Failed to read back {key}: {err}"))?
+    .bytes()
+    .await
+    .map_err(|err| format!("Failed to read back {key}: {err}"))?;
+  if sha256_hex(&read_back) != sha256 {
+    return Err(format!(
+      "{key} doesn't match what was uploaded after reading it back"
+    ));
+  }
+  Ok(())
+}
+
+/// Reads the corpus index along with the version of it that was read, which is `None` if it doesn't
+/// exist yet
+async fn read_corpus_index(
+  store: &impl ObjectStore,
+  key: &str,
+) -> Result<(CorpusIndex, Option<UpdateVersion>), String> {
+  let (index, version): (CorpusIndex, _) = match store.get(&ObjectPath::from(key)).await {
+    Ok(result) => {
+      let version = UpdateVersion {
+        e_tag: result.meta.e_tag.clone(),
+        version: result.meta.version.clone(),
+      };
+      let data = result
+        .bytes()
+        .await
+        .map_err(|err| format!("Failed to read {key}: {err}"))?;
+      let index =
+        serde_json::from_slice(&data).map_err(|err| format!("Failed to parse {key}: {err}"))?;
+      (index, Some(version))
+    },
+    Err(object_store::Error::NotFound { .. }) => return Ok((CorpusIndex::default(), None)),
+    Err(err) => return Err(format!("Failed to read {key}: {err}")),
+  };
+  if index.index_version != CORPUS_INDEX_VERSION {
+    return Err(format!(
+      "{key} has index version {}, but only version {CORPUS_INDEX_VERSION} is supported",
+      index.index_version
+    ));
+  }
+  Ok((index, version))
+}
+
+/// Writes the corpus index if it's still at `version`, or if it still doesn't exist when `version`
+/// is `None`.  Returns `false` without writing anything if it has been changed since.
+async fn write_corpus_index(
+  store: &impl ObjectStore,
+  key: &str,
+  index: &mut CorpusIndex,
+  version: Option<UpdateVersion>,
+) -> Result<bool, String> {
+  index.releases.sort_by_key(|release| release.built_at);
+  let index_json = serde_json::to_vec_pretty(index).expect("Failed to serialize corpus index");
+  let mut attributes = Attributes::new();
+  attributes.insert(Attribute::ContentType, "application/json".into());
+  attributes.insert(Attribute::CacheControl, INDEX_CACHE_CONTROL.into());
+  let mode = match version {
+    Some(version) => PutMode::Update(version),
+    None => PutMode::Create,
+  };
+  match store
+    .put_opts(
+      &ObjectPath::from(key),
+      PutPayload::from(index_json),
+      PutOptions {
+        mode,
+        attributes,
+        ..Default::default()
+      },
+    )
+    .await
+  {
+    Ok(_) => Ok(true),
+    Err(object_store::Error::Precondition { .. } | object_store::Error::AlreadyExists { .. }) =>
+      Ok(false),
+    Err(err) => Err(format!("Failed to write {key}: {err}")),
+  }
+}
+
+/// Applies `update` to the latest corpus index and writes it back, starting over from the new
+/// index if someone else wrote it in the meantime
+async fn update_corpus_index(
+  store: &impl ObjectStore,
+  key: &str,
+  mut update: impl FnMut(&mut CorpusIndex),
+) -> Result<(), String> {
+  for _ in 0..MAX_INDEX_UPDATE_ATTEMPTS {
+    let (mut index, version) = read_corpus_index(store, key).await?;
+    update(&mut index);
+    if write_corpus_index(store, key, &mut index, version).await? {
+      return Ok(());
+    }
+    warn!("{key} was changed while updating it; retrying");
+  }
+  Err(format!(
+    "Gave up updating {key} after {MAX_INDEX_UPDATE_ATTEMPTS} attempts since it kept changing"
+  ))
+}
+
+/// Uploads a file listed in the manifest after checking that it matches its entry, recording its
+/// key in `uploaded` as soon as it's written so that it can be cleaned up on failure
+async fn upload_artifact(
+  store: &impl ObjectStore,
+  corpus_dir: &Path,
+  artifact: &CorpusArtifact,
+  version_prefix: &str,
+  uploaded: &mut Vec<String>,
+) -> Result<PublishedObject, String> {
+  let path = corpus_dir.join(&artifact.file_name);
+  let data =
+    std::fs::read(&path).map_err(|err| format!("Failed to read {}: {err}", path.display()))?;
+  if data.len() != artifact.size || sha256_hex(&data) != artifact.sha256 {
+    return Err(format!(
+      "{} doesn't match its manifest entry; rebuild the corpus before publishing",
+      path.display()
+    ));
+  }
+
+  let key = format!("{version_prefix}/{}", artifact.file_name);
+  let content_type = if artifact.file_name.ends_with(".json") {
+    "application/json"
+  } else {
+    "application/octet-stream"
+  };
+  uploaded.push(key.clone());
+  put_verified(store, &key, data, content_type, IMMUTABLE_CACHE_CONTROL).await?;
+  info!("Uploaded {key} ({} bytes)", artifact.size);
+  Ok(PublishedObject {
+    encoding: artifact.encoding.clone(),
+    key,
+    size: artifact.size,
+    sha256: artifact.sha256.clone(),
+  })
+}
+
+/// Uploads the artifacts and sidecars listed in `manifest` and the manifest itself, returning the
+/// uploaded artifacts
+async fn upload_release(
+  store: &impl ObjectStore,
+  corpus_dir: &Path,
+  manifest: &CorpusManifest,
+  version_prefix: &str,
+  uploaded: &mut Vec<String>,
+) -> Result<Vec<PublishedObject>, String> {
+  let mut artifacts = Vec::with_capacity(manifest.artifacts.len());
+  for artifact in &manifest.artifacts {
+    artifacts.push(upload_artifact(store, corpus_dir, artifact, version_prefix, uploaded).await?);
+  }
+  for sidecar in &manifest.sidecars {
+    upload_artifact(store, corpus_dir, sidecar, version_prefix, uploaded).await?;
+  }
+
+  let manifest_path = corpus_dir.join("manifest.json");
+  let manifest_data = std::fs::read(&manifest_path)
+    .map_err(|err| format!("Failed to read {}: {err}", manifest_path.display()))?;
+  let manifest_key = format!("{version_prefix}/manifest.json");
+  uploaded.push(manifest_key.clone());
+  put_verified(
+    store,
+    &manifest_key,
+    manifest_data,
+    "application/json",
+    IMMUTABLE_CACHE_CONTROL,
+  )
+  .await?;
+  Ok(artifacts)
+}
+
+fn read_manifest(manifest_path: &Path) -> Result<CorpusManifest, String> {
+  std::fs::read(manifest_path)
+    .map_err(|err| format!("Failed to read {}: {err}", manifest_path.display()))
+    .and_then(|manifest| {
+      serde_json::from_slice(&manifest)
+        .map_err(|err| format!("Failed to parse {}: {err}", manifest_path.display()))
+    })
+}
+
+/// Names a release after its build time and the start of its content hash
+fn release_version(manifest: &CorpusManifest, manifest_path: &Path) -> Result<String, String> {
+  let built_at = chrono::DateTime::from_timestamp(manifest.built_at, 0)
+    .ok_or_else(|| format!("Invalid build time in {}", manifest_path.display()))?;
+  let version_hash = manifest
+    .content_sha256
+    .get(..VERSION_HASH_LENGTH)
+    .filter(|hash| hash.bytes().all(|b| b.is_ascii_hexdigit()))
+    .ok_or_else(|| format!("Invalid content hash in {}", manifest_path.display()))?;
+  Ok(format!(
+    "{}-{version_hash}",
+    built_at.format("%Y%m%d%H%M%S")
+  ))
+}
+
+/// Index entry for the corpus described by `manifest`, whose files are in `release_dir` relative
+/// to the index
+fn manifest_release(
+  manifest: &CorpusManifest,
+  manifest_path: &Path,
+  id: String,
+  release_dir: &str,
+) -> Result<CorpusRelease, String> {
+  let corpus_file_name = &manifest
+    .artifacts
+    .iter()
+    .find(|artifact| artifact.encoding == "identity")
+    .ok_or_else(|| format!("{} lists no uncompressed corpus", manifest_path.display()))?
+    .file_name;
+  Ok(CorpusRelease {
+    id,
+    url: object_key(release_dir, corpus_file_name),
+    format_version: manifest.format_version,
+    row_count: manifest.row_count,
+    built_at: manifest.built_at,
+    published_at: chrono::Utc::now().timestamp(),
+    content_sha256: manifest.content_sha256.clone(),
+    bounds: manifest.bounds.clone(),
+    default_view: manifest.default_view.clone(),
+    mirrored: false,
+    manifest_key: None,
+    artifacts: Vec::new(),
+    sidecars: manifest
+      .sidecars
+      .iter()
+      .map(|sidecar| {
+        (
+          sidecar.file_name.clone(),
+          object_key(release_dir, &sidecar.file_name),
+        )
+      })
+      .collect(),
+  })
+}
+
+/// Writes a `corpus-index.json` listing only the corpus in `corpus_dir` next to it, so that the
+/// frontend can load a local build from `serve` or any static file server without publishing it
+pub(crate) fn write_local_corpus_index(corpus_dir: &Path) -> Result<(), String> {
+  let manifest_path = corpus_dir.join("manifest.json");
+  let manifest = read_manifest(&manifest_path)?;
+  let version = release_version(&manifest, &manifest_path)?;
+  let release = manifest_release(&manifest, &manifest_path, version, "")?;
+  let index = CorpusIndex {
+    latest: Some(release.id.clone()),
+    releases: vec![release],
+    ..Default::default()
+  };
+
+  let index_path = corpus_dir.join(CORPUS_INDEX_KEY);
+  let index_json = serde_json::to_vec_pretty(&index).expect("Failed to serialize corpus index");
+  std::fs::write(&index_path, index_json)
+    .map_err(|err| format!("Failed to write {}: {err}", index_path.display()))?;
+  info!("Wrote corpus index to {}", index_path.display());
+  Ok(())
+}
+
+/// Publishes the corpus in `corpus_dir` along with its manifest and adds it to the corpus index as
+/// the latest release.  Publishing a corpus whose content was already published returns the
+/// existing release.
+pub(crate) async fn publish_corpus(
+  store: &impl ObjectStore,
+  prefix: &str,
+  corpus_dir: &Path,
+) -> Result<CorpusRelease, String> {
+  let manifest_path = corpus_dir.join("manifest.json");
+  let manifest = read_manifest(&manifest_path)?;
+
+  let index_key = object_key(prefix, CORPUS_INDEX_KEY);
+  let (index, _) = read_corpus_index(store, &index_key).await?;
+  if let Some(existing) = index
+    .releases
+    .iter()
+    .find(|release| release.content_sha256 == manifest.content_sha256)
+  {
+    info!("This corpus was already published as {}", existing.id);
+    return Ok(existing.clone());
+  }
+
+  let version = release_version(&manifest, &manifest_path)?;
+  // Relative to the index
+  let release_dir = format!("corpora/{version}");
+  let version_prefix = object_key(prefix, &release_dir);
+  let release = manifest_release(&manifest, &manifest_path, version.clone(), &release_dir)?;
+
+  let mut uploaded = Vec::new();
+  let published: Result<CorpusRelease, String> = async {
+    let artifacts =
+      upload_release(store, corpus_dir, &manifest, &version_prefix, &mut uploaded).await?;
+    let published = CorpusRelease {
+      manifest_key: Some(format!("{version_prefix}/manifest.json")),
+      artifacts,
+      ..release
+    };
+    update_corpus_index(store, &index_key, |index| {
+      index
+        .releases
+        .retain(|existing| existing.id != published.id);
+      index.releases.push(published.clone());
+      index.latest = Some(published.id.clone());
+    })
+    .await?;
+    Ok(published)
+  }
+  .await;
+
+  match published {
+    Ok(published) => {
+      info!(
+        "Published {version} with {} rows to {version_prefix}",
+        published.row_count
+      );
+      Ok(published)
+    },
+    // The index is only written as the very last step, so nothing points at the uploaded objects
+    Err(err) => {
+      for key in uploaded {
+        if let Err(delete_err) = store.delete(&ObjectPath::from(key.as_str())).await {
+          warn!("Failed to clean up {key}: {delete_err}");
+        }
+      }
+      Err(format!("Failed to publish {version}: {err}"))
+    },
+  }
+}
+
+/// A corpus which is already hosted somewhere other than the bucket
+pub(crate) struct ExternalRelease<'a> {
+  pub id: &'a str,
+  pub url: &'a str,
+  /// Local copy of the hosted corpus, which the index entry is computed from
+  pub corpus_path: &'a Path,
+  /// Unix timestamp in seconds
+  pub built_at: i64,
+  pub mirrored: bool,
+  /// Makes this the release that's shown by default.  The first release added to an empty index
+  /// is always made the latest.
+  pub latest: bool,
+}
+
+/// Adds a corpus which is hosted elsewhere to the corpus index without uploading it, replacing any
+/// existing release with the same ID
+pub(crate) async fn register_release(
+  store: &impl ObjectStore,
+  prefix: &str,
+  release: &ExternalRelease<'_>,
+) -> Result<CorpusRelease, String> {
+  let data = std::fs::read(release.corpus_path)
+    .map_err(|err| format!("Failed to read {}: {err}", release.corpus_path.display()))?;
+  let rows = decode_corpus(&data)?;
+  let positions: Vec<[f32; 2]> = rows.iter().map(|row| row.position).collect();
+  let registered = CorpusRelease {
+    id: release.id.to_owned(),
+    url: release.url.to_owned(),
+    format_version: corpus_format_version(&data),
+    row_count: rows.len() as u32,
+    built_at: release.built_at,
+    published_at: chrono::Utc::now().timestamp(),
+    content_sha256: sha256_hex(&data),
+    bounds: compute_bounds(&positions),
+    default_view: compute_default_view(&positions),
+    mirrored: release.mirrored,
+    manifest_key: None,
+    artifacts: Vec::new(),
+    sidecars: BTreeMap::new(),
+  };
+
+  let index_key = object_key(prefix, CORPUS_INDEX_KEY);
+  update_corpus_index(store, &index_key, |index| {
+    index.releases.retain(|existing| existing.id != release.id);
+    index.releases.push(registered.clone());
+    if release.latest || index.latest.is_none() {
+      index.latest = Some(registered.id.clone());
+    }
+  })
+  .await?;
+  info!(
+    "Registered {} with {} rows in {index_key}",
+    registered.id, registered.row_count
+  );
+  Ok(registered)
+}
+
+/// Uploads and verifies every artifact, publishing the same corpus again reuses the existing
+/// release rather than adding a duplicate, registered releases are listed in build order even when
+/// registered concurrently, and stale index writes are refused
+#[tokio::test(flavor = "multi_thread")]
+async fn publish_uploads_verified_release_and_indexes_it() {
+  use crate::build_corpus::{encode_corpus, CorpusRow};
+
+  let dir = std::env::temp_dir().join(format!("atlas-publish-test-{}", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+  let corpus = encode_corpus(vec![CorpusRow::test_row(1, 0)]);
+  let sidecars = [crate::manifest::Sidecar {
+    file_name: "search_index",
+    data: crate::search_index::build_search_index(&corpus.rows),
+  }];
+  crate::manifest::write_corpus_artifacts(&corpus, &sidecars, &dir).await;
+
+  let store = object_store::memory::InMemory::new();
+  let published = publish_corpus(&store, "atlas", &dir).await.unwrap();
+  let republished = publish_corpus(&store, "atlas", &dir).await.unwrap();
+  let legacy = ExternalRelease {
+    id: "1716022133",
+    url: "https://example.com/corpus_1716022133.txt",
+    corpus_path: &dir.join("corpus"),
+    built_at: 1716022133,
+    mirrored: false,
+    latest: false,
+  };
+  let other_legacy = ExternalRelease {
+    id: "1716022134",
+    built_at: 1716022134,
+    ..legacy
+  };
+  let (registered, other_registered) = tokio::join!(
+    register_release(&store, "atlas", &legacy),
+    register_release(&store, "atlas", &other_legacy)
+  );
+  registered.unwrap();
+  other_registered.unwrap();
+
+  write_local_corpus_index(&dir).unwrap();
+  let local_index: CorpusIndex =
+    serde_json::from_slice(&std::fs::read(dir.join(CORPUS_INDEX_KEY)).unwrap()).unwrap();
+  assert_eq!(local_index.releases.len(), 1);
+  let local_release = &local_index.releases[0];
+  assert_eq!(local_index.latest.as_ref(), Some(&local_release.id));
+  assert_eq!(local_release.id, published.id);
+  assert_eq!(local_release.url, "corpus");
+  assert_eq!(local_release.sidecars["search_index"], "search_index");
+
+  let manifest_path = dir.join("manifest.json");
+  let mut manifest: serde_json::Value =
+    serde_json::from_slice(&std::fs::read(&manifest_path).unwrap()).unwrap();
+  manifest["content_sha256"] = "not-a-hash".into();
+  std::fs::write(&manifest_path, manifest.to_string()).unwrap();
+  let edited = publish_corpus(&object_store::memory::InMemory::new(), "atlas", &dir).await;
+  assert!(edited.is_err_and(|err| err.contains("Invalid content hash")));
+  std::fs::remove_dir_all(&dir).unwrap();
+
+  assert_eq!(published.row_count, 1);
+  assert_eq!(published.artifacts.len(), 3);
+  for artifact in &published.artifacts {
+    assert!(artifact.key.starts_with("atlas/corpora/"));
+    let data = store
+      .get(&ObjectPath::from(artifact.key.as_str()))
+      .await
+      .unwrap()
+      .bytes()
+      .await
+      .unwrap();
+    assert_eq!(sha256_hex(&data), artifact.sha256);
+  }
+  assert_eq!(republished.id, published.id);
+  assert_eq!(published.url, format!("corpora/{}/corpus", published.id));
+  let search_index_url = &published.sidecars["search_index"];
+  assert_eq!(
+    search_index_url,
+    &format!("corpora/{}/search_index", published.id)
+  );
+  assert!(store
+    .head(&ObjectPath::from(format!("atlas/{search_index_url}")))
+    .await
+    .is_ok());
+
+  let (mut index, version) = read_corpus_index(&store, "atlas/corpus-index.json")
+    .await
+    .unwrap();
+  let ids: Vec<&str> = index
+    .releases
+    .iter()
+    .map(|release| release.id.as_str())
+    .collect();
+  assert_eq!(ids, ["1716022133", "1716022134", published.id.as_str()]);
+  assert_eq!(index.latest.as_deref(), Some(published.id.as_str()));
+
+  let index_key = "atlas/corpus-index.json";
+  assert!(
+    write_corpus_index(&store, index_key, &mut index, version.clone())
+      .await
+      .unwrap()
+  );
+  assert!(!write_corpus_index(&store, index_key, &mut index, version)
+    .await
+    .unwrap());
+}
@@ -0,0 +1,236 @@
+//! Recommends maps at the frontier of a player's skill.
+//!
+//! The player's frontier is the upper end of the star rating and average pp of the corpus rows for
+//! their top plays.  Candidates are unplayed rows which are up to a bit harder than that and which
+//! sit among the player's plays, either as embedding neighbors or as co-occurrence neighbors.
+//! Candidates near more of the player's plays rank higher, and ties go to the smaller step up.
+
+use std::path::Path;
+
+use foundations::telemetry::log::*;
+use fxhash::{FxHashMap, FxHashSet};
+use serde::Serialize;
+
+use crate::{
+  build_corpus::{decode_corpus, CorpusRow},
+  cooccurrence::{read_cooccurrence_graph, CooccurrenceGraph},
+  neighbors::nearest_rows,
+};
+
+pub(crate) struct RecommendParams {
+  /// Maximum number of recommendations to return
+  pub count: usize,
+  /// Number of embedding and co-occurrence neighbors of each play that are considered
+  pub neighbors_k: usize,
+  /// How far above the player's frontier star rating candidates can be
+  pub max_star_gain: f64,
+  /// How far above the player's frontier average pp candidates can be, as a fraction
+  pub max_pp_gain: f64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Recommendation {
+  pub score_id: String,
+  /// Index of the corresponding row in the corpus
+  pub row_ix: usize,
+  /// Number of the player's plays which have this row as an embedding neighbor
+  pub nearby_plays: usize,
+  /// Number of the player's plays which have this row as a co-occurrence neighbor
+  pub cooccurring_plays: usize,
+  pub star_delta: f64,
+  pub avg_pp_delta: f64,
+  pub reason: String,
+}
+
+/// Upper end of a player's range; high enough to ignore most of the easier plays, but not so high
+/// that a single outlier sets it
+const FRONTIER_QUANTILE: f64 = 0.9;
+
+fn frontier(mut values: Vec<f64>) -> f64 {
+  values.sort_unstable_by(f64::total_cmp);
+  values[((values.len() - 1) as f64 * FRONTIER_QUANTILE).round() as usize]
+}
+
+fn format_reason(recommendation: &Recommendation) -> String {
+  let mut parts = Vec::new();
+  if recommendation.nearby_plays > 0 {
+    parts.push(format!(
+      "near {} of your top plays",
+      recommendation.nearby_plays
+    ));
+  }
+  if recommendation.cooccurring_plays > 0 {
+    parts.push(format!(
+      "often played alongside {} of your top plays",
+      recommendation.cooccurring_plays
+    ));
+  }
+  parts.push(format!("{:+.1}★", recommendation.star_delta));
+  parts.join(", ")
+}
+
+/// Recommends unplayed rows for a player whose top plays are `played_score_ids`.  Score IDs which
+/// aren't in the corpus are ignored.  Co-occurrence neighbors are only considered if `graph` is
+/// provided.
+pub(crate) fn recommend(
+  rows: &[CorpusRow],
+  played_score_ids: &[String],
+  graph: Option<&CooccurrenceGraph>,
+  params: &RecommendParams,
+) -> Result<Vec<Recommendation>, String> {
+  let row_ix_by_score_id: FxHashMap<&str, usize> = rows
+    .iter()
+    .enumerate()
+    .map(|(ix, row)| (row.score_id.as_str(), ix))
+    .collect();
+  let played: FxHashSet<usize> = played_score_ids
+    .iter()
+    .filter_map(|score_id| row_ix_by_score_id.get(score_id.as_str()).copied())
+    .collect();
+  if played.is_empty() {
+    return Err("None of the provided score IDs are in the corpus".to_owned());
+  }
+  let unmatched_count = played_score_ids
+    .iter()
+    .filter(|score_id| !row_ix_by_score_id.contains_key(score_id.as_str()))
+    .count();
+  if unmatched_count > 0 {
+    warn!("{unmatched_count} of the provided score IDs aren't in the corpus");
+  }
+
+  let star_frontier = frontier(played.iter().map(|&ix| rows[ix].stars).collect());
+  let pp_frontier = frontier(played.iter().map(|&ix| rows[ix].avg_pp).collect());
+  let is_candidate = |ix: usize| {
+    let row = &rows[ix];
+    !played.contains(&ix)
+      && row.stars >= star_frontier
+      && row.stars <= star_frontier + params.max_star_gain
+      && row.avg_pp <= pp_frontier * (1. + params.max_pp_gain)
+  };
+
+  // `(nearby plays, co-occurring plays)` for each candidate row
+  let mut counts: FxHashMap<usize, (usize, usize)> = FxHashMap::default();
+  for &played_ix in &played {
+    for neighbor_ix in nearest_rows(
+      rows,
+      rows[played_ix].position,
+      params.neighbors_k,
+      Some(played_ix),
+    ) {
+      if is_candidate(neighbor_ix) {
+        counts.entry(neighbor_ix).or_default().0 += 1;
+      }
+    }
+  }
+  if let Some(graph) = graph {
+    let graph_ix_by_score_id: FxHashMap<&str, usize> = graph
+      .score_ids
+      .iter()
+      .enumerate()
+      .map(|(ix, score_id)| (score_id.as_str(), ix))
+      .collect();
+    for &played_ix in &played {
+      let Some(&graph_ix) = graph_ix_by_score_id.get(rows[played_ix].score_id.as_str()) else {
+        continue;
+      };
+      let mut neighbors: Vec<(f32, usize)> = graph.adjacency[graph_ix]
+        .iter()
+        .filter_map(|&(neighbor_graph_ix, weight)| {
+          let score_id = graph.score_ids[neighbor_graph_ix as usize].as_str();
+          row_ix_by_score_id
+            .get(score_id)
+            .map(|&row_ix| (weight, row_ix))
+        })
+        .collect();
+      neighbors.sort_unstable_by(|a, b| b.0.total_cmp(&a.0));
+      for &(_, neighbor_ix) in neighbors.iter().take(params.neighbors_k) {
+        if is_candidate(neighbor_ix) {
+          counts.entry(neighbor_ix).or_default().1 += 1;
+        }
+      }
+    }
+  }
+
+  let mut recommendations: Vec<Recommendation> = counts
+    .into_iter()
+    .map(|(row_ix, (nearby_plays, cooccurring_plays))| {
+      let row = &rows[row_ix];
+      let mut recommendation = Recommendation {
+        score_id: row.score_id.clone(),
+        row_ix,
+        nearby_plays,
+        cooccurring_plays,
+        star_delta: row.stars - star_frontier,
+        avg_pp_delta: row.avg_pp - pp_frontier,
+        reason: String::new(),
+      };
+      recommendation.reason = format_reason(&recommendation);
+      recommendation
+    })
+    .collect();
+  recommendations.sort_unstable_by(|a, b| {
+    (b.nearby_plays + b.cooccurring_plays)
+      .cmp(&(a.nearby_plays + a.cooccurring_plays))
+      .then(a.star_delta.total_cmp(&b.star_delta))
+      .then(a.row_ix.cmp(&b.row_ix))
+  });
+  recommendations.truncate(params.count);
+  Ok(recommendations)
+}
+
+/// Recommends maps from the corpus at `corpus_path` for a player whose top plays are
+/// `played_score_ids`, using the co-occurrence graph at `cooccurrence_path` if provided.
+pub(crate) fn recommend_from_files(
+  corpus_path: &Path,
+  cooccurrence_path: Option<&Path>,
+  played_score_ids: &[String],
+  params: &RecommendParams,
+) -> Result<Vec<Recommendation>, String> {
+  let corpus_data = std::fs::read(corpus_path)
+    .map_err(|err| format!("Failed to read {}: {err}", corpus_path.display()))?;
+  let rows = decode_corpus(&corpus_data)?;
+  let graph = cooccurrence_path.map(read_cooccurrence_graph).transpose()?;
+  recommend(&rows, played_score_ids, graph.as_ref(), params)
+}
+
+/// Candidates must be unplayed, near the player's plays, and a bit harder than their frontier, and
+/// ones near more plays rank first
+#[test]
+fn recommends_nearby_maps_above_frontier() {
+  let row = |beatmap_id: i32, position: [f32; 2], stars: f64| CorpusRow {
+    position,
+    avg_pp: stars * 50.,
+    stars,
+    ..CorpusRow::test_row(beatmap_id, 0)
+  };
+  let rows = vec![
+    // Played
+    row(1, [0., 0.], 5.),
+    row(2, [1., 0.], 5.),
+    // Near both plays, +0.2★
+    row(3, [0.5, 0.5], 5.2),
+    // Near one play, +0.1★
+    row(4, [1.5, 0.], 5.1),
+    // Near but too hard
+    row(5, [0.5, -0.5], 6.),
+    // Near but easier
+    row(6, [0.5, 0.], 4.),
+    // Far away
+    row(7, [100., 100.], 5.1),
+  ];
+  let params = RecommendParams {
+    count: 10,
+    neighbors_k: 4,
+    max_star_gain: 0.5,
+    max_pp_gain: 0.2,
+  };
+
+  let recommendations =
+    recommend(&rows, &["1_".to_owned(), "2_".to_owned()], None, &params).unwrap();
+  let score_ids: Vec<&str> = recommendations
+    .iter()
+    .map(|r| r.score_id.as_str())
+    .collect();
+  assert_eq!(score_ids, ["3_", "4_"]);
+  assert_eq!(recommendations[0].reason, "near 2 of your top plays, +0.2★");
+}
@@ -0,0 +1,178 @@
+//! Derives `score_metadata.csv` directly from the hiscores parquet file.
+//!
+//! The first three columns (`score_id`, `avg_pp`, `num_users`) match what `embed.ipynb` writes.
+//! The rest are extra per-score statistics:
+//!
+//! - `median_pp`, `p10_pp`, `p25_pp`, `p75_pp`, `p90_pp`: pp distribution of the plays
+//! - `mean_accuracy`, `median_accuracy`: only filled in if the dump has judgement counts
+//! - `first_seen`, `last_seen`: dates of the earliest and latest plays, as `YYYY-MM-DD`
+//! - `grade_ss`, `grade_s`, `grade_a`, `grade_b`, `grade_c`, `grade_d`: number of players with each
+//!   letter grade
+//!
+//! Statistics can be restricted to plays recorded before a given date, which is used to build
+//! snapshots of the atlas as it was at that point in time.
+
+use std::path::Path;
+
+use chrono::{DateTime, NaiveDate};
+use foundations::telemetry::log::*;
+use fxhash::FxHashMap;
+
+use crate::{
+  hiscores::{dedupe_hiscores, read_hiscores, Grade, Hiscore},
+  ScoreMetadata,
+};
+
+/// Linearly interpolated quantile of sorted values, like pandas' default
+fn quantile(sorted: &[f32], q: f64) -> Option<f64> {
+  let last = sorted.len().checked_sub(1)?;
+  let pos = q * last as f64;
+  let (lo, hi) = (pos.floor() as usize, pos.ceil() as usize);
+  let frac = pos - lo as f64;
+  Some(sorted[lo] as f64 * (1. - frac) + sorted[hi] as f64 * frac)
+}
+
+fn format_date(unix_secs: i64) -> String {
+  DateTime::from_timestamp(unix_secs, 0)
+    .map(|dt| dt.format("%Y-%m-%d").to_string())
+    .unwrap_or_default()
+}
+
+fn format_opt(value: Option<f64>) -> String { value.map(|v| format!("{v}")).unwrap_or_default() }
+
+fn score_metadata_record(score_id: &str, plays: &[&Hiscore]) -> Vec<String> {
+  let mut pps: Vec<f32> = plays.iter().map(|play| play.pp).collect();
+  pps.sort_unstable_by(f32::total_cmp);
+  let avg_pp = pps.iter().map(|&pp| pp as f64).sum::<f64>() / pps.len() as f64;
+
+  let mut accuracies: Vec<f32> = plays.iter().filter_map(|play| play.accuracy).collect();
+  accuracies.sort_unstable_by(f32::total_cmp);
+  let mean_accuracy = (!accuracies.is_empty())
+    .then(|| accuracies.iter().map(|&acc| acc as f64).sum::<f64>() / accuracies.len() as f64);
+
+  let seen_times = plays
+    .iter()
+    .map(|play| play.score_time.unwrap_or(play.update_time));
+  let first_seen = seen_times.clone().min().unwrap();
+  let last_seen = seen_times.max().unwrap();
+
+  let mut record = vec![
+    score_id.to_owned(),
+    format!("{avg_pp}"),
+    plays.len().to_string(),
+    format_opt(quantile(&pps, 0.5)),
+    format_opt(quantile(&pps, 0.1)),
+    format_opt(quantile(&pps, 0.25)),
+    format_opt(quantile(&pps, 0.75)),
+    format_opt(quantile(&pps, 0.9)),
+    format_opt(mean_accuracy),
+    format_opt(quantile(&accuracies, 0.5)),
+    format_date(first_seen),
+    format_date(last_seen),
+  ];
+  for grade in Grade::ALL {
+    let count = plays
+      .iter()
+      .filter(|play| play.grade == Some(grade))
+      .count();
+    record.push(count.to_string());
+  }
+  record
+}
+
+/// Reads deduplicated plays from `hiscores_path`, only including plays recorded before `as_of`
+/// if it's provided.
+fn read_plays(hiscores_path: &Path, as_of: Option<NaiveDate>) -> Result<Vec<Hiscore>, String> {
+  let mut plays = read_hiscores(hiscores_path)?;
+  if let Some(as_of) = as_of {
+    let cutoff = as_of.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp();
+    plays.retain(|play| play.update_time < cutoff);
+    info!("{} plays were recorded before {as_of}", plays.len());
+  }
+  Ok(dedupe_hiscores(plays))
+}
+
+/// Groups plays by score, sorted by score ID like the output of the `groupby` in the notebook
+fn group_plays_by_score(plays: &[Hiscore]) -> Vec<(String, Vec<&Hiscore>)> {
+  let mut plays_by_score: FxHashMap<u64, Vec<&Hiscore>> = FxHashMap::default();
+  for play in plays {
+    plays_by_score
+      .entry(play.score_key())
+      .or_default()
+      .push(play);
+  }
+  let mut scores: Vec<(String, Vec<&Hiscore>)> = plays_by_score
+    .into_values()
+    .map(|plays| (plays[0].score_id(), plays))
+    .collect();
+  scores.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+  scores
+}
+
+/// Computes the basic score metadata from only the plays recorded before `as_of`.  Scores
+/// without any plays by then are left out.
+pub(crate) fn score_metadata_as_of(
+  hiscores_path: &Path,
+  as_of: NaiveDate,
+) -> Result<Vec<ScoreMetadata>, String> {
+  let plays = read_plays(hiscores_path, Some(as_of))?;
+  Ok(
+    group_plays_by_score(&plays)
+      .into_iter()
+      .map(|(score_id, plays)| ScoreMetadata {
+        score_id,
+        avg_pp: plays.iter().map(|play| play.pp as f64).sum::<f64>() / plays.len() as f64,
+        num_users: plays.len() as i32,
+      })
+      .collect(),
+  )
+}
+
+/// Reads hiscores from `hiscores_path` and writes per-score statistics to `out_path`.  If `as_of`
+/// is provided, only plays recorded before that date are included.
+pub(crate) fn generate_score_metadata(
+  hiscores_path: &Path,
+  out_path: &Path,
+  as_of: Option<NaiveDate>,
+) -> Result<(), String> {
+  let plays = read_plays(hiscores_path, as_of)?;
+  let scores = group_plays_by_score(&plays);
+
+  let mut wtr = csv::Writer::from_path(out_path)
+    .map_err(|err| format!("Failed to create {}: {err}", out_path.display()))?;
+  let mut header = vec![
+    "score_id",
+    "avg_pp",
+    "num_users",
+    "median_pp",
+    "p10_pp",
+    "p25_pp",
+    "p75_pp",
+    "p90_pp",
+    "mean_accuracy",
+    "median_accuracy",
+    "first_seen",
+    "last_seen",
+  ]
+  .into_iter()
+  .map(String::from)
+  .collect::<Vec<_>>();
+  header.extend(Grade::ALL.map(|grade| format!("grade_{}", grade.name())));
+  wtr.write_record(&header).map_err(|err| format!("{err}"))?;
+
+  for (score_id, plays) in &scores {
+    wtr
+      .write_record(score_metadata_record(score_id, plays))
+      .map_err(|err| format!("{err}"))?;
+  }
+  wtr
+    .flush()
+    .map_err(|err| format!("Failed to write {}: {err}", out_path.display()))?;
+
+  info!(
+    "Wrote metadata for {} scores to {}",
+    scores.len(),
+    out_path.display()
+  );
+  Ok(())
+}
@@ -0,0 +1,362 @@
+//! Prebuilt trigram index over the searchable text of the corpus so that the frontend can search
+//! as soon as it's downloaded rather than indexing every row itself first.
+//!
+//! Text is normalized with [`normalize_search_text`], which lowercases it, strips diacritics, and
+//! folds compatibility forms like fullwidth letters, then split into words.  Every word is padded
+//! with a space on either side and broken into overlapping three-character trigrams, so `osu`
+//! becomes ` os`, `osu`, and `su `.  Since all of the mod variants of a beatmap share the same
+//! text, the index has one document per beatmap rather than one per row.
+//!
+//! The search index sidecar is a binary file which starts with a header:
+//!
+//! [u8; 4] magic bytes `OSUS`
+//! [u32] format version
+//! [u32] number of rows; matches the corpus that it was built alongside
+//! [u32] number of documents
+//! [u32] number of trigrams
+//!
+//! This is followed by the documents, each of which is a [u32] row count followed by that many
+//! [u32] corpus row indices.  After that come the trigrams, sorted by their UTF-8 bytes.  Each is
+//! stored as a [u8] byte length, the trigram's UTF-8 bytes, a [u32] number of documents containing
+//! it, and a [u32] byte length of its posting list.  The posting list holds the ascending indices
+//! of those documents, each stored as the difference from the previous one (or from 0 for the
+//! first) as an unsigned LEB128 varint.
+
+use std::collections::BTreeMap;
+
+use foundations::telemetry::log::*;
+use fxhash::FxHashMap;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use crate::build_corpus::CorpusRow;
+
+pub(crate) const SEARCH_INDEX_MAGIC: [u8; 4] = *b"OSUS";
+pub(crate) const SEARCH_INDEX_FORMAT_VERSION: u32 = 1;
+/// Fraction of a query's trigrams which a document needs to contain to match, which leaves room
+/// for typos
+const MIN_MATCHED_TRIGRAM_FRACTION: f32 = 0.75;
+
+/// Folds compatibility characters, strips diacritics, lowercases `text`, and replaces everything
+/// other than letters and numbers with spaces.  Lowercasing comes last so that characters which
+/// only decompose into uppercase letters, like `ℌ`, are folded too.  The frontend normalizes
+/// queries the same way.
+pub(crate) fn normalize_search_text(text: &str) -> String {
+  text
+    .nfkd()
+    .filter(|&c| !is_combining_mark(c))
+    .collect::<String>()
+    .to_lowercase()
+    .chars()
+    .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+    .collect()
+}
+
+/// Adds the trigrams of `word` to `out`.  If `is_prefix`, the word may continue past its end, so
+/// the trigram containing the padding after it is left out.
+fn word_trigrams(word: &str, is_prefix: bool, out: &mut Vec<String>) {
+  let mut chars: Vec<char> = Vec::with_capacity(word.len() + 2);
+  chars.push(' ');
+  chars.extend(word.chars());
+  if !is_prefix {
+    chars.push(' ');
+  }
+  out.extend(chars.windows(3).map(|window| window.iter().collect()));
+}
+
+/// Trigrams for a search query.  The last word is treated as a prefix so that results show up
+/// while it's still being typed.
+pub(crate) fn query_trigrams(query: &str) -> Vec<String> {
+  let normalized = normalize_search_text(query);
+  let words: Vec<&str> = normalized.split_whitespace().collect();
+  let mut trigrams = Vec::new();
+  for (word_ix, word) in words.iter().enumerate() {
+    word_trigrams(word, word_ix == words.len() - 1, &mut trigrams);
+  }
+  trigrams.sort_unstable();
+  trigrams.dedup();
+  trigrams
+}
+
+/// The text which a row can be found by
+fn searchable_fields(row: &CorpusRow) -> impl Iterator<Item = &str> {
+  [
+    Some(row.title.as_str()),
+    Some(&row.artist),
+    Some(&row.creator),
+    Some(&row.version),
+    Some(&row.tags),
+    row.title_unicode.as_deref(),
+    row.artist_unicode.as_deref(),
+  ]
+  .into_iter()
+  .flatten()
+}
+
+pub(crate) struct SearchIndex {
+  /// Corpus row indices in each document
+  pub doc_rows: Vec<Vec<u32>>,
+  /// Ascending document indices containing each trigram
+  pub postings: BTreeMap<String, Vec<u32>>,
+}
+
+impl SearchIndex {
+  /// Indexes `rows`, which must be in the same order as the rows of the corpus
+  pub fn build(rows: &[CorpusRow]) -> Self {
+    let mut doc_ix_by_beatmap_id: FxHashMap<i32, u32> = FxHashMap::default();
+    let mut doc_rows: Vec<Vec<u32>> = Vec::new();
+    let mut postings: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    let mut trigrams = Vec::new();
+    for (row_ix, row) in rows.iter().enumerate() {
+      if let Some(&doc_ix) = doc_ix_by_beatmap_id.get(&row.beatmap_id) {
+        doc_rows[doc_ix as usize].push(row_ix as u32);
+        continue;
+      }
+
+      let doc_ix = doc_rows.len() as u32;
+      doc_ix_by_beatmap_id.insert(row.beatmap_id, doc_ix);
+      doc_rows.push(vec![row_ix as u32]);
+
+      trigrams.clear();
+      for field in searchable_fields(row) {
+        for word in normalize_search_text(field).split_whitespace() {
+          word_trigrams(word, false, &mut trigrams);
+        }
+      }
+      trigrams.sort_unstable();
+      trigrams.dedup();
+      for trigram in trigrams.drain(..) {
+        postings.entry(trigram).or_default().push(doc_ix);
+      }
+    }
+
+    SearchIndex { doc_rows, postings }
+  }
+
+  /// Finds the rows matching `query`, best matches first.  Documents are ranked by how many of the
+  /// query's trigrams they contain and then by how many users have played them, and all of the rows
+  /// of each document are returned together.
+  pub fn search(&self, rows: &[CorpusRow], query: &str, limit: usize) -> Vec<usize> {
+    let trigrams = query_trigrams(query);
+    if trigrams.is_empty() {
+      return Vec::new();
+    }
+    let min_matched = ((trigrams.len() as f32 * MIN_MATCHED_TRIGRAM_FRACTION).ceil() as u32).max(1);
+
+    let mut matched_count_by_doc: FxHashMap<u32, u32> = FxHashMap::default();
+    for trigram in &trigrams {
+      for &doc_ix in self.postings.get(trigram).into_iter().flatten() {
+        *matched_count_by_doc.entry(doc_ix).or_default() += 1;
+      }
+    }
+
+    let popularity = |doc_ix: u32| {
+      self.doc_rows[doc_ix as usize]
+        .iter()
+        .map(|&row_ix| rows[row_ix as usize].num_users)
+        .max()
+        .unwrap_or(0)
+    };
+    let mut matches: Vec<(u32, i32, u32)> = matched_count_by_doc
+      .into_iter()
+      .filter(|&(_, matched_count)| matched_count >= min_matched)
+      .map(|(doc_ix, matched_count)| (matched_count, popularity(doc_ix), doc_ix))
+      .collect();
+    matches.sort_unstable_by(|a, b| b.cmp(a));
+
+    matches
+      .into_iter()
+      .flat_map(|(_, _, doc_ix)| &self.doc_rows[doc_ix as usize])
+      .map(|&row_ix| row_ix as usize)
+      .take(limit)
+      .collect()
+  }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u32) {
+  while value >= 0x80 {
+    buf.push((value as u8) | 0x80);
+    value >>= 7;
+  }
+  buf.push(value as u8);
+}
+
+pub(crate) fn encode_search_index(index: &SearchIndex, num_rows: usize) -> Vec<u8> {
+  let mut buf = Vec::new();
+  buf.extend_from_slice(&SEARCH_INDEX_MAGIC);
+  buf.extend_from_slice(&SEARCH_INDEX_FORMAT_VERSION.to_le_bytes());
+  buf.extend_from_slice(&(num_rows as u32).to_le_bytes());
+  buf.extend_from_slice(&(index.doc_rows.len() as u32).to_le_bytes());
+  buf.extend_from_slice(&(index.postings.len() as u32).to_le_bytes());
+
+  for doc_rows in &index.doc_rows {
+    buf.extend_from_slice(&(doc_rows.len() as u32).to_le_bytes());
+    for &row_ix in doc_rows {
+      buf.extend_from_slice(&row_ix.to_le_bytes());
+    }
+  }
+
+  let mut posting_buf = Vec::new();
+  for (trigram, doc_ixs) in &index.postings {
+    posting_buf.clear();
+    let mut prev_doc_ix = 0;
+    for &doc_ix in doc_ixs {
+      write_varint(&mut posting_buf, doc_ix - prev_doc_ix);
+      prev_doc_ix = doc_ix;
+    }
+
+    buf.push(trigram.len() as u8);
+    buf.extend_from_slice(trigram.as_bytes());
+    buf.extend_from_slice(&(doc_ixs.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(posting_buf.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&posting_buf);
+  }
+  buf
+}
+
+/// Builds the search index sidecar for `rows`, which must be in the same order as the rows of the
+/// corpus
+pub(crate) fn build_search_index(rows: &[CorpusRow]) -> Vec<u8> {
+  let index = SearchIndex::build(rows);
+  let encoded = encode_search_index(&index, rows.len());
+  info!(
+    "Built search index with {} trigrams over {} beatmaps ({} bytes)",
+    index.postings.len(),
+    index.doc_rows.len(),
+    encoded.len()
+  );
+  encoded
+}
+
+/// Reads values sequentially out of a search index sidecar
+struct SearchIndexReader<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> SearchIndexReader<'a> {
+  fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+    let bytes = self
+      .data
+      .get(self.pos..self.pos + len)
+      .ok_or_else(|| format!("Search index is truncated at offset {}", self.pos))?;
+    self.pos += len;
+    Ok(bytes)
+  }
+
+  fn u32(&mut self) -> Result<u32, String> {
+    Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+  }
+
+  fn varint(&mut self) -> Result<u32, String> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+      let byte = self.take(1)?[0];
+      value |= ((byte & 0x7f) as u32) << shift;
+      if byte & 0x80 == 0 {
+        return Ok(value);
+      }
+    }
+    Err(format!(
+      "Invalid varint in search index at offset {}",
+      self.pos
+    ))
+  }
+}
+
+/// Parses a search index sidecar, checking that it was built for a corpus with `num_rows` rows
+pub(crate) fn decode_search_index(data: &[u8], num_rows: usize) -> Result<SearchIndex, String> {
+  let mut reader = SearchIndexReader { data, pos: 0 };
+  if reader.take(SEARCH_INDEX_MAGIC.len())? != SEARCH_INDEX_MAGIC {
+    return Err("Not a search index".to_owned());
+  }
+  let format_version = reader.u32()?;
+  if format_version != SEARCH_INDEX_FORMAT_VERSION {
+    return Err(format!(
+      "Unsupported search index format version {format_version}"
+    ));
+  }
+  let index_num_rows = reader.u32()? as usize;
+  if index_num_rows != num_rows {
+    return Err(format!(
+      "Search index was built for {index_num_rows} rows but the corpus has {num_rows}"
+    ));
+  }
+  let num_docs = reader.u32()? as usize;
+  let num_trigrams = reader.u32()? as usize;
+
+  let mut doc_rows = Vec::with_capacity(num_docs);
+  for _ in 0..num_docs {
+    let row_count = reader.u32()? as usize;
+    let rows = (0..row_count)
+      .map(|_| reader.u32())
+      .collect::<Result<Vec<_>, _>>()?;
+    if rows.iter().any(|&row_ix| row_ix as usize >= num_rows) {
+      return Err("Search index references a row which isn't in the corpus".to_owned());
+    }
+    doc_rows.push(rows);
+  }
+
+  let mut postings = BTreeMap::new();
+  for _ in 0..num_trigrams {
+    let len = reader.take(1)?[0] as usize;
+    let trigram = String::from_utf8(reader.take(len)?.to_vec())
+      .map_err(|err| format!("Invalid trigram in search index: {err}"))?;
+    let doc_count = reader.u32()?;
+    let _posting_len = reader.u32()?;
+    let mut doc_ix = 0;
+    let mut doc_ixs = Vec::with_capacity(doc_count as usize);
+    for _ in 0..doc_count {
+      doc_ix += reader.varint()?;
+      if doc_ix as usize >= num_docs {
+        return Err("Search index references a document which doesn't exist".to_owned());
+      }
+      doc_ixs.push(doc_ix);
+    }
+    postings.insert(trigram, doc_ixs);
+  }
+
+  Ok(SearchIndex { doc_rows, postings })
+}
+
+/// Searches survive encoding, ignore case and diacritics, find rows by any of their names, and
+/// tolerate a typo
+#[test]
+fn search_index_folds_case_and_diacritics() {
+  let row =
+    |beatmap_id: i32, mods_bits: u32, title: &str, artist: &str, num_users: i32| CorpusRow {
+      num_users,
+      title: title.to_owned(),
+      version: "Insane".to_owned(),
+      creator: "Mapper".to_owned(),
+      artist: artist.to_owned(),
+      ..CorpusRow::test_row(beatmap_id, mods_bits)
+    };
+  let mut rows = vec![
+    row(1, 0, "Pokémon Theme", "Jason Paige", 10),
+    row(2, 0, "FREEDOM DiVE", "xi", 500),
+    row(1, 64, "Pokémon Theme", "Jason Paige", 20),
+    row(3, 0, "Blue Bird", "Ikimono-gakari", 300),
+    row(4, 0, "Freedom", "Someone", 5),
+  ];
+  rows[3].title_unicode = Some("ブルーバード".to_owned());
+  rows[3].artist_unicode = Some("いきものがかり".to_owned());
+  rows[4].tags = "ＦＵＬＬＷＩＤＴＨ".to_owned();
+
+  let encoded = build_search_index(&rows);
+  let index = decode_search_index(&encoded, rows.len()).unwrap();
+  assert_eq!(index.doc_rows, vec![vec![0, 2], vec![1], vec![3], vec![4]]);
+
+  assert_eq!(index.search(&rows, "pokemon", 10), vec![0, 2]);
+  assert_eq!(index.search(&rows, "POKÉMON theme", 10), vec![0, 2]);
+  assert_eq!(index.search(&rows, "freedom", 10), vec![1, 4]);
+  assert_eq!(index.search(&rows, "freedon", 10), vec![1, 4]);
+  assert_eq!(index.search(&rows, "ブルーバ", 10), vec![3]);
+  assert_eq!(index.search(&rows, "いきものがかり", 10), vec![3]);
+  assert_eq!(index.search(&rows, "ikimono", 10), vec![3]);
+  assert_eq!(index.search(&rows, "fullwidth", 10), vec![4]);
+  assert_eq!(index.search(&rows, "ＦＵＬＬ", 10), vec![4]);
+  assert_eq!(normalize_search_text("ℌＡＬＦ-İstanbul"), "half istanbul");
+  assert_eq!(index.search(&rows, "xi", 10), vec![1]);
+  assert!(index.search(&rows, "x", 10).is_empty());
+  assert!(decode_search_index(&encoded, rows.len() + 1).is_err());
+}
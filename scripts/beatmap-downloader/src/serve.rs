@@ -0,0 +1,660 @@
+//! Small HTTP API over the corpus and the difficulty calculation, so that the frontend can get
+//! everything from one backend.
+//!
+//! - `GET /corpus`: the corpus file, pre-compressed with brotli or zstd if the client accepts it
+//!   and the variants were built alongside it.  Served with an `ETag` and `Last-Modified` so
+//!   clients can revalidate cheaply.
+//! - `GET /manifest.json`: the corpus manifest
+//! - `GET /corpus-index.json`: the local corpus index written by `build-corpus`, whose URLs are
+//!   relative to it and so point at `/corpus` and the sidecar routes below
+//! - `GET /{file_name}`: a sidecar listed in the manifest, like `/search_index`
+//! - `GET /scores/{score_id}`: the corpus row for a score ID
+//! - `GET /scores/{score_id}/neighbors?k=`: closest rows to a score in the embedding
+//! - `GET /scores/{score_id}/related`: other difficulties and mod variants in the same beatmapset
+//!   as a score, and rows from other beatmapsets of the same song
+//! - `GET /neighbors?x=&y=&k=`: closest rows to a point in the embedding
+//! - `GET /search?q=&limit=`: rows matching a search query, best matches first
+//! - `GET /difficulty/{beatmap_id}?mods=&acc=&misses=`: difficulty attributes and pp for a ranked
+//!   beatmap, which is downloaded and stored if it hasn't been already.  Needs a database.
+//! - `POST /difficulty?mods=&acc=&misses=`: same, but for the `.osu` file in the request body
+
+use std::{net::SocketAddr, path::Path, sync::Arc};
+
+use axum::{
+  body::Bytes,
+  extract::{DefaultBodyLimit, Path as UrlPath, Query, State},
+  http::{header, HeaderMap, HeaderValue, StatusCode},
+  response::{IntoResponse, Response},
+  routing::get,
+  Json, Router,
+};
+use chrono::{DateTime, Utc};
+use foundations::telemetry::log::*;
+use fxhash::FxHashMap;
+use rosu_pp::osu::{OsuDifficultyAttributes, OsuPerformance};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+  build_corpus::{decode_corpus, CorpusRow},
+  compute_difficulty, compute_difficulty_inner,
+  export::ExportRow,
+  groups::{decode_groups, CorpusGroups},
+  manifest::CorpusManifest,
+  neighbors::nearest_rows,
+  parse_mods,
+  publish::CORPUS_INDEX_KEY,
+  search_index::{decode_search_index, SearchIndex},
+};
+
+const CORPUS_CACHE_CONTROL: &str = "public, max-age=3600";
+const LOOKUP_CACHE_CONTROL: &str = "public, max-age=300";
+/// Difficulty only changes when rosu-pp is updated
+const DIFFICULTY_CACHE_CONTROL: &str = "public, max-age=86400";
+const MAX_NEIGHBORS: usize = 100;
+const DEFAULT_NEIGHBORS: usize = 10;
+const MAX_SEARCH_RESULTS: usize = 100;
+const DEFAULT_SEARCH_RESULTS: usize = 20;
+const MAX_UPLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+struct CorpusVariant {
+  /// Value of the `Content-Encoding` that the variant is stored with
+  encoding: &'static str,
+  data: Bytes,
+  etag: String,
+}
+
+pub(crate) struct ServerState {
+  /// In order of preference
+  corpus_variants: Vec<CorpusVariant>,
+  corpus_last_modified: Option<String>,
+  manifest: Option<Bytes>,
+  corpus_index: Option<Bytes>,
+  /// Sidecars listed in the manifest, keyed by file name
+  sidecars: FxHashMap<String, Bytes>,
+  rows: Vec<CorpusRow>,
+  row_ix_by_score_id: FxHashMap<String, usize>,
+  search_index: SearchIndex,
+  groups: CorpusGroups,
+}
+
+fn etag(data: &[u8]) -> String { format!("\"{:x}\"", Sha256::digest(data)) }
+
+fn http_date(time: std::time::SystemTime) -> String {
+  DateTime::<Utc>::from(time)
+    .format("%a, %d %b %Y %H:%M:%S GMT")
+    .to_string()
+}
+
+impl ServerState {
+  /// Loads `corpus`, along with `corpus.br`, `corpus.zst`, `manifest.json`, `corpus-index.json`,
+  /// and the sidecars listed in the manifest if present, from `corpus_dir`.  The search index and
+  /// groups are built from the corpus if they're missing.
+  pub fn load(corpus_dir: &Path) -> Result<Self, String> {
+    let read = |file_name: &str| -> Result<Option<Vec<u8>>, String> {
+      let path = corpus_dir.join(file_name);
+      match std::fs::read(&path) {
+        Ok(data) => Ok(Some(data)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(format!("Failed to read {}: {err}", path.display())),
+      }
+    };
+
+    let corpus_path = corpus_dir.join("corpus");
+    let corpus =
+      read("corpus")?.ok_or_else(|| format!("{} doesn't exist", corpus_path.display()))?;
+    let rows = decode_corpus(&corpus)?;
+    let corpus_last_modified = std::fs::metadata(&corpus_path)
+      .and_then(|metadata| metadata.modified())
+      .ok()
+      .map(http_date);
+
+    let mut corpus_variants = Vec::new();
+    for (encoding, file_name) in [("br", "corpus.br"), ("zstd", "corpus.zst")] {
+      if let Some(data) = read(file_name)? {
+        corpus_variants.push(CorpusVariant {
+          encoding,
+          etag: etag(&data),
+          data: data.into(),
+        });
+      }
+    }
+    corpus_variants.push(CorpusVariant {
+      encoding: "identity",
+      etag: etag(&corpus),
+      data: corpus.into(),
+    });
+
+    let row_ix_by_score_id = rows
+      .iter()
+      .enumerate()
+      .map(|(ix, row)| (row.score_id.clone(), ix))
+      .collect();
+    let search_index = match read("search_index")? {
+      Some(data) => decode_search_index(&data, rows.len())?,
+      None => {
+        warn!("No search index was built alongside the corpus, so building one now");
+        SearchIndex::build(&rows)
+      },
+    };
+    let groups = match read("groups")? {
+      Some(data) => decode_groups(&data, rows.len())?,
+      None => {
+        warn!("No groups were built alongside the corpus, so building them now");
+        CorpusGroups::build(&rows)
+      },
+    };
+    let manifest = read("manifest.json")?;
+    let mut sidecars = FxHashMap::default();
+    if let Some(manifest) = &manifest {
+      let manifest: CorpusManifest = serde_json::from_slice(manifest)
+        .map_err(|err| format!("Failed to parse manifest.json: {err}"))?;
+      for sidecar in manifest.sidecars {
+        if let Some(data) = read(&sidecar.file_name)? {
+          sidecars.insert(sidecar.file_name, Bytes::from(data));
+        }
+      }
+    }
+    Ok(Self {
+      corpus_variants,
+      corpus_last_modified,
+      manifest: manifest.map(Bytes::from),
+      corpus_index: read(CORPUS_INDEX_KEY)?.map(Bytes::from),
+      sidecars,
+      rows,
+      row_ix_by_score_id,
+      search_index,
+      groups,
+    })
+  }
+}
+
+/// Whether `encoding` is accepted by an `Accept-Encoding` header.  An entry naming the encoding
+/// takes precedence over a `*` entry, so `br;q=0, *` accepts anything but brotli.
+fn accepts_encoding(accept_encoding: &str, encoding: &str) -> bool {
+  let mut wildcard = None;
+  for entry in accept_encoding.split(',') {
+    let mut parts = entry.split(';').map(str::trim);
+    let name = parts.next().unwrap_or_default();
+    let disabled = parts.any(|param| {
+      param
+        .strip_prefix("q=")
+        .and_then(|q| q.parse::<f32>().ok())
+        .is_some_and(|q| q == 0.)
+    });
+    if name.eq_ignore_ascii_case(encoding) {
+      return !disabled;
+    }
+    if name == "*" {
+      wildcard = Some(!disabled);
+    }
+  }
+  wildcard.unwrap_or(false)
+}
+
+fn corpus_response(state: &ServerState, headers: &HeaderMap) -> Response {
+  let accept_encoding = headers
+    .get(header::ACCEPT_ENCODING)
+    .and_then(|value| value.to_str().ok())
+    .unwrap_or_default();
+  let variant = state
+    .corpus_variants
+    .iter()
+    .find(|variant| {
+      variant.encoding == "identity" || accepts_encoding(accept_encoding, variant.encoding)
+    })
+    .unwrap();
+
+  let mut response_headers = HeaderMap::new();
+  response_headers.insert(
+    header::CACHE_CONTROL,
+    HeaderValue::from_static(CORPUS_CACHE_CONTROL),
+  );
+  response_headers.insert(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+  response_headers.insert(header::ETAG, HeaderValue::from_str(&variant.etag).unwrap());
+  if let Some(last_modified) = &state.corpus_last_modified {
+    response_headers.insert(
+      header::LAST_MODIFIED,
+      HeaderValue::from_str(last_modified).unwrap(),
+    );
+  }
+
+  let not_modified = headers
+    .get(header::IF_NONE_MATCH)
+    .and_then(|value| value.to_str().ok())
+    .is_some_and(|if_none_match| {
+      if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == variant.etag || tag.trim() == "*")
+    });
+  if not_modified {
+    return (StatusCode::NOT_MODIFIED, response_headers).into_response();
+  }
+
+  response_headers.insert(
+    header::CONTENT_TYPE,
+    HeaderValue::from_static("application/octet-stream"),
+  );
+  if variant.encoding != "identity" {
+    response_headers.insert(
+      header::CONTENT_ENCODING,
+      HeaderValue::from_static(variant.encoding),
+    );
+  }
+  (response_headers, variant.data.clone()).into_response()
+}
+
+async fn get_corpus(State(state): State<Arc<ServerState>>, headers: HeaderMap) -> Response {
+  corpus_response(&state, &headers)
+}
+
+/// Serves a file which is replaced whenever the corpus is rebuilt
+fn corpus_file_response(data: Option<&Bytes>, content_type: &'static str) -> Response {
+  match data {
+    Some(data) => (
+      [
+        (header::CONTENT_TYPE, content_type),
+        (header::CACHE_CONTROL, "no-cache"),
+      ],
+      data.clone(),
+    )
+      .into_response(),
+    None => StatusCode::NOT_FOUND.into_response(),
+  }
+}
+
+async fn get_manifest(State(state): State<Arc<ServerState>>) -> Response {
+  corpus_file_response(state.manifest.as_ref(), "application/json")
+}
+
+async fn get_corpus_index(State(state): State<Arc<ServerState>>) -> Response {
+  corpus_file_response(state.corpus_index.as_ref(), "application/json")
+}
+
+async fn get_sidecar(
+  State(state): State<Arc<ServerState>>,
+  UrlPath(file_name): UrlPath<String>,
+) -> Response {
+  let content_type = if file_name.ends_with(".json") {
+    "application/json"
+  } else {
+    "application/octet-stream"
+  };
+  corpus_file_response(state.sidecars.get(&file_name), content_type)
+}
+
+type ApiResult<T> = Result<T, (StatusCode, String)>;
+
+fn lookup_score<'a>(state: &'a ServerState, score_id: &str) -> ApiResult<(usize, &'a CorpusRow)> {
+  state
+    .row_ix_by_score_id
+    .get(score_id)
+    .map(|&ix| (ix, &state.rows[ix]))
+    .ok_or_else(|| {
+      (
+        StatusCode::NOT_FOUND,
+        format!("{score_id} isn't in the corpus"),
+      )
+    })
+}
+
+#[derive(Serialize)]
+struct RowResponse<'a> {
+  row_ix: usize,
+  #[serde(flatten)]
+  row: ExportRow<'a>,
+}
+
+async fn get_score(
+  State(state): State<Arc<ServerState>>,
+  UrlPath(score_id): UrlPath<String>,
+) -> ApiResult<Response> {
+  let (row_ix, row) = lookup_score(&state, &score_id)?;
+  let response = RowResponse {
+    row_ix,
+    row: row.into(),
+  };
+  Ok(
+    (
+      [(header::CACHE_CONTROL, LOOKUP_CACHE_CONTROL)],
+      Json(response),
+    )
+      .into_response(),
+  )
+}
+
+#[derive(Deserialize)]
+struct NeighborsQuery {
+  k: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct PointNeighborsQuery {
+  x: f32,
+  y: f32,
+  k: Option<usize>,
+}
+
+fn neighbors_response(
+  state: &ServerState,
+  pos: [f32; 2],
+  k: Option<usize>,
+  exclude_ix: Option<usize>,
+) -> Response {
+  let k = k.unwrap_or(DEFAULT_NEIGHBORS).min(MAX_NEIGHBORS);
+  let neighbors: Vec<RowResponse> = nearest_rows(&state.rows, pos, k, exclude_ix)
+    .into_iter()
+    .map(|row_ix| RowResponse {
+      row_ix,
+      row: (&state.rows[row_ix]).into(),
+    })
+    .collect();
+  (
+    [(header::CACHE_CONTROL, LOOKUP_CACHE_CONTROL)],
+    Json(neighbors),
+  )
+    .into_response()
+}
+
+async fn get_score_neighbors(
+  State(state): State<Arc<ServerState>>,
+  UrlPath(score_id): UrlPath<String>,
+  Query(query): Query<NeighborsQuery>,
+) -> ApiResult<Response> {
+  let (row_ix, row) = lookup_score(&state, &score_id)?;
+  Ok(neighbors_response(
+    &state,
+    row.position,
+    query.k,
+    Some(row_ix),
+  ))
+}
+
+async fn get_point_neighbors(
+  State(state): State<Arc<ServerState>>,
+  Query(query): Query<PointNeighborsQuery>,
+) -> Response {
+  neighbors_response(&state, [query.x, query.y], query.k, None)
+}
+
+#[derive(Serialize)]
+struct RelatedResponse<'a> {
+  /// Other difficulties and mod variants in the same beatmapset
+  set_rows: Vec<RowResponse<'a>>,
+  /// Rows from other beatmapsets of the same song
+  song_rows: Vec<RowResponse<'a>>,
+}
+
+async fn get_score_related(
+  State(state): State<Arc<ServerState>>,
+  UrlPath(score_id): UrlPath<String>,
+) -> ApiResult<Response> {
+  let (row_ix, row) = lookup_score(&state, &score_id)?;
+  let set_ix = state.groups.set_ix(row.beatmapset_id).ok_or_else(|| {
+    (
+      StatusCode::INTERNAL_SERVER_ERROR,
+      format!("{score_id}'s beatmapset isn't grouped"),
+    )
+  })?;
+  let row_response = |row_ix: u32| RowResponse {
+    row_ix: row_ix as usize,
+    row: (&state.rows[row_ix as usize]).into(),
+  };
+
+  let set_rows = state
+    .groups
+    .rows_of_set(set_ix)
+    .iter()
+    .filter(|&&other_ix| other_ix as usize != row_ix)
+    .map(|&other_ix| row_response(other_ix))
+    .collect();
+  let song_ix = state.groups.sets[set_ix].song_ix as usize;
+  let song_rows = state
+    .groups
+    .sets_of_song(song_ix)
+    .iter()
+    .filter(|&&other_set_ix| other_set_ix as usize != set_ix)
+    .flat_map(|&other_set_ix| state.groups.rows_of_set(other_set_ix as usize))
+    .map(|&other_ix| row_response(other_ix))
+    .collect();
+  Ok(
+    (
+      [(header::CACHE_CONTROL, LOOKUP_CACHE_CONTROL)],
+      Json(RelatedResponse {
+        set_rows,
+        song_rows,
+      }),
+    )
+      .into_response(),
+  )
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+  q: String,
+  limit: Option<usize>,
+}
+
+async fn get_search(
+  State(state): State<Arc<ServerState>>,
+  Query(query): Query<SearchQuery>,
+) -> Response {
+  let limit = query
+    .limit
+    .unwrap_or(DEFAULT_SEARCH_RESULTS)
+    .min(MAX_SEARCH_RESULTS);
+  let results: Vec<RowResponse> = state
+    .search_index
+    .search(&state.rows, &query.q, limit)
+    .into_iter()
+    .map(|row_ix| RowResponse {
+      row_ix,
+      row: (&state.rows[row_ix]).into(),
+    })
+    .collect();
+  (
+    [(header::CACHE_CONTROL, LOOKUP_CACHE_CONTROL)],
+    Json(results),
+  )
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct DifficultyQuery {
+  /// Concatenated mod acronyms like `HDDT`
+  #[serde(default)]
+  mods: String,
+  /// Accuracy percentage used for the pp calculation; defaults to 100
+  acc: Option<f64>,
+  #[serde(default)]
+  misses: u32,
+}
+
+#[derive(Serialize)]
+struct DifficultyResponse {
+  stars: f64,
+  aim: f64,
+  speed: f64,
+  flashlight: f64,
+  slider_factor: f64,
+  speed_note_count: f64,
+  ar: f64,
+  hp: f64,
+  great_hit_window: f64,
+  n_circles: u32,
+  n_sliders: u32,
+  n_spinners: u32,
+  max_combo: u32,
+  pp: f64,
+}
+
+fn difficulty_response(
+  attrs: OsuDifficultyAttributes,
+  query: &DifficultyQuery,
+) -> ApiResult<Response> {
+  let mods = parse_mods(&query.mods).map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+  let performance = OsuPerformance::new(attrs.clone())
+    .mods(mods.bits())
+    .accuracy(query.acc.unwrap_or(100.))
+    .misses(query.misses)
+    .calculate()
+    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}")))?;
+  let response = DifficultyResponse {
+    stars: attrs.stars,
+    aim: attrs.aim,
+    speed: attrs.speed,
+    flashlight: attrs.flashlight,
+    slider_factor: attrs.slider_factor,
+    speed_note_count: attrs.speed_note_count,
+    ar: attrs.ar,
+    hp: attrs.hp,
+    great_hit_window: attrs.great_hit_window,
+    n_circles: attrs.n_circles,
+    n_sliders: attrs.n_sliders,
+    n_spinners: attrs.n_spinners,
+    max_combo: attrs.max_combo,
+    pp: performance.pp,
+  };
+  Ok(
+    (
+      [(header::CACHE_CONTROL, DIFFICULTY_CACHE_CONTROL)],
+      Json(response),
+    )
+      .into_response(),
+  )
+}
+
+async fn get_difficulty(
+  UrlPath(beatmap_id): UrlPath<i32>,
+  Query(query): Query<DifficultyQuery>,
+) -> ApiResult<Response> {
+  parse_mods(&query.mods).map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+  if crate::DB_POOL.get().is_none() {
+    return Err((
+      StatusCode::SERVICE_UNAVAILABLE,
+      "No database is configured, so only uploaded beatmaps can be calculated".to_owned(),
+    ));
+  }
+  let attrs = compute_difficulty(&format!("{beatmap_id}_{}", query.mods))
+    .await
+    .map_err(|err| (StatusCode::BAD_GATEWAY, err))?;
+  difficulty_response(attrs, &query)
+}
+
+async fn post_difficulty(Query(query): Query<DifficultyQuery>, body: Bytes) -> ApiResult<Response> {
+  let mods = parse_mods(&query.mods).map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+  let attrs = tokio::task::block_in_place(|| compute_difficulty_inner(&body, mods))
+    .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err))?;
+  difficulty_response(attrs, &query)
+}
+
+fn router(state: Arc<ServerState>) -> Router {
+  Router::new()
+    .route("/corpus", get(get_corpus))
+    .route("/manifest.json", get(get_manifest))
+    .route("/corpus-index.json", get(get_corpus_index))
+    .route("/{file_name}", get(get_sidecar))
+    .route("/scores/{score_id}", get(get_score))
+    .route("/scores/{score_id}/neighbors", get(get_score_neighbors))
+    .route("/scores/{score_id}/related", get(get_score_related))
+    .route("/neighbors", get(get_point_neighbors))
+    .route("/search", get(get_search))
+    .route("/difficulty/{beatmap_id}", get(get_difficulty))
+    .route(
+      "/difficulty",
+      axum::routing::post(post_difficulty).layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE)),
+    )
+    .layer(axum::middleware::map_response(
+      |mut response: Response| async move {
+        response.headers_mut().insert(
+          header::ACCESS_CONTROL_ALLOW_ORIGIN,
+          HeaderValue::from_static("*"),
+        );
+        response
+      },
+    ))
+    .with_state(state)
+}
+
+/// Serves the API over the corpus in `corpus_dir` on `addr` until the process is stopped.
+pub(crate) async fn serve(corpus_dir: &Path, addr: SocketAddr) -> Result<(), String> {
+  let state = tokio::task::block_in_place(|| ServerState::load(corpus_dir))?;
+  info!(
+    "Loaded {} corpus rows from {}",
+    state.rows.len(),
+    corpus_dir.display()
+  );
+
+  let listener = tokio::net::TcpListener::bind(addr)
+    .await
+    .map_err(|err| format!("Failed to bind to {addr}: {err}"))?;
+  info!("Listening on http://{addr}");
+  axum::serve(listener, router(Arc::new(state)))
+    .await
+    .map_err(|err| format!("{err}"))
+}
+
+/// The most preferred variant that the client accepts is served, and a matching `If-None-Match`
+/// gets a 304 without a body
+#[test]
+fn corpus_negotiates_encoding_and_revalidates() {
+  let variant = |encoding: &'static str, data: &'static [u8]| CorpusVariant {
+    encoding,
+    data: Bytes::from_static(data),
+    etag: etag(data),
+  };
+  let state = ServerState {
+    corpus_variants: vec![
+      variant("br", b"brotli"),
+      variant("zstd", b"zstd"),
+      variant("identity", b"raw"),
+    ],
+    corpus_last_modified: None,
+    manifest: None,
+    corpus_index: None,
+    sidecars: FxHashMap::default(),
+    rows: Vec::new(),
+    row_ix_by_score_id: FxHashMap::default(),
+    search_index: SearchIndex::build(&[]),
+    groups: CorpusGroups::build(&[]),
+  };
+  let request = |pairs: &[(header::HeaderName, &str)]| {
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+      headers.insert(name, HeaderValue::from_str(value).unwrap());
+    }
+    corpus_response(&state, &headers)
+  };
+  let encoding = |response: &Response| {
+    response
+      .headers()
+      .get(header::CONTENT_ENCODING)
+      .map(|value| value.to_str().unwrap().to_owned())
+  };
+
+  let response = request(&[(header::ACCEPT_ENCODING, "gzip, deflate, br, zstd")]);
+  assert_eq!(response.status(), StatusCode::OK);
+  assert_eq!(encoding(&response).as_deref(), Some("br"));
+
+  let response = request(&[(header::ACCEPT_ENCODING, "br;q=0, zstd")]);
+  assert_eq!(encoding(&response).as_deref(), Some("zstd"));
+
+  let response = request(&[(header::ACCEPT_ENCODING, "br;q=0, *")]);
+  assert_eq!(encoding(&response).as_deref(), Some("zstd"));
+
+  let response = request(&[(header::ACCEPT_ENCODING, "gzip")]);
+  assert_eq!(encoding(&response), None);
+  let raw_etag = response.headers()[header::ETAG]
+    .to_str()
+    .unwrap()
+    .to_owned();
+
+  let response = request(&[
+    (header::ACCEPT_ENCODING, "gzip"),
+    (header::IF_NONE_MATCH, &raw_etag),
+  ]);
+  assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+  // Routing panics if the sidecar route conflicts with the others
+  let _ = router(Arc::new(state));
+}
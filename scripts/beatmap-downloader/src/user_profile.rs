@@ -0,0 +1,435 @@
+//! Analyzes where a player's top plays sit in the atlas.
+//!
+//! Top plays are fetched from the osu! API, mapped to corpus score IDs, and summarized into a JSON
+//! profile with the weighted centroid and spread of the plays in the embedding, their mod mix,
+//! whether they lean towards aim or speed, and how the player ranks against everyone tracked in
+//! the hiscores dump.
+//!
+//! The API is accessed through the [`OsuApi`] trait so that the analysis can be run against a
+//! stand-in instead of the real osu! API.
+
+use std::path::Path;
+
+use foundations::telemetry::log::*;
+use fxhash::FxHashMap;
+use rosu_mods::GameMode;
+use rosu_v2::Osu;
+use serde::Serialize;
+
+use crate::{
+  build_corpus::{decode_corpus, score_id_from_parts, CorpusRow, SCORE_ID_MODS},
+  hiscores::{dedupe_hiscores, read_hiscores, score_key, Hiscore, MIN_PP},
+};
+
+/// Number of top plays that the osu! API returns at most, and that count towards a player's pp
+const TOP_PLAY_COUNT: usize = 100;
+/// Weight multiplier applied to each successive top play, like the profile pp calculation
+const PLAY_WEIGHT_DECAY: f64 = 0.95;
+
+#[derive(Clone, Debug)]
+pub(crate) struct TopPlay {
+  pub beatmap_id: i32,
+  /// Raw mods bitflags as returned by the API
+  pub mods_bits: u32,
+  pub pp: f32,
+}
+
+pub(crate) struct ApiUser {
+  pub user_id: u32,
+  pub username: String,
+  pub top_plays: Vec<TopPlay>,
+}
+
+pub(crate) trait OsuApi {
+  /// Looks up a user by ID or username and fetches their osu!standard top plays
+  async fn fetch_user(&self, user: &str) -> Result<ApiUser, String>;
+}
+
+/// [`OsuApi`] implementation backed by the osu! API v2
+pub(crate) struct RosuApi {
+  osu: Osu,
+}
+
+impl RosuApi {
+  pub async fn new(client_id: u64, client_secret: String) -> Result<Self, String> {
+    let osu = Osu::new(client_id, client_secret)
+      .await
+      .map_err(|err| format!("Failed to authenticate with the osu! API: {err}"))?;
+    Ok(Self { osu })
+  }
+}
+
+impl OsuApi for RosuApi {
+  async fn fetch_user(&self, user: &str) -> Result<ApiUser, String> {
+    let user_id = match user.parse::<u32>() {
+      Ok(user_id) => user_id,
+      Err(_) =>
+        self
+          .osu
+          .user(user)
+          .mode(GameMode::Osu)
+          .await
+          .map_err(|err| format!("Failed to look up user {user}: {err}"))?
+          .user_id,
+    };
+    let scores = self
+      .osu
+      .user_scores(user_id)
+      .best()
+      .mode(GameMode::Osu)
+      .limit(TOP_PLAY_COUNT)
+      .await
+      .map_err(|err| format!("Failed to fetch top plays for user {user}: {err}"))?;
+
+    let username = scores
+      .iter()
+      .find_map(|score| score.user.as_ref())
+      .map_or_else(|| user.to_owned(), |user| user.username.to_string());
+    let top_plays = scores
+      .iter()
+      .map(|score| TopPlay {
+        beatmap_id: score.map_id as i32,
+        mods_bits: score.mods.bits(),
+        pp: score.pp.unwrap_or(0.),
+      })
+      .collect();
+    Ok(ApiUser {
+      user_id,
+      username,
+      top_plays,
+    })
+  }
+}
+
+/// Total of pp values sorted descending, each weighted by [`PLAY_WEIGHT_DECAY`] to the power of
+/// its position
+fn weighted_pp(sorted_pps: impl Iterator<Item = f32>) -> f64 {
+  sorted_pps
+    .take(TOP_PLAY_COUNT)
+    .enumerate()
+    .map(|(ix, pp)| pp as f64 * PLAY_WEIGHT_DECAY.powi(ix as i32))
+    .sum()
+}
+
+/// Weighted pp of each tracked player's top plays, sorted ascending.  Only plays worth at least
+/// [`MIN_PP`] are in the dump, so this is lower than their actual profile pp.
+pub(crate) fn tracked_weighted_pps(plays: Vec<Hiscore>) -> Vec<f64> {
+  let plays = dedupe_hiscores(plays);
+  let mut totals: Vec<f64> = plays
+    .chunk_by(|a, b| a.user == b.user)
+    .map(|user_plays| {
+      let mut pps: Vec<f32> = user_plays.iter().map(|play| play.pp).collect();
+      pps.sort_unstable_by(|a, b| b.total_cmp(a));
+      weighted_pp(pps.into_iter())
+    })
+    .collect();
+  totals.sort_unstable_by(f64::total_cmp);
+  totals
+}
+
+#[derive(Serialize)]
+pub(crate) struct ProfilePlay {
+  pub score_id: String,
+  /// Index of the corresponding row in the corpus
+  pub row_ix: usize,
+  pub pp: f32,
+  /// Weight of the play within the profile; the weights of all matched plays sum to 1
+  pub weight: f64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct UserProfile {
+  pub user_id: u32,
+  pub username: String,
+  pub top_play_count: usize,
+  /// Number of top plays which are part of the corpus.  The rest don't contribute to the profile.
+  pub matched_count: usize,
+  /// pp-weighted mean position of the matched plays in the embedding
+  pub centroid: Option<[f32; 2]>,
+  /// Weighted RMS distance from the centroid to the matched plays
+  pub spread: Option<f32>,
+  /// Share of matched plays by mod combination, keyed by the mods portion of the score ID (`""`
+  /// for no mods)
+  pub mod_shares: FxHashMap<String, f64>,
+  pub mean_aim_speed_ratio: Option<f64>,
+  /// Mean aim/speed ratio over the whole corpus, for reference.  A player's ratio above this means
+  /// that they lean towards aim-heavy maps, and below means that they lean towards speed.
+  pub corpus_mean_aim_speed_ratio: f64,
+  /// Weighted pp of the top plays worth at least [`MIN_PP`], which is what `percentile` compares
+  pub weighted_pp: f64,
+  /// Percentage of tracked players with a lower weighted pp
+  pub percentile: f64,
+  pub plays: Vec<ProfilePlay>,
+}
+
+fn mean_aim_speed_ratio<'a>(rows: impl Iterator<Item = (&'a CorpusRow, f64)>) -> Option<f64> {
+  let (total, total_weight) = rows.filter(|(row, _)| row.speed_difficulty > 0.).fold(
+    (0., 0.),
+    |(total, total_weight), (row, weight)| {
+      (
+        total + row.aim_difficulty / row.speed_difficulty * weight,
+        total_weight + weight,
+      )
+    },
+  );
+  (total_weight > 0.).then(|| total / total_weight)
+}
+
+/// Builds the profile for `user`, whose top plays must be sorted by pp descending, from the corpus
+/// rows and the sorted output of [`tracked_weighted_pps`].
+pub(crate) fn analyze_profile(
+  user: &ApiUser,
+  rows: &[CorpusRow],
+  tracked_weighted_pps: &[f64],
+) -> UserProfile {
+  let row_ix_by_key: FxHashMap<u64, usize> = rows
+    .iter()
+    .enumerate()
+    .map(|(ix, row)| (score_key(row.beatmap_id, row.mods_bits), ix))
+    .collect();
+  let mods_mask: u32 = SCORE_ID_MODS.iter().map(|&(_, bit)| bit).sum();
+
+  let mut plays: Vec<ProfilePlay> = user
+    .top_plays
+    .iter()
+    .filter_map(|play| {
+      let mods_bits = play.mods_bits & mods_mask;
+      let &row_ix = row_ix_by_key.get(&score_key(play.beatmap_id, mods_bits))?;
+      Some(ProfilePlay {
+        score_id: score_id_from_parts(play.beatmap_id, mods_bits),
+        row_ix,
+        pp: play.pp,
+        weight: play.pp as f64,
+      })
+    })
+    .collect();
+  let total_weight: f64 = plays.iter().map(|play| play.weight).sum();
+  for play in &mut plays {
+    play.weight /= total_weight;
+  }
+
+  let (centroid, spread) = if plays.is_empty() || total_weight <= 0. {
+    (None, None)
+  } else {
+    let mut centroid = [0f64; 2];
+    for play in &plays {
+      let pos = rows[play.row_ix].position;
+      centroid[0] += pos[0] as f64 * play.weight;
+      centroid[1] += pos[1] as f64 * play.weight;
+    }
+    let spread = plays
+      .iter()
+      .map(|play| {
+        let pos = rows[play.row_ix].position;
+        let (dx, dy) = (pos[0] as f64 - centroid[0], pos[1] as f64 - centroid[1]);
+        (dx * dx + dy * dy) * play.weight
+      })
+      .sum::<f64>()
+      .sqrt();
+    (
+      Some([centroid[0] as f32, centroid[1] as f32]),
+      Some(spread as f32),
+    )
+  };
+
+  let mut mod_shares: FxHashMap<String, f64> = FxHashMap::default();
+  for play in &plays {
+    *mod_shares
+      .entry(rows[play.row_ix].mods_str().to_owned())
+      .or_default() += 1. / plays.len() as f64;
+  }
+
+  let weighted_pp = weighted_pp(
+    user
+      .top_plays
+      .iter()
+      .map(|play| play.pp)
+      .filter(|&pp| pp >= MIN_PP),
+  );
+  let percentile = if tracked_weighted_pps.is_empty() {
+    0.
+  } else {
+    let lower_count = tracked_weighted_pps.partition_point(|&total| total < weighted_pp);
+    lower_count as f64 / tracked_weighted_pps.len() as f64 * 100.
+  };
+
+  UserProfile {
+    user_id: user.user_id,
+    username: user.username.clone(),
+    top_play_count: user.top_plays.len(),
+    matched_count: plays.len(),
+    centroid,
+    spread,
+    mod_shares,
+    mean_aim_speed_ratio: mean_aim_speed_ratio(
+      plays.iter().map(|play| (&rows[play.row_ix], play.weight)),
+    ),
+    corpus_mean_aim_speed_ratio: mean_aim_speed_ratio(rows.iter().map(|row| (row, 1.)))
+      .unwrap_or(1.),
+    weighted_pp,
+    percentile,
+    plays,
+  }
+}
+
+/// Fetches `user`'s top plays from `api` and writes their profile against the corpus at
+/// `corpus_path` and the hiscores at `hiscores_path` to `out_path` as JSON.
+pub(crate) async fn user_profile(
+  api: &impl OsuApi,
+  user: &str,
+  corpus_path: &Path,
+  hiscores_path: &Path,
+  out_path: &Path,
+) -> Result<(), String> {
+  let mut api_user = api.fetch_user(user).await?;
+  api_user
+    .top_plays
+    .sort_unstable_by(|a, b| b.pp.total_cmp(&a.pp));
+  info!(
+    "Fetched {} top plays for {} ({})",
+    api_user.top_plays.len(),
+    api_user.username,
+    api_user.user_id
+  );
+
+  let corpus_data = std::fs::read(corpus_path)
+    .map_err(|err| format!("Failed to read {}: {err}", corpus_path.display()))?;
+  let rows = decode_corpus(&corpus_data)?;
+  let tracked =
+    tokio::task::block_in_place(|| read_hiscores(hiscores_path).map(tracked_weighted_pps))?;
+
+  let profile = analyze_profile(&api_user, &rows, &tracked);
+  if profile.matched_count < profile.top_play_count {
+    warn!(
+      "{} of {}'s top plays aren't in the corpus",
+      profile.top_play_count - profile.matched_count,
+      profile.username
+    );
+  }
+
+  let json = serde_json::to_string(&profile).map_err(|err| format!("{err}"))?;
+  std::fs::write(out_path, json)
+    .map_err(|err| format!("Failed to write {}: {err}", out_path.display()))?;
+  info!(
+    "Wrote profile for {} to {}",
+    profile.username,
+    out_path.display()
+  );
+  Ok(())
+}
+
+/// Top plays fetched through the API are sorted by pp and matched to corpus rows by score ID with
+/// mods that aren't part of score IDs ignored, and the centroid is weighted by pp
+#[tokio::test(flavor = "multi_thread")]
+async fn profile_from_stand_in_api() {
+  use std::sync::Arc;
+
+  use parquet::{
+    data_type::{DoubleType, Int64Type},
+    file::writer::SerializedFileWriter,
+    schema::parser::parse_message_type,
+  };
+
+  use crate::build_corpus::encode_corpus;
+
+  struct StandInApi;
+
+  impl OsuApi for StandInApi {
+    async fn fetch_user(&self, user: &str) -> Result<ApiUser, String> {
+      assert_eq!(user, "peppy");
+      let play = |beatmap_id: i32, mods_bits: u32, pp: f32| TopPlay {
+        beatmap_id,
+        mods_bits,
+        pp,
+      };
+      Ok(ApiUser {
+        user_id: 2,
+        username: "peppy".to_owned(),
+        // An unranked map, HD, and DT, out of order
+        top_plays: vec![play(3, 0, 100.), play(1, 8, 300.), play(2, 64, 150.)],
+      })
+    }
+  }
+
+  /// Writes a hiscores dump with the required columns and one play per `(user, pp)`
+  fn write_hiscores(path: &Path, plays: &[(i64, f64)]) {
+    let schema = parse_message_type(
+      "message schema { required int64 user; required int64 beatmap_id; required double pp; \
+       required int64 mods; required int64 mode; required int64 update_time; }",
+    )
+    .unwrap();
+    let file = std::fs::File::create(path).unwrap();
+    let mut writer = SerializedFileWriter::new(file, Arc::new(schema), Default::default()).unwrap();
+    let mut rg = writer.next_row_group().unwrap();
+    let users: Vec<i64> = plays.iter().map(|&(user, _)| user).collect();
+    let pps: Vec<f64> = plays.iter().map(|&(_, pp)| pp).collect();
+    let zeros = vec![0i64; plays.len()];
+    for column_ix in 0..6 {
+      let mut col = rg.next_column().unwrap().unwrap();
+      if column_ix == 2 {
+        col
+          .typed::<DoubleType>()
+          .write_batch(&pps, None, None)
+          .unwrap();
+      } else {
+        let values = if column_ix == 0 { &users } else { &zeros };
+        col
+          .typed::<Int64Type>()
+          .write_batch(values, None, None)
+          .unwrap();
+      }
+      col.close().unwrap();
+    }
+    rg.close().unwrap();
+    writer.close().unwrap();
+  }
+
+  let row = |beatmap_id: i32, mods_bits: u32, position: [f32; 2], aim_difficulty: f64| CorpusRow {
+    position,
+    avg_pp: 200.,
+    aim_difficulty,
+    ..CorpusRow::test_row(beatmap_id, mods_bits)
+  };
+  let corpus = encode_corpus(vec![row(1, 0, [0., 0.], 2.), row(2, 64, [3., 0.], 4.)]);
+
+  let dir = std::env::temp_dir().join(format!("atlas-user-profile-test-{}", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+  let (corpus_path, hiscores_path, out_path) = (
+    dir.join("corpus"),
+    dir.join("hiscores.parquet"),
+    dir.join("profile.json"),
+  );
+  std::fs::write(&corpus_path, &corpus.data).unwrap();
+  write_hiscores(&hiscores_path, &[(10, 100.), (11, 200.), (12, 1000.)]);
+  user_profile(
+    &StandInApi,
+    "peppy",
+    &corpus_path,
+    &hiscores_path,
+    &out_path,
+  )
+  .await
+  .unwrap();
+  let profile: serde_json::Value =
+    serde_json::from_slice(&std::fs::read(&out_path).unwrap()).unwrap();
+  std::fs::remove_dir_all(&dir).unwrap();
+
+  assert_eq!(profile["username"], "peppy");
+  assert_eq!(profile["top_play_count"], 3);
+  assert_eq!(profile["matched_count"], 2);
+  let score_ids: Vec<&str> = profile["plays"]
+    .as_array()
+    .unwrap()
+    .iter()
+    .map(|play| play["score_id"].as_str().unwrap())
+    .collect();
+  assert_eq!(score_ids, ["1_", "2_DT"]);
+  let number = |value: &serde_json::Value| value.as_f64().unwrap();
+  assert_eq!(profile["centroid"], serde_json::json!([1., 0.]));
+  assert!((number(&profile["spread"]) - 2f64.sqrt()).abs() < 1e-5);
+  assert!((number(&profile["mod_shares"][""]) - 0.5).abs() < 1e-9);
+  assert!((number(&profile["mod_shares"]["DT"]) - 0.5).abs() < 1e-9);
+  assert!((number(&profile["mean_aim_speed_ratio"]) - 4. / 3.).abs() < 1e-9);
+  assert!((number(&profile["corpus_mean_aim_speed_ratio"]) - 1.5).abs() < 1e-9);
+  assert!((number(&profile["percentile"]) - 200. / 3.).abs() < 1e-9);
+}